@@ -1,224 +1,1021 @@
-use anyhow::{Context, anyhow};
-use log::{debug, info, trace};
-use std::collections::{BTreeMap, HashSet};
-use std::io::{BufRead, stdin};
-use std::path::PathBuf;
-
-use crate::cache::Cache;
-use crate::format::{fmt_file, fmt_link, fmt_number};
-use crate::manifest::Manifest;
-use crate::symlink::Symlink;
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use std::collections::BTreeSet;
+use std::io::stdin;
+use std::path::{Path, PathBuf};
+
+use crate::args::ExportFormat;
+use crate::config::{BackupPolicy, OutputFormat};
+use crate::prompt::{confirm, keep_orphans, repair_replaced, resolve_conflicts};
+use crate::secret_cli::load_key;
+use homage::bundle;
+use homage::diff::diff_files;
+use homage::export;
+use homage::format::{fmt_diff, fmt_error, fmt_file, fmt_link, fmt_number, fmt_privileged};
+use homage::plan::{Resolution, apply};
+use homage::relocate::Relocation;
+use homage::script::Script;
+use homage::symlink::Status;
+use homage::utils::git_root;
+use homage::{Cache, EscalatedFs, Fs, Layout, Manifest, Operation, OsFs, Plan, Report, Selection};
+
+/// The command `[system]` entries are installed through unless configured otherwise.
+pub const DEFAULT_ESCALATE: &str = "sudo";
 
 pub struct Action {
-    manifest_path: PathBuf,
+    manifest_paths: Vec<PathBuf>,
     cache_path: PathBuf,
+    layout: Layout,
+    selection: Selection,
     dry_run: bool,
     skip_confirmation: bool,
+    interactive: bool,
+    replace_identical: bool,
+    backup: BackupPolicy,
+    output_format: OutputFormat,
+    identity: Option<PathBuf>,
+    escalate: EscalatedFs,
 }
 
 impl Action {
     pub fn new(
-        manifest_path: PathBuf,
+        manifest_paths: Vec<PathBuf>,
         cache_path: PathBuf,
+        layout: Layout,
+        selection: Selection,
         dry_run: bool,
         skip_confirmation: bool,
     ) -> Action {
         Action {
-            manifest_path,
+            manifest_paths,
             cache_path,
+            layout,
+            selection,
             dry_run,
             skip_confirmation,
+            interactive: false,
+            replace_identical: false,
+            backup: BackupPolicy::default(),
+            output_format: OutputFormat::default(),
+            identity: None,
+            escalate: EscalatedFs::new(DEFAULT_ESCALATE),
         }
     }
 
-    pub fn install(&self) -> anyhow::Result<()> {
-        info!(
-            "Installing dotfiles from manifest: {}",
-            fmt_file(&self.manifest_path)
-        );
+    /// Prompts how to resolve each conflict instead of aborting the install.
+    pub fn interactive(mut self, interactive: bool) -> Action {
+        self.interactive = interactive;
+        self
+    }
 
-        let manifest = Manifest::load(&self.manifest_path).context("Failed to load manifest")?;
+    /// Replaces conflicting targets that are identical to their source.
+    pub fn replace_identical(mut self, replace_identical: bool) -> Action {
+        self.replace_identical = replace_identical;
+        self
+    }
 
-        let cache = Cache::load(&self.cache_path).context("Failed to load cache")?;
+    /// What to do with conflicting targets when not resolving them interactively.
+    pub fn backup(mut self, backup: BackupPolicy) -> Action {
+        self.backup = backup;
+        self
+    }
 
-        // Find stale entries: in cache but no longer in manifest (or target changed)
-        let stale: Vec<_> = cache
-            .stale_entries(&manifest.entries)
-            .into_iter()
-            .filter(|s| s.is_installed())
-            .collect();
+    /// How results are printed.
+    pub fn output_format(mut self, output_format: OutputFormat) -> Action {
+        self.output_format = output_format;
+        self
+    }
 
-        // Find new entries: in manifest but not yet installed
-        let to_install: Vec<_> = manifest
-            .to_symlinks()
-            .into_iter()
-            .filter(|s| !s.is_installed())
-            .collect();
+    /// The age identity file to decrypt secrets with, otherwise a passphrase is used.
+    pub fn identity(mut self, identity: Option<PathBuf>) -> Action {
+        self.identity = identity;
+        self
+    }
+
+    /// The command the changes to `[system]` entries are run through, such as "sudo" or "doas".
+    pub fn escalate(mut self, command: &str) -> Action {
+        self.escalate = EscalatedFs::new(command);
+        self
+    }
+
+    pub fn install(&self) -> anyhow::Result<()> {
+        for path in &self.manifest_paths {
+            info!("Installing dotfiles from manifest: {}", fmt_file(path));
+        }
+
+        let (mut plan, system) = self.install_plans(true)?;
+
+        // Conflicts of system entries are never resolved, as that would mean changing root owned files
+        if self.interactive && !plan.conflicts.is_empty() {
+            info!(
+                "Found {} conflicting file(s) to resolve",
+                fmt_number(plan.conflicts.len()),
+            );
+            if !resolve_conflicts(&mut plan, &mut stdin().lock()) {
+                return Ok(());
+            }
+        } else {
+            if !self.skip_confirmation && !plan.replaced.is_empty() {
+                info!(
+                    "Found {} link(s) replaced by a file",
+                    fmt_number(plan.replaced.len()),
+                );
+                if !repair_replaced(&mut plan, &mut stdin().lock()) {
+                    return Ok(());
+                }
+            }
+
+            // Edits to a replaced link belong in the repository, so they are never backed up
+            if self.backup == BackupPolicy::Always {
+                let conflicts: Vec<_> = plan
+                    .conflicts
+                    .iter()
+                    .filter(|s| !plan.is_replaced(s))
+                    .map(|s| s.target.clone())
+                    .collect();
+                if !conflicts.is_empty() {
+                    info!(
+                        "Found {} conflicting file(s) to back up",
+                        fmt_number(conflicts.len()),
+                    );
+                }
+                for target in conflicts {
+                    plan.resolve(&OsFs, &target, Resolution::Backup);
+                }
+            }
+        }
 
-        self.pre_flight_check(&to_install, &stale)?;
+        // Verifies every target is available before making any changes
+        log_conflicts(&plan);
+        log_conflicts(&system);
+        plan.check_conflicts()?;
+        system.check_conflicts()?;
+
+        for symlink in plan.exposed(&OsFs, &self.layout) {
+            warn!(
+                "{} is readable by other users but placed in a private location, \
+                 set a mode such as \"0600\" for the entry",
+                symlink
+            );
+        }
 
-        if stale.is_empty() && to_install.is_empty() {
+        if plan.is_empty() && system.is_empty() {
             info!("Everything is up to date");
             return Ok(());
         }
 
-        if !stale.is_empty() {
+        if !plan.remove.is_empty() {
             info!(
                 "Found {} stale dotfile(s) to remove",
-                fmt_number(stale.len()),
+                fmt_number(plan.remove.len()),
             );
         }
 
-        if !to_install.is_empty() {
+        if !plan.install.is_empty() {
             info!(
                 "Found {} dotfile(s) to install",
-                fmt_number(to_install.len()),
+                fmt_number(plan.install.len()),
             );
         }
 
-        if !self.skip_confirmation {
-            println!("Do you want to proceed? (y/n)");
-            if !confirm() {
-                return Ok(());
+        if !plan.resolved.is_empty() {
+            info!(
+                "Found {} existing file(s) to replace",
+                fmt_number(plan.resolved.len()),
+            );
+        }
+
+        if !system.is_empty() {
+            info!(
+                "Found {} system file(s) to change through {}",
+                fmt_number(system.install.len() + system.remove.len()),
+                self.escalate.name(),
+            );
+        }
+
+        self.execute(plan, system)
+    }
+
+    /// Prints the changes an install would make, along with the conflicting targets.
+    /// With `content` the contents of each conflicting target are diffed against the source.
+    pub fn diff(&self, content: bool) -> anyhow::Result<()> {
+        let (plan, system) = self.install_plans(false)?;
+
+        if plan.is_empty()
+            && system.is_empty()
+            && plan.conflicts.is_empty()
+            && system.conflicts.is_empty()
+        {
+            info!("Everything is up to date");
+            return Ok(());
+        }
+
+        let operations = plan.operations(&OsFs, &self.cache_path);
+        let privileged = system.operations(&self.escalate, &self.layout.system_cache_path());
+        for (operation, privileged) in operations
+            .iter()
+            .map(|operation| (operation, false))
+            .chain(privileged.iter().map(|operation| (operation, true)))
+        {
+            if matches!(
+                operation,
+                Operation::WriteCache { .. } | Operation::DeleteCache(_)
+            ) {
+                continue;
+            }
+            match privileged {
+                true => println!("{} {}", fmt_privileged(self.escalate.name()), operation),
+                false => println!("{}", operation),
             }
         }
 
-        self.remove_stale_entries(&stale);
-        self.install_entries(&to_install);
-        self.update_cache(&manifest)?;
+        for conflict in plan.conflicts.iter().chain(&system.conflicts) {
+            match plan.is_replaced(conflict) || system.is_replaced(conflict) {
+                true => println!("Replaced link at {}", fmt_error(&conflict.target)),
+                false => println!("Conflict at {}", fmt_error(&conflict.target)),
+            }
+            if content {
+                match diff_files(&OsFs, &conflict.target, &conflict.source) {
+                    Some(diff) => print!("{}", fmt_diff(&diff)),
+                    None => println!("The file is identical to {}", fmt_file(&conflict.source)),
+                }
+            }
+        }
 
         Ok(())
     }
 
-    // Verifies every target is available before making any changes.
-    // Targets occupied by stale symlinks are excluded since those will be removed first.
-    fn pre_flight_check(&self, to_install: &[Symlink], stale: &[Symlink]) -> anyhow::Result<()> {
-        let stale_targets: HashSet<&PathBuf> = stale.iter().map(|s| &s.target).collect();
-        let conflicts: Vec<_> = to_install
-            .iter()
-            .filter(|entry| entry.exists() && !stale_targets.contains(&entry.target))
-            .collect();
+    /// Writes the install plan as a shell script, to `output` or standard output.
+    /// Occupied targets are backed up, since the script can not ask how to resolve them.
+    pub fn script(&self, output: Option<&Path>) -> anyhow::Result<()> {
+        let (mut plan, mut system) = self.install_plans(false)?;
+        for plan in [&mut plan, &mut system] {
+            let conflicts: Vec<_> = plan.conflicts.iter().map(|s| s.target.clone()).collect();
+            for target in conflicts {
+                warn!("Backing up {} in the script", fmt_error(&target));
+                plan.resolve(&OsFs, &target, Resolution::Backup);
+            }
+        }
 
-        if !conflicts.is_empty() {
-            let listing: Vec<String> = conflicts
-                .iter()
-                .map(|e| format!("  {}", e.target.display()))
-                .collect();
-            return Err(anyhow!(
-                "Cannot install, the following target files already exist:\n{}",
-                listing.join("\n")
-            ));
+        let mut script = Script::default();
+        script.push(&OsFs, &plan.operations(&OsFs, &self.cache_path), None)?;
+        script.push(
+            &OsFs,
+            &system.operations(&self.escalate, &self.layout.system_cache_path()),
+            Some(self.escalate.command()),
+        )?;
+        for skipped in &script.skipped {
+            warn!("Not in the script: {}", skipped);
+        }
+        if script.is_empty() {
+            info!("Everything is up to date");
         }
 
+        match output {
+            Some(output) if !self.dry_run => {
+                if !self.write_output(output, &script.render())? {
+                    return Ok(());
+                }
+                OsFs.set_mode(output, 0o755)
+                    .with_context(|| format!("Failed to set mode of {}", output.display()))?;
+                info!("Wrote the plan to {}", fmt_file(output));
+            }
+            _ => print!("{}", script.render()),
+        }
         Ok(())
     }
 
-    fn remove_stale_entries(&self, stale: &Vec<Symlink>) {
-        for entry in stale {
-            debug!("Removing stale symlink: {}", fmt_link(&entry.target));
-            if !self.dry_run {
-                entry.uninstall();
+    pub fn uninstall(&self) -> anyhow::Result<()> {
+        for path in &self.manifest_paths {
+            info!("Uninstalling dotfiles from manifest: {}", fmt_file(path));
+        }
+
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &self.layout).context(
+            "Failed to load manifest, use --all to uninstall every link recorded in the state",
+        )?;
+
+        let (user, system) = manifest.partition_system();
+        let cache = self.load_cache(&OsFs, &self.cache_path, true)?;
+        let system_cache =
+            self.load_cache(&self.escalate, &self.layout.system_cache_path(), true)?;
+
+        let plan = Plan::uninstall(&OsFs, &user, &cache, &self.selection);
+        let system = Plan::uninstall(&OsFs, &system, &system_cache, &self.selection);
+
+        if plan.is_empty() && system.is_empty() {
+            info!("No dotfiles to uninstall");
+        } else {
+            info!(
+                "Found {} dotfile(s) to uninstall",
+                fmt_number(plan.remove.len() + system.remove.len()),
+            );
+        }
+
+        self.execute(plan, system)
+    }
+
+    /// Replaces the selected installed links with copies of their sources and drops them from the
+    /// state. Targets no longer linked to their recorded source are left alone.
+    pub fn eject(&self) -> anyhow::Result<()> {
+        for path in &self.manifest_paths {
+            info!("Ejecting dotfiles from manifest: {}", fmt_file(path));
+        }
+
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &self.layout)
+            .context("Failed to load manifest")?;
+
+        let (user, system) = manifest.partition_system();
+        let cache = self.load_cache(&OsFs, &self.cache_path, true)?;
+        let system_cache =
+            self.load_cache(&self.escalate, &self.layout.system_cache_path(), true)?;
+
+        let user_plan = Plan::eject(&OsFs, &user, &cache, &self.selection);
+        let system_plan = Plan::eject(&OsFs, &system, &system_cache, &self.selection);
+
+        for (manifest, cache, plan) in [
+            (&user, &cache, &user_plan),
+            (&system, &system_cache, &system_plan),
+        ] {
+            let selected: BTreeSet<PathBuf> = Plan::selected(manifest, cache, &self.selection)
+                .into_iter()
+                .map(|s| s.target)
+                .collect();
+            let skipped = plan
+                .cache
+                .all_entries()
+                .into_iter()
+                .filter(|s| selected.contains(&s.target))
+                .filter(|s| OsFs.symlink_metadata(&s.target).is_ok());
+
+            for symlink in skipped {
+                match symlink.is_installed(&OsFs) {
+                    true => warn!(
+                        "The source of {} is gone, leaving the link alone",
+                        fmt_error(&symlink.target)
+                    ),
+                    false => warn!(
+                        "{} no longer links to {}, leaving it alone",
+                        fmt_error(&symlink.target),
+                        fmt_file(&symlink.source)
+                    ),
+                }
             }
         }
+
+        if user_plan.is_empty() && system_plan.is_empty() {
+            info!("No dotfiles to eject");
+        } else {
+            info!(
+                "Found {} dotfile(s) to eject",
+                fmt_number(user_plan.eject.len() + system_plan.eject.len()),
+            );
+        }
+
+        self.execute(user_plan, system_plan)
     }
 
-    fn install_entries(&self, to_install: &Vec<Symlink>) {
-        for entry in to_install {
-            debug!("Installing: {}", entry);
-            if !self.dry_run {
-                entry.install();
+    /// Uninstalls every selected link recorded in the state without loading a manifest.
+    /// Links no longer pointing at their recorded source are left alone. Secrets and hard
+    /// links whose source is gone hold the only copy of their content, so each can be kept
+    /// as a plain file, which is the default without confirmation.
+    pub fn uninstall_all(&self) -> anyhow::Result<()> {
+        let cache = self.load_cache(&OsFs, &self.cache_path, true)?;
+        let system_cache =
+            self.load_cache(&self.escalate, &self.layout.system_cache_path(), true)?;
+
+        let mut plan = Plan::uninstall_all(&OsFs, &cache, &self.selection);
+        let mut system = Plan::uninstall_all(&OsFs, &system_cache, &self.selection);
+
+        for (cache, plan) in [(&cache, &plan), (&system_cache, &system)] {
+            for symlink in cache.all_entries() {
+                if !plan.remove.contains(&symlink) && OsFs.symlink_metadata(&symlink.target).is_ok()
+                {
+                    warn!(
+                        "{} no longer links to {}, leaving it alone",
+                        fmt_error(&symlink.target),
+                        fmt_file(&symlink.source)
+                    );
+                }
             }
         }
+
+        for plan in [&mut plan, &mut system] {
+            if self.skip_confirmation {
+                let orphaned: Vec<PathBuf> = plan
+                    .orphaned(&OsFs)
+                    .iter()
+                    .map(|s| s.target.clone())
+                    .collect();
+                for target in orphaned {
+                    warn!(
+                        "The source of {} is gone, keeping it as a file",
+                        fmt_file(&target)
+                    );
+                    plan.keep(&target);
+                }
+            } else if !keep_orphans(plan, &mut stdin().lock()) {
+                return Ok(());
+            }
+        }
+
+        if plan.is_empty() && system.is_empty() {
+            info!("No dotfiles to uninstall");
+        } else {
+            info!(
+                "Found {} dotfile(s) to uninstall",
+                fmt_number(plan.remove.len() + system.remove.len()),
+            );
+        }
+
+        self.execute(plan, system)
     }
 
-    fn update_cache(&self, manifest: &Manifest) -> anyhow::Result<()> {
-        if !self.dry_run {
-            let mut new_cache = Cache::default();
-            new_cache.update(&manifest.entries);
-            new_cache
-                .save(&self.cache_path)
-                .context("Failed to save cache")?;
-            trace!("Cache updated at {}", fmt_file(&self.cache_path));
+    /// Prints the status of each selected entry.
+    pub fn status(&self) -> anyhow::Result<()> {
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &self.layout)
+            .context("Failed to load manifest")?;
+
+        let (user, system) = manifest.select(&self.selection).partition_system();
+        let cache = self.load_cache(&OsFs, &self.cache_path, false)?;
+        let system_cache =
+            self.load_cache(&self.escalate, &self.layout.system_cache_path(), false)?;
+
+        let mut entries = Vec::new();
+        for (manifest, cache) in [(&user, &cache), (&system, &system_cache)] {
+            for symlink in manifest.to_symlinks() {
+                let symlink = cache.with_state(symlink);
+                let status = match symlink.status(&OsFs) {
+                    Status::Conflict if cache.is_replaced(&OsFs, &symlink) => {
+                        "replaced".to_string()
+                    }
+                    status => status.to_string(),
+                };
+
+                match self.output_format {
+                    OutputFormat::Text => println!("{:<9} {}", status, symlink),
+                    OutputFormat::Json => entries.push(serde_json::json!({
+                        "source": symlink.source.display().to_string(),
+                        "target": symlink.target.display().to_string(),
+                        "status": status,
+                    })),
+                }
+            }
         }
 
+        if self.output_format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
         Ok(())
     }
 
-    pub fn uninstall(&self) -> anyhow::Result<()> {
+    /// Rebuilds the state from the links found on disk and saves it once confirmed.
+    /// Links into the directories of the manifests and their sources are recognized,
+    /// the entries found are added to the current state.
+    pub fn rebuild_state(&self) -> anyhow::Result<()> {
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &self.layout)
+            .context("Failed to load manifest")?;
+
+        let dirs = self
+            .manifest_paths
+            .iter()
+            .filter_map(|path| OsFs.canonicalize(path).ok())
+            .chain(manifest.entries.keys().cloned())
+            .filter_map(|path| path.parent().map(Path::to_path_buf));
+        let roots: BTreeSet<PathBuf> = dirs
+            .map(|dir| git_root(&OsFs, &dir).unwrap_or(dir))
+            .collect();
+        let roots: Vec<PathBuf> = roots.into_iter().collect();
+
+        let (user, system) = manifest.partition_system();
+        let system_cache_path = self.layout.system_cache_path();
+        let states: [(&dyn Fs, &Path, Manifest); 2] = [
+            (&OsFs, &self.cache_path, user),
+            (&self.escalate, &system_cache_path, system),
+        ];
+
+        let mut rebuilt = Vec::new();
+        for (fs, path, manifest) in states {
+            let mut cache = self.load_cache(fs, path, true)?;
+            let found = Cache::rebuild(&OsFs, &manifest, &roots);
+            if found.files.is_empty() {
+                continue;
+            }
+
+            println!(
+                "Found {} installed link(s) for {}",
+                fmt_number(found.files.len()),
+                fmt_file(path)
+            );
+            for symlink in found.all_entries() {
+                println!("  {}", symlink);
+            }
+
+            cache.files.extend(found.files);
+            cache.hardlinks.extend(found.hardlinks);
+            rebuilt.push((fs, path, cache));
+        }
+
+        if rebuilt.is_empty() {
+            info!("No installed links found");
+            return Ok(());
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        if !self.skip_confirmation {
+            println!("Do you want to save the state? (y/n)");
+            if !confirm(&mut stdin().lock()) {
+                return Ok(());
+            }
+        }
+
+        for (fs, path, cache) in rebuilt {
+            cache.save(fs, path)?;
+            info!("Saved state to {}", fmt_file(path));
+        }
+
+        Ok(())
+    }
+
+    // Plans the user entries and the `[system]` entries separately, each against its own cache.
+    // With `mutating` the plans are about to be applied: a moved source tree is detected and the
+    // installed links are moved along, and a corrupted cache is moved aside.
+    fn install_plans(&self, mutating: bool) -> anyhow::Result<(Plan, Plan)> {
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &self.layout)
+            .context("Failed to load manifest")?;
+
+        let (user, system) = manifest.partition_system();
+        let plan = self.install_plan(&user, &OsFs, &self.cache_path, mutating)?;
+        let system = self.install_plan(
+            &system,
+            &self.escalate,
+            &self.layout.system_cache_path(),
+            mutating,
+        )?;
+
+        Ok((plan, system))
+    }
+
+    fn install_plan(
+        &self,
+        manifest: &Manifest,
+        fs: &dyn Fs,
+        cache_path: &Path,
+        mutating: bool,
+    ) -> anyhow::Result<Plan> {
+        let mut cache = self.load_cache(fs, cache_path, mutating)?;
+        warn_missing_state(manifest, cache_path);
+
+        if mutating && let Some(relocation) = Relocation::detect(&OsFs, manifest, &cache) {
+            warn!(
+                "The sources moved from {} to {}",
+                fmt_error(&relocation.from),
+                fmt_file(&relocation.to)
+            );
+            self.apply_relocation(fs, cache_path, &mut cache, &relocation)?;
+        }
+
+        let mut plan = Plan::install(&OsFs, manifest, &cache, &self.selection);
+
+        if self.replace_identical {
+            let replaced = plan.resolve_identical(&OsFs);
+            if replaced > 0 {
+                info!(
+                    "Found {} identical file(s) to replace",
+                    fmt_number(replaced)
+                );
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Packs the sources of the manifests into a bundle at `output`. Targets keep their `~`,
+    /// which is expanded on the machine the bundle is installed on.
+    pub fn bundle(&self, output: &Path) -> anyhow::Result<()> {
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &Layout::portable())
+            .context("Failed to load manifest")?;
+
+        if self.dry_run {
+            for source in manifest.entries.keys() {
+                info!("Pack {}", fmt_file(source));
+            }
+            return Ok(());
+        }
+
+        let skipped = bundle::pack(&OsFs, &manifest, output).context("Failed to create bundle")?;
+        for skipped in &skipped {
+            warn!("Not packed: {}", skipped);
+        }
         info!(
-            "Uninstalling dotfiles from manifest: {}",
-            fmt_file(&self.manifest_path)
+            "Packed {} source file(s) into {}",
+            fmt_number(manifest.entries.len() - skipped.len()),
+            fmt_file(output)
         );
+        Ok(())
+    }
 
-        let manifest = Manifest::load(&self.manifest_path).context("Failed to load manifest")?;
-
-        let cache = Cache::load(&self.cache_path).context("Failed to load cache")?;
+    /// Writes the resolved entries in the given format, to `output` or standard output.
+    pub fn export(&self, format: ExportFormat, output: Option<&Path>) -> anyhow::Result<()> {
+        let manifest = Manifest::load_all(&OsFs, &self.manifest_paths, &Layout::portable())
+            .context("Failed to load manifest")?;
+
+        let content = match format {
+            ExportFormat::Nix => export::nix(&manifest)?,
+            ExportFormat::Sh => export::sh(&manifest)?,
+            ExportFormat::Json => export::json(&manifest)?,
+            ExportFormat::Stow => {
+                let dir = output.context("Exporting to stow needs an output directory")?;
+                return self.export_stow(&manifest, dir);
+            }
+        };
+        for skipped in &content.skipped {
+            warn!("Not exported: {}", skipped);
+        }
 
-        // Collect all entries from both manifest and cache (union)
-        let mut all_entries: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
-        for (src, target) in &manifest.entries {
-            all_entries.insert(src.clone(), target.clone());
+        match output {
+            Some(output) if !self.dry_run => {
+                if !self.write_output(output, &content.content)? {
+                    return Ok(());
+                }
+                info!(
+                    "Exported {} entries to {}",
+                    fmt_number(manifest.entries.len() - content.skipped.len()),
+                    fmt_file(output)
+                );
+            }
+            _ => print!("{}", content.content),
         }
-        for entry in cache.all_entries() {
-            all_entries
-                .entry(entry.source.clone())
-                .or_insert(entry.target.clone());
+        Ok(())
+    }
+
+    // Writes a file, asking before replacing an existing one. Returns false if the user declined.
+    fn write_output(&self, output: &Path, content: &str) -> anyhow::Result<bool> {
+        if OsFs.exists(output) && !self.skip_confirmation {
+            println!(
+                "{} already exists, do you want to replace it? (y/n)",
+                fmt_error(output)
+            );
+            if !confirm(&mut stdin().lock()) {
+                return Ok(false);
+            }
         }
 
-        // Filter to entries that are actually installed as symlinks
-        let to_remove: Vec<_> = all_entries
-            .iter()
-            .map(|(src, target)| Symlink::new(src.clone(), target.clone()))
-            .filter(|s| s.is_installed())
-            .collect();
+        OsFs.write(output, content.as_bytes())
+            .with_context(|| format!("Failed to write {}", output.display()))?;
+        Ok(true)
+    }
 
-        if to_remove.is_empty() {
-            info!("No dotfiles to uninstall");
-            if !self.dry_run {
-                Cache::delete(&self.cache_path)?;
+    fn export_stow(&self, manifest: &Manifest, dir: &Path) -> anyhow::Result<()> {
+        if self.dry_run {
+            for source in manifest.entries.keys() {
+                info!("Export {}", fmt_file(source));
             }
             return Ok(());
         }
 
+        let non_empty = OsFs.read_dir(dir).is_ok_and(|entries| !entries.is_empty());
+        if non_empty && !self.skip_confirmation {
+            println!(
+                "{} is not empty, do you want to write into it? (y/n)",
+                fmt_error(dir)
+            );
+            if !confirm(&mut stdin().lock()) {
+                return Ok(());
+            }
+        }
+
+        let skipped = export::stow(&OsFs, manifest, dir).context("Failed to export")?;
+        for skipped in &skipped {
+            warn!("Not exported: {}", skipped);
+        }
         info!(
-            "Found {} dotfile(s) to uninstall",
-            fmt_number(to_remove.len()),
+            "Exported {} entries to {}",
+            fmt_number(manifest.entries.len() - skipped.len()),
+            fmt_file(dir)
         );
+        Ok(())
+    }
+
+    /// Moves the installed links below the old root of the sources to the new root.
+    pub fn relocate(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let to = to
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", to.display()))?;
+        let relocation = Relocation::new(std::path::absolute(from)?, to);
+
+        let system_cache_path = self.layout.system_cache_path();
+        let states: [(&dyn Fs, &Path); 2] = [
+            (&OsFs, &self.cache_path),
+            (&self.escalate, &system_cache_path),
+        ];
+
+        let mut relocated = 0;
+        for (fs, path) in states {
+            let mut cache = self.load_cache(fs, path, true)?;
+            relocated += self.apply_relocation(fs, path, &mut cache, &relocation)?;
+        }
+
+        if relocated == 0 {
+            info!("No installed links below {}", fmt_file(&relocation.from));
+        }
+
+        Ok(())
+    }
+
+    // Shows the entries moved by the relocation and applies it once confirmed, saving the cache.
+    // Returns the number of relocated entries.
+    fn apply_relocation(
+        &self,
+        fs: &dyn Fs,
+        cache_path: &Path,
+        cache: &mut Cache,
+        relocation: &Relocation,
+    ) -> anyhow::Result<usize> {
+        let entries = relocation.entries(cache);
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        for (old, new) in &entries {
+            let message = format!(
+                "Relocate {} from {} to {}",
+                fmt_link(&old.target),
+                fmt_error(&old.source),
+                fmt_file(&new.source)
+            );
+            match self.dry_run {
+                true => info!("{}", message),
+                false => debug!("{}", message),
+            }
+        }
+
+        if self.dry_run {
+            return Ok(0);
+        }
 
         if !self.skip_confirmation {
-            println!("Do you want to proceed? (y/n)");
-            if !confirm() {
-                return Ok(());
+            println!(
+                "Do you want to relocate {} installed link(s)? (y/n)",
+                fmt_number(entries.len())
+            );
+            if !confirm(&mut stdin().lock()) {
+                return Ok(0);
+            }
+        }
+
+        let relocated = relocation.apply(fs, cache);
+        // Entries relocated before a failure are kept, their links already point at the new root
+        cache.save(fs, cache_path)?;
+        let relocated = relocated?;
+
+        info!("Relocated {} link(s)", fmt_number(relocated.len()));
+        Ok(relocated.len())
+    }
+
+    // Loads the cache at the path. A corrupted cache is replaced by an empty one, rather than
+    // blocking every command until it is fixed by hand. Only `mutating` commands, which are about
+    // to save the cache, move the corrupted one aside through `fs` first.
+    fn load_cache(&self, fs: &dyn Fs, path: &Path, mutating: bool) -> anyhow::Result<Cache> {
+        let err = match Cache::load(fs, path) {
+            Ok(cache) => return Ok(cache),
+            Err(err @ homage::Error::Parse { .. }) => err,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to load cache {}", path.display()));
             }
+        };
+
+        if !mutating {
+            warn!("{}, treating it as empty", err);
+            return Ok(Cache::default());
+        }
+        if self.dry_run {
+            warn!("{}, it would be moved aside", err);
+            return Ok(Cache::default());
         }
 
-        for entry in &to_remove {
-            debug!("Uninstalling: {}", fmt_file(&entry.target));
-            if !self.dry_run {
-                entry.uninstall();
+        let backup = Cache::move_aside(fs, path)?;
+        warn!(
+            "{}, moved it to {}. Run 'homage state rebuild' to recover the installed links",
+            err,
+            fmt_file(&backup)
+        );
+        Ok(Cache::default())
+    }
+
+    // Renders the operations of the plans on a dry run, otherwise applies them once confirmed.
+    // Both use the same operations so a dry run always shows what would actually be done.
+    // Secrets are decrypted first, a dry run shows them without asking for the key.
+    // The system plan is applied through the escalation command, which is only run when
+    // something actually changes.
+    fn execute(&self, mut plan: Plan, mut system: Plan) -> anyhow::Result<()> {
+        if !self.dry_run && (plan.needs_key() || system.needs_key()) {
+            let key = load_key(self.identity.as_deref())?;
+            plan.decrypt(&OsFs, &key)?;
+            system.decrypt(&OsFs, &key)?;
+        }
+
+        let operations = plan.operations(&OsFs, &self.cache_path);
+        let privileged = match system.is_empty() {
+            true => Vec::new(),
+            false => system.operations(&self.escalate, &self.layout.system_cache_path()),
+        };
+        let marker = fmt_privileged(self.escalate.name());
+
+        if self.dry_run {
+            for operation in &operations {
+                info!("{}", operation);
+            }
+            for operation in &privileged {
+                info!("{} {}", marker, operation);
             }
+            return Ok(());
         }
 
-        // Delete cache
-        if !self.dry_run {
-            Cache::delete(&self.cache_path)?;
-            trace!("Cache deleted");
+        for operation in &operations {
+            debug!("{}", operation);
+        }
+        for operation in &privileged {
+            debug!("{} {}", marker, operation);
         }
 
+        let is_empty = plan.is_empty() && system.is_empty();
+        if !self.skip_confirmation && !is_empty {
+            if !privileged.is_empty() {
+                println!(
+                    "The following changes run as root through '{}':",
+                    self.escalate.name()
+                );
+                for operation in &privileged {
+                    println!("  {} {}", marker, operation);
+                }
+            }
+            println!("Do you want to proceed? (y/n)");
+            if !confirm(&mut stdin().lock()) {
+                return Ok(());
+            }
+        }
+
+        let report = apply(&OsFs, &operations)?;
+        log_failures(&report);
+
+        let report = apply(&self.escalate, &privileged)?;
+        log_failures(&report);
+
         Ok(())
     }
 }
 
-fn confirm() -> bool {
-    let mut buffer = String::new();
-    let mut handle = stdin().lock();
+/// Verifies a bundle and unpacks it into the local store, replacing the bundle unpacked before.
+/// Returns the path of the unpacked manifest, or nothing in a dry run, which only verifies it.
+pub fn unpack_bundle(
+    fs: &dyn Fs,
+    path: &Path,
+    layout: &Layout,
+    dry_run: bool,
+) -> anyhow::Result<Option<PathBuf>> {
+    let store = layout.bundle_dir()?;
+
+    if dry_run {
+        let count = bundle::verify(fs, path)?;
+        info!(
+            "Verified {} source file(s) in {}, they would be unpacked into {}",
+            fmt_number(count),
+            fmt_file(path),
+            fmt_file(&store)
+        );
+        return Ok(None);
+    }
+
+    // Unpack next to the store and swap it in, so a rejected bundle leaves the store untouched
+    let with_suffix = |suffix: &str| {
+        let mut name = store.as_os_str().to_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let staging = with_suffix(".new");
+    let previous = with_suffix(".old");
+    for dir in [&staging, &previous] {
+        if fs.exists(dir) {
+            fs.remove_dir_all(dir)
+                .with_context(|| format!("Failed to remove {}", dir.display()))?;
+        }
+    }
+
+    bundle::unpack(fs, path, &staging)?;
+    if fs.exists(&store) {
+        fs.rename(&store, &previous)
+            .with_context(|| format!("Failed to replace {}", store.display()))?;
+    }
+    fs.rename(&staging, &store)
+        .with_context(|| format!("Failed to replace {}", store.display()))?;
+    if fs.exists(&previous) {
+        fs.remove_dir_all(&previous)
+            .with_context(|| format!("Failed to remove {}", previous.display()))?;
+    }
+
+    info!("Unpacked {} into {}", fmt_file(path), fmt_file(&store));
+    Ok(Some(store.join(bundle::MANIFEST)))
+}
+
+/// Returns the state file to use, moving the cache kept by earlier versions into place first.
+/// A dry run reads the old cache instead of moving it.
+pub fn state_file(layout: &Layout, dry_run: bool) -> anyhow::Result<PathBuf> {
+    let state = layout.state_path()?;
+    let legacy = layout.legacy_cache_path()?;
+
+    if dry_run {
+        let moved = !state.exists() && legacy.exists();
+        return Ok(if moved { legacy } else { state });
+    }
+
+    if Cache::migrate(&OsFs, &legacy, &state).context("Failed to move install state")? {
+        warn!(
+            "Moved install state from {} to {}",
+            fmt_file(&legacy),
+            fmt_file(&state)
+        );
+    }
+
+    Ok(state)
+}
+
+// Without a state file homage can not tell which links it installed before. Targets that are
+// already linked to their source mean the state existed and was lost, for example by clearing
+// the cache directory of an older version.
+fn warn_missing_state(manifest: &Manifest, state_path: &Path) {
+    if state_path.exists() {
+        return;
+    }
+
+    let linked = manifest
+        .to_symlinks()
+        .iter()
+        .filter(|symlink| symlink.is_installed(&OsFs))
+        .count();
+
+    if linked > 0 {
+        warn!(
+            "No install state found at {}, but {} target(s) are already linked by homage. \
+             The state was most likely deleted, links to entries since removed from the manifest \
+             will not be cleaned up. Run 'homage state rebuild' to recover it",
+            fmt_error(state_path),
+            fmt_number(linked)
+        );
+    }
+}
+
+// Shows how each conflicting target differs from its source.
+fn log_conflicts(plan: &Plan) {
+    for conflict in &plan.conflicts {
+        // The source of a secret is encrypted, diffing it against the target shows nothing useful
+        if conflict.is_secret() {
+            warn!(
+                "{} is not the secret decrypted from {}",
+                fmt_error(&conflict.target),
+                fmt_file(&conflict.source)
+            );
+            continue;
+        }
+
+        if plan.is_replaced(conflict) {
+            let diff = diff_files(&OsFs, &conflict.source, &conflict.target).unwrap_or_default();
+            warn!(
+                "{} was a link to {} but was replaced by a file, most likely by an editor. \
+                 Run install without --no-confirm to pull the changes into the repository \
+                 or discard them:\n{}",
+                fmt_error(&conflict.target),
+                fmt_file(&conflict.source),
+                fmt_diff(&diff).trim_end()
+            );
+            continue;
+        }
+
+        match diff_files(&OsFs, &conflict.target, &conflict.source) {
+            Some(diff) => warn!(
+                "{} differs from {}:\n{}",
+                fmt_error(&conflict.target),
+                fmt_file(&conflict.source),
+                fmt_diff(&diff).trim_end()
+            ),
+            None => warn!(
+                "{} is identical to {}, use --replace-identical to replace it",
+                fmt_error(&conflict.target),
+                fmt_file(&conflict.source)
+            ),
+        }
+    }
+}
 
-    match handle.read_line(&mut buffer) {
-        Ok(_) => matches!(
-            buffer.to_string().trim().to_lowercase().as_str(),
-            "yes" | "y"
-        ),
-        Err(_) => false,
+fn log_failures(report: &Report) {
+    for (_, err) in &report.failed {
+        match std::error::Error::source(err) {
+            Some(source) => error!("{}: {}", err, source),
+            None => error!("{}", err),
+        }
     }
 }
 
@@ -227,228 +1024,785 @@ mod tests {
     use super::*;
 
     use std::fs;
+    use std::os::unix::fs::PermissionsExt;
 
-    use crate::tests::tests::{test_dir, write_file};
+    use crate::tests::{test_dir, write_file};
+    use homage::{Cache, MemFs};
 
     #[test]
-    fn install_creates_symlinks_and_cache() {
-        let dir = test_dir("install");
+    fn uninstall_all_works_without_the_repository() {
+        let dir = test_dir("uninstall_all");
         let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
-
-        // Create source dotfiles
-        write_file(&dir, "dotfiles/config.toml", "config content");
-        write_file(&dir, "dotfiles/nested/app.conf", "app content");
+        write_file(&dir, "dotfiles/a.conf", "a");
+        write_file(&dir, "dotfiles/b.conf", "b");
 
-        // Create manifest pointing to the dotfiles directory
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/dotfiles_target\"",
-            target_dir.display()
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            &format!(
+                "[files]\n\"a.conf\" = \"{t}/a.conf\"\n\
+                 \"b.conf\" = {{ target = \"{t}/b.conf\", link = \"hardlink\" }}",
+                t = target_dir.display()
+            ),
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
-
-        let cache_path = dir.join("cache/cache.toml");
-
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
-        action.install().unwrap();
+        let cache_path = dir.join("state/state.toml");
+        let action = |manifest_paths| {
+            Action::new(
+                manifest_paths,
+                cache_path.clone(),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        action(vec![manifest_path]).install().unwrap();
+
+        fs::remove_dir_all(dir.join("dotfiles")).unwrap();
+        action(vec![]).uninstall_all().unwrap();
+
+        // The dangling link is removed, the hard link is kept as the only copy of its content
+        assert!(!target_dir.join("a.conf").is_symlink());
+        assert_eq!(fs::read_to_string(target_dir.join("b.conf")).unwrap(), "b");
+        assert!(!cache_path.exists());
+    }
 
-        // Verify symlinks were created
-        let target_config = target_dir.join("dotfiles_target/config.toml");
-        let target_app = target_dir.join("dotfiles_target/nested/app.conf");
-        assert!(target_config.is_symlink());
-        assert!(target_app.is_symlink());
+    #[test]
+    fn non_utf8_file_names_are_tracked() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
 
-        // Verify cache was created
-        assert!(cache_path.exists());
+        let dir = test_dir("non_utf8");
+        let target_dir = dir.join("home");
+        let name = OsStr::from_bytes(b"caf\xe9.conf");
+        write_file(&dir, "dotfiles/plain.conf", "plain");
+        fs::write(dir.join("dotfiles").join(name), "latin1").unwrap();
+
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!(
+                "[files]\n\"dotfiles\" = \"{}/dotfiles\"",
+                target_dir.display()
+            ),
+        );
+        let cache_path = dir.join("state/state.toml");
+        let action = || {
+            Action::new(
+                vec![manifest_path.clone()],
+                cache_path.clone(),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        action().install().unwrap();
+
+        let target = target_dir.join("dotfiles").join(name);
+        assert!(target.is_symlink());
+        let cache = Cache::load(&OsFs, &cache_path).unwrap();
+        assert!(
+            cache
+                .all_entries()
+                .iter()
+                .any(|symlink| symlink.target == target)
+        );
 
-        let cache = Cache::load(&cache_path).unwrap();
-        assert_eq!(cache.files.len(), 2);
+        // A second install finds nothing stale, removing the source makes the link stale
+        action().install().unwrap();
+        assert!(target.is_symlink());
+        fs::remove_file(dir.join("dotfiles").join(name)).unwrap();
+        action().install().unwrap();
+        assert!(!target.is_symlink());
+        assert_eq!(Cache::load(&OsFs, &cache_path).unwrap().files.len(), 1);
+
+        fs::write(dir.join("dotfiles").join(name), "latin1").unwrap();
+        action().install().unwrap();
+        action().uninstall().unwrap();
+        assert!(!target.is_symlink());
+        assert!(!target_dir.join("dotfiles/plain.conf").is_symlink());
     }
 
     #[test]
-    fn install_removes_stale_entries() {
-        let dir = test_dir("stale");
+    fn eject_leaves_copies_behind() {
+        let dir = test_dir("eject");
         let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
-
-        // Create source dotfiles
-        write_file(&dir, "dotfiles/keep.conf", "keep");
-        write_file(&dir, "dotfiles/remove.conf", "remove");
+        write_file(&dir, "dotfiles/a.conf", "a");
+        write_file(&dir, "dotfiles/b.conf", "b");
 
-        // Create manifest with both files
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/target\"",
-            target_dir.display()
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            &format!(
+                "[files]\n\"a.conf\" = \"{t}/a.conf\"\n\"b.conf\" = \"{t}/b.conf\"",
+                t = target_dir.display()
+            ),
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
-        let cache_path = dir.join("cache/cache.toml");
-
-        // Install both files
-        let action = Action::new(manifest_path.clone(), cache_path.clone(), false, true);
-        action.install().unwrap();
-
-        assert!(target_dir.join("target/keep.conf").is_symlink());
-        assert!(target_dir.join("target/remove.conf").is_symlink());
+        let cache_path = dir.join("state/state.toml");
+        let action = |selection, dry_run| {
+            Action::new(
+                vec![manifest_path.clone()],
+                cache_path.clone(),
+                Layout::default(),
+                selection,
+                dry_run,
+                true,
+            )
+        };
+        action(Selection::default(), false).install().unwrap();
+
+        let only_a = Selection::new(vec![], vec![target_dir.join("a.conf")], vec![]);
+        action(only_a.clone(), true).eject().unwrap();
+        assert!(target_dir.join("a.conf").is_symlink());
+
+        action(only_a, false).eject().unwrap();
+        assert!(!target_dir.join("a.conf").is_symlink());
+        assert!(target_dir.join("b.conf").is_symlink());
+        assert_eq!(Cache::load(&OsFs, &cache_path).unwrap().files.len(), 1);
+
+        fs::remove_dir_all(dir.join("dotfiles")).unwrap();
+        assert_eq!(fs::read_to_string(target_dir.join("a.conf")).unwrap(), "a");
+    }
 
-        // Remove one file from source and reinstall
-        fs::remove_file(dir.join("dotfiles/remove.conf")).unwrap();
+    #[test]
+    fn install_from_bundle() {
+        let dir = test_dir("bundle");
+        write_file(&dir, "dotfiles/nvim/init.lua", "nvim");
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = \"~/.config/nvim\"",
+        );
+        let bundle_path = dir.join("dotfiles.tar");
+        let cache_path = dir.join("state/state.toml");
+        let action = |manifest_path| {
+            Action::new(
+                vec![manifest_path],
+                cache_path.clone(),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        action(manifest_path).bundle(&bundle_path).unwrap();
+
+        // The lab machine has its own home and no repository
+        fs::remove_dir_all(dir.join("dotfiles")).unwrap();
+        let home = dir.join("lab");
+        let layout = Layout::new(None, Some(home.clone()));
+        let manifest = unpack_bundle(&OsFs, &bundle_path, &layout, false)
+            .unwrap()
+            .unwrap();
+        let store = layout.bundle_dir().unwrap();
+        assert_eq!(manifest, store.join("manifest.toml"));
+
+        Action::new(
+            vec![manifest],
+            cache_path.clone(),
+            layout.clone(),
+            Selection::default(),
+            false,
+            true,
+        )
+        .install()
+        .unwrap();
+        let target = home.join(".config/nvim/init.lua");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "nvim");
+        assert!(fs::read_link(&target).unwrap().starts_with(&store));
+
+        // A truncated bundle is rejected and leaves the store alone
+        let archive = fs::read(&bundle_path).unwrap();
+        fs::write(&bundle_path, &archive[..archive.len() / 3]).unwrap();
+        assert!(unpack_bundle(&OsFs, &bundle_path, &layout, false).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "nvim");
+    }
 
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/target\"",
-            target_dir.display()
+    #[test]
+    fn unpacking_replaces_the_previous_bundle() {
+        let fs = MemFs::new();
+        fs.add_file("/src/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file("/src/dotfiles/git/config", "git");
+        let manifest_path = fs.add_file(
+            "/src/dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = \"~/.config/nvim\"\n\"git\" = \"~/.config/git\"",
         );
-        write_file(&dir, "manifest.toml", &manifest_content);
+        let manifest = Manifest::load(&fs, &manifest_path, &Layout::portable()).unwrap();
+        let bundle_path = Path::new("/out/dotfiles.tar");
+        fs.create_dir_all(Path::new("/out")).unwrap();
+        bundle::pack(&fs, &manifest, bundle_path).unwrap();
+
+        let layout = Layout::new(None, Some(PathBuf::from("/home/lab")));
+        let store = layout.bundle_dir().unwrap();
+        fs.add_file(store.join("sources/stale.conf"), "stale");
+        fs.add_file(store.with_extension("new").join("partial"), "partial");
+
+        unpack_bundle(&fs, bundle_path, &layout, false).unwrap();
+        assert!(fs.is_file(&store.join("sources/nvim/init.lua")));
+        assert!(!fs.exists(&store.join("sources/stale.conf")));
+        // Neither the staged nor the replaced bundle is left next to the store
+        assert_eq!(fs.read_dir(store.parent().unwrap()).unwrap(), vec![store]);
+    }
 
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
-        action.install().unwrap();
+    #[test]
+    fn exported_script_links_entries() {
+        let dir = test_dir("export_sh");
+        let source = write_file(&dir, "dotfiles/it's.conf", "quote");
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            "[files]\n\"it's.conf\" = \"~/.config/it's.conf\"\n\
+             \"ssh/config\" = { target = \"~/.ssh/keys/config\", mode = \"0600\", dir_mode = \"0700\" }",
+        );
+        write_file(&dir, "dotfiles/ssh/config", "ssh");
+        fs::set_permissions(
+            dir.join("dotfiles/ssh/config"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        let script = dir.join("install.sh");
+        Action::new(
+            vec![manifest_path],
+            dir.join("state/state.toml"),
+            Layout::default(),
+            Selection::default(),
+            false,
+            true,
+        )
+        .export(ExportFormat::Sh, Some(&script))
+        .unwrap();
+
+        let home = dir.join("home");
+        let run = || {
+            std::process::Command::new("sh")
+                .arg(&script)
+                .env("HOME", &home)
+                .output()
+                .unwrap()
+        };
+        assert!(run().status.success());
+        let target = home.join(".config/it's.conf");
+        assert_eq!(
+            fs::read_link(&target).unwrap(),
+            source.canonicalize().unwrap()
+        );
+        // Created directories get the directory mode, while the source keeps its mode
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&home.join(".ssh")), 0o700);
+        assert_eq!(mode(&home.join(".ssh/keys")), 0o700);
+        assert_eq!(mode(&dir.join("dotfiles/ssh/config")), 0o644);
+        // Links in place are skipped, other files are never replaced
+        assert!(run().status.success());
+        fs::remove_file(&target).unwrap();
+        fs::write(&target, "local").unwrap();
+        assert!(!run().status.success());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "local");
+    }
 
-        // keep.conf should still be installed
-        assert!(target_dir.join("target/keep.conf").is_symlink());
-        // remove.conf should be gone (stale entry removed)
-        assert!(!target_dir.join("target/remove.conf").exists());
+    #[test]
+    fn plan_script_checks_its_targets() {
+        let dir = test_dir("plan_script");
+        let source = write_file(&dir, "dotfiles/bashrc", "bashrc");
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            "[files]\n\"bashrc\" = \"~/.bashrc\"",
+        );
+        let home = dir.join("home");
+        let target = write_file(&home, ".bashrc", "existing");
+        let script = dir.join("plan.sh");
+        let cache_path = dir.join("state/state.toml");
+        let action = || {
+            Action::new(
+                vec![manifest_path.clone()],
+                cache_path.clone(),
+                Layout::new(None, Some(home.clone())),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        let run = || {
+            std::process::Command::new("sh")
+                .arg(&script)
+                .output()
+                .unwrap()
+        };
+
+        // The file is replaced after the plan was made, so the script refuses to move it
+        action().script(Some(&script)).unwrap();
+        let edited = write_file(&home, ".bashrc.new", "edited");
+        fs::rename(&edited, &target).unwrap();
+        let output = run();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("changed since the plan"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "edited");
+
+        action().script(Some(&script)).unwrap();
+        assert!(run().status.success());
+        assert_eq!(
+            fs::read_link(&target).unwrap(),
+            source.canonicalize().unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(home.join(".bashrc.bak")).unwrap(),
+            "edited"
+        );
 
-        let cache = Cache::load(&cache_path).unwrap();
+        // The script records the new link in the state as its last step
+        let cache = Cache::load(&OsFs, &cache_path).unwrap();
         assert_eq!(cache.files.len(), 1);
     }
 
     #[test]
-    fn uninstall_removes_all_and_deletes_cache() {
-        let dir = test_dir("uninstall");
-        let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
+    fn dry_run_changes_nothing() {
+        let dir = test_dir("dry_run");
+        write_file(&dir, "dotfiles/a.conf", "a");
+        let target = dir.join("home/a.conf");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"dotfiles/a.conf\" = \"{}\"", target.display()),
+        );
+        let cache_path = dir.join("cache.toml");
+
+        let action = Action::new(
+            vec![manifest_path],
+            cache_path.clone(),
+            Layout::default(),
+            Selection::default(),
+            true,
+            true,
+        );
+        action.install().unwrap();
+
+        assert!(!target.is_symlink());
+        assert!(!dir.join("home").exists());
+        assert!(!cache_path.exists());
+    }
 
+    #[test]
+    fn backup_policy_moves_conflicts_aside() {
+        let dir = test_dir("backup_policy");
         write_file(&dir, "dotfiles/a.conf", "a");
-        write_file(&dir, "dotfiles/b.conf", "b");
+        let target = write_file(&dir, "home/a.conf", "existing");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"dotfiles/a.conf\" = \"{}\"", target.display()),
+        );
 
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/target\"",
-            target_dir.display()
+        let action = |backup| {
+            Action::new(
+                vec![manifest_path.clone()],
+                dir.join("cache.toml"),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+            .backup(backup)
+        };
+        assert!(action(BackupPolicy::Never).install().is_err());
+        assert!(!target.is_symlink());
+
+        action(BackupPolicy::Always).install().unwrap();
+        assert!(target.is_symlink());
+        assert_eq!(
+            fs::read_to_string(dir.join("home/a.conf.bak")).unwrap(),
+            "existing"
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
-        let cache_path = dir.join("cache/cache.toml");
+    }
 
-        // Install first
-        let action = Action::new(manifest_path.clone(), cache_path.clone(), false, true);
-        action.install().unwrap();
+    #[test]
+    fn backup_policy_leaves_replaced_links_alone() {
+        let dir = test_dir("backup_policy_replaced");
+        write_file(&dir, "dotfiles/a.conf", "a");
+        let target = dir.join("home/a.conf");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"dotfiles/a.conf\" = \"{}\"", target.display()),
+        );
+        let action = || {
+            Action::new(
+                vec![manifest_path.clone()],
+                dir.join("cache.toml"),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+            .backup(BackupPolicy::Always)
+        };
+        action().install().unwrap();
+
+        // An editor saving the target replaces the link with a file
+        fs::remove_file(&target).unwrap();
+        write_file(&dir, "home/a.conf", "edited");
+
+        assert!(action().install().is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "edited");
+        assert!(!dir.join("home/a.conf.bak").exists());
+    }
 
-        assert!(target_dir.join("target/a.conf").is_symlink());
-        assert!(target_dir.join("target/b.conf").is_symlink());
-        assert!(cache_path.exists());
+    #[test]
+    fn install_replaces_identical_targets() {
+        let dir = test_dir("replace_identical");
+        write_file(&dir, "dotfiles/a.conf", "same");
+        let target = write_file(&dir, "home/a.conf", "same");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"dotfiles/a.conf\" = \"{}\"", target.display()),
+        );
 
-        // Uninstall
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
-        action.uninstall().unwrap();
+        let action = |replace_identical| {
+            Action::new(
+                vec![manifest_path.clone()],
+                dir.join("cache.toml"),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+            .replace_identical(replace_identical)
+        };
+
+        assert!(action(false).install().is_err());
+        assert!(!target.is_symlink());
+
+        action(true).install().unwrap();
+        assert!(target.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "same");
+    }
 
-        assert!(!target_dir.join("target/a.conf").exists());
-        assert!(!target_dir.join("target/b.conf").exists());
-        assert!(!cache_path.exists());
+    #[test]
+    fn install_decrypts_secrets_with_identity() {
+        let dir = test_dir("install_secret");
+        let identity = age::x25519::Identity::generate();
+        let identity_path = write_file(
+            &dir,
+            "identity.txt",
+            age::secrecy::ExposeSecret::expose_secret(&identity.to_string()),
+        );
+        let ciphertext = age::encrypt(&identity.to_public(), b"token").unwrap();
+        fs::write(dir.join("token.age"), ciphertext).unwrap();
+
+        let target = dir.join("home/token");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!(
+                "[files]\n\"token.age\" = {{ target = \"{}\", encrypted = true }}",
+                target.display()
+            ),
+        );
+        let cache_path = dir.join("cache.toml");
+
+        Action::new(
+            vec![manifest_path],
+            cache_path.clone(),
+            Layout::default(),
+            Selection::default(),
+            false,
+            true,
+        )
+        .identity(Some(identity_path))
+        .install()
+        .unwrap();
+
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "token");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let cache = Cache::load(&OsFs, &cache_path).unwrap();
+        assert_eq!(cache.secrets.len(), 1);
     }
 
     #[test]
-    fn idempotent_install() {
-        let dir = test_dir("idempotent");
-        let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
+    fn system_entries_are_installed_through_escalation_command() {
+        let dir = test_dir("install_system");
+        let root = dir.join("stage");
+
+        // Records every command it is asked to run before running it unprivileged
+        let log = dir.join("escalate.log");
+        let stub = write_file(
+            &dir,
+            "escalate.sh",
+            &format!("#!/bin/sh\necho \"$@\" >> {}\nexec \"$@\"\n", log.display()),
+        );
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        write_file(&dir, "dotfiles/keyd.conf", "keyd");
+        write_file(&dir, "dotfiles/zshrc", "zsh");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            "[files]\n\"dotfiles/zshrc\" = \"~/.zshrc\"\n\n\
+             [system]\n\"dotfiles/keyd.conf\" = \"/etc/keyd/default.conf\"",
+        );
 
-        write_file(&dir, "dotfiles/file.conf", "content");
+        let layout = Layout::new(Some(root.clone()), Some(PathBuf::from("/home/user")));
+        let cache_path = layout.state_path().unwrap();
+        let system_cache_path = layout.system_cache_path();
+        let action = || {
+            Action::new(
+                vec![manifest_path.clone()],
+                cache_path.clone(),
+                layout.clone(),
+                Selection::default(),
+                false,
+                true,
+            )
+            .escalate(&stub.to_string_lossy())
+        };
+
+        action().install().unwrap();
+
+        let link = root.join("etc/keyd/default.conf");
+        assert!(link.is_symlink());
+        assert_eq!(fs::read_to_string(&link).unwrap(), "keyd");
+        assert!(root.join("home/user/.zshrc").is_symlink());
 
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/target\"",
-            target_dir.display()
+        // Only the system entry is tracked in the system cache, and only it was escalated
+        let cache = Cache::load(&OsFs, &cache_path).unwrap();
+        let system_cache = Cache::load(&OsFs, &system_cache_path).unwrap();
+        assert_eq!(cache.files.len(), 1);
+        assert_eq!(system_cache.files.len(), 1);
+
+        let commands = fs::read_to_string(&log).unwrap();
+        assert!(commands.contains(&format!(
+                "ln -s -- {}",
+                dir.join("dotfiles/keyd.conf")
+                    .canonicalize()
+                    .unwrap()
+                    .display()
+            )));
+        assert!(!commands.contains(".zshrc"));
+
+        action().uninstall().unwrap();
+
+        assert!(!link.exists());
+        assert!(!system_cache_path.exists());
+        assert!(fs::read_to_string(&log).unwrap().contains("rm --"));
+    }
+
+    #[test]
+    fn install_under_alternate_root() {
+        let dir = test_dir("root");
+        let root = dir.join("stage");
+
+        write_file(&dir, "dotfiles/shell.rc", "rc");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            "[files]\n\"dotfiles/shell.rc\" = \"~/.shellrc\"",
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
-        let cache_path = dir.join("cache/cache.toml");
 
-        // Install twice - second should be a no-op
-        let action = Action::new(manifest_path.clone(), cache_path.clone(), false, true);
-        action.install().unwrap();
+        let layout = Layout::new(Some(root.clone()), Some(PathBuf::from("/home/user")));
+        let cache_path = layout.state_path().unwrap();
 
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
+        let action = Action::new(
+            vec![manifest_path],
+            cache_path.clone(),
+            layout,
+            Selection::default(),
+            false,
+            true,
+        );
         action.install().unwrap();
 
-        assert!(target_dir.join("target/file.conf").is_symlink());
-
-        let cache = Cache::load(&cache_path).unwrap();
-        assert_eq!(cache.files.len(), 1);
+        let link = root.join("home/user/.shellrc");
+        assert!(link.is_symlink());
+        assert_eq!(
+            fs::read_link(&link).unwrap(),
+            dir.join("dotfiles/shell.rc").canonicalize().unwrap()
+        );
+        assert!(
+            root.join("home/user/.local/state/homage/state.toml")
+                .exists()
+        );
     }
 
     #[test]
-    fn install_aborts_on_existing_target_files() {
-        let dir = test_dir("conflict");
-        let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
+    fn state_is_moved_out_of_the_cache_directory() {
+        let dir = test_dir("state_migration");
+        let layout = Layout::new(Some(dir.to_path_buf()), Some(PathBuf::from("/home/user")));
+        let legacy = write_file(
+            &dir,
+            "home/user/.cache/homage/cache.toml",
+            "[files]\n\"/src/a\" = \"/target/a\"\n",
+        );
+        let state = dir.join("home/user/.local/state/homage/state.toml");
 
-        write_file(&dir, "dotfiles/a.conf", "a");
-        write_file(&dir, "dotfiles/b.conf", "b");
+        // A dry run reads the old cache where it is
+        assert_eq!(state_file(&layout, true).unwrap(), legacy);
+        assert!(legacy.exists());
 
-        // Pre-create target files so they conflict
-        write_file(&target_dir, "target/a.conf", "existing a");
-        write_file(&target_dir, "target/b.conf", "existing b");
+        assert_eq!(state_file(&layout, false).unwrap(), state);
+        assert!(!legacy.exists());
+        assert_eq!(Cache::load(&OsFs, &state).unwrap().files.len(), 1);
+    }
 
-        let manifest_content = format!(
-            "[files]\n\"dotfiles\" = \"{}/target\"",
-            target_dir.display()
+    #[test]
+    fn lost_state_is_rebuilt_from_links() {
+        let dir = test_dir("state_rebuild");
+        let target_dir = dir.join("home");
+        write_file(&dir, "dotfiles/a.conf", "a");
+        write_file(&dir, "dotfiles/old.conf", "old");
+
+        let manifest_path = write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            &format!(
+                "[files]\n\"a.conf\" = \"{t}/a.conf\"\n\"old.conf\" = \"{t}/old.conf\"",
+                t = target_dir.display()
+            ),
+        );
+        let cache_path = dir.join("state/state.toml");
+        let action = || {
+            Action::new(
+                vec![manifest_path.clone()],
+                cache_path.clone(),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        action().install().unwrap();
+
+        // Lose the state and drop an entry from the manifest
+        fs::remove_file(&cache_path).unwrap();
+        write_file(
+            &dir,
+            "dotfiles/manifest.toml",
+            &format!("[files]\n\"a.conf\" = \"{}/a.conf\"", target_dir.display()),
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
-        let cache_path = dir.join("cache/cache.toml");
 
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
-        let result = action.install();
+        action().rebuild_state().unwrap();
+        assert_eq!(Cache::load(&OsFs, &cache_path).unwrap().files.len(), 2);
 
-        // Should fail listing all conflicts
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("a.conf"));
-        assert!(err.contains("b.conf"));
+        // The link of the removed entry is stale again and gets cleaned up
+        action().install().unwrap();
+        assert!(!target_dir.join("old.conf").exists());
+        assert!(target_dir.join("a.conf").is_symlink());
+    }
 
-        // Nothing should have been modified
-        assert!(!cache_path.exists());
-        assert!(!target_dir.join("target/a.conf").is_symlink());
-        assert!(!target_dir.join("target/b.conf").is_symlink());
-        // Original files should be untouched
+    #[test]
+    fn moved_repository_is_relocated_on_install() {
+        let dir = test_dir("relocate_install");
+        let target_dir = dir.join("home");
+        write_file(&dir, "old/dotfiles/a.conf", "a");
+        write_file(&dir, "old/dotfiles/nvim/init.lua", "nvim");
+        write_file(
+            &dir,
+            "old/dotfiles/manifest.toml",
+            &format!(
+                "[files]\n\"a.conf\" = \"{t}/a.conf\"\n\"nvim\" = \"{t}/.config/nvim\"",
+                t = target_dir.display()
+            ),
+        );
+        let cache_path = dir.join("state/state.toml");
+        let action = |manifest_path: PathBuf| {
+            Action::new(
+                vec![manifest_path],
+                cache_path.clone(),
+                Layout::default(),
+                Selection::default(),
+                false,
+                true,
+            )
+        };
+        action(dir.join("old/dotfiles/manifest.toml"))
+            .install()
+            .unwrap();
+
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::rename(dir.join("old/dotfiles"), dir.join("new/dotfiles")).unwrap();
+        action(dir.join("new/dotfiles/manifest.toml"))
+            .install()
+            .unwrap();
+
+        let new_root = dir.join("new/dotfiles").canonicalize().unwrap();
         assert_eq!(
-            fs::read_to_string(target_dir.join("target/a.conf")).unwrap(),
-            "existing a"
+            fs::read_link(target_dir.join("a.conf")).unwrap(),
+            new_root.join("a.conf")
         );
         assert_eq!(
-            fs::read_to_string(target_dir.join("target/b.conf")).unwrap(),
-            "existing b"
+            fs::read_link(target_dir.join(".config/nvim/init.lua")).unwrap(),
+            new_root.join("nvim/init.lua")
+        );
+        let cache = Cache::load(&OsFs, &cache_path).unwrap();
+        assert!(
+            cache
+                .files
+                .keys()
+                .all(|src| src.starts_with(new_root.to_str().unwrap()))
         );
     }
 
     #[test]
-    fn install_allows_stale_target_reuse() {
-        let dir = test_dir("stale_reuse");
-        let target_dir = dir.join("home");
-        fs::create_dir_all(&target_dir).unwrap();
-
-        // First: install file_a.conf -> target/shared.conf
-        write_file(&dir, "file_a.conf", "a");
-        let manifest_v1 = format!(
-            "[files]\n\"file_a.conf\" = \"{}/target/shared.conf\"",
-            target_dir.display()
+    fn corrupted_state_is_moved_aside() {
+        let dir = test_dir("state_corrupted");
+        write_file(&dir, "a.conf", "a");
+        let target = dir.join("home/a.conf");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"a.conf\" = \"{}\"", target.display()),
         );
-        let manifest_path = write_file(&dir, "manifest.toml", &manifest_v1);
-        let cache_path = dir.join("cache/cache.toml");
-
-        let action = Action::new(manifest_path.clone(), cache_path.clone(), false, true);
-        action.install().unwrap();
-        assert!(target_dir.join("target/shared.conf").is_symlink());
-
-        // Second: change manifest so file_b.conf -> target/shared.conf (same target, different source)
-        write_file(&dir, "file_b.conf", "b");
-        let manifest_v2 = format!(
-            "[files]\n\"file_b.conf\" = \"{}/target/shared.conf\"",
-            target_dir.display()
+        let cache_path = write_file(&dir, "state/state.toml", "files = [");
+
+        Action::new(
+            vec![manifest_path],
+            cache_path.clone(),
+            Layout::default(),
+            Selection::default(),
+            false,
+            true,
+        )
+        .install()
+        .unwrap();
+
+        assert!(target.is_symlink());
+        assert_eq!(
+            fs::read_to_string(dir.join("state/state.toml.bak")).unwrap(),
+            "files = ["
         );
-        write_file(&dir, "manifest.toml", &manifest_v2);
+        assert_eq!(Cache::load(&OsFs, &cache_path).unwrap().files.len(), 1);
+    }
 
-        // This should succeed: old symlink is stale and will be removed before installing new one
-        let action = Action::new(manifest_path, cache_path.clone(), false, true);
-        action.install().unwrap();
+    #[test]
+    fn read_only_commands_keep_a_corrupted_state() {
+        let dir = test_dir("state_corrupted_read_only");
+        write_file(&dir, "a.conf", "a");
+        let manifest_path = write_file(
+            &dir,
+            "manifest.toml",
+            &format!(
+                "[files]\n\"a.conf\" = \"{}\"",
+                dir.join("home/a.conf").display()
+            ),
+        );
+        let cache_path = write_file(&dir, "state/state.toml", "files = [");
+
+        let action = Action::new(
+            vec![manifest_path],
+            cache_path.clone(),
+            Layout::default(),
+            Selection::default(),
+            false,
+            true,
+        );
+        action.diff(false).unwrap();
+        action.status().unwrap();
+        action.script(None).unwrap();
 
-        let link = target_dir.join("target/shared.conf");
-        assert!(link.is_symlink());
-        assert_eq!(fs::read_to_string(&link).unwrap(), "b");
+        assert_eq!(fs::read_to_string(&cache_path).unwrap(), "files = [");
+        assert!(!dir.join("state/state.toml.bak").exists());
     }
 }
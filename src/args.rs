@@ -1,10 +1,18 @@
 use std::io::Write;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use anyhow::anyhow;
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
 use log::{Level, LevelFilter};
 
+use crate::action::DEFAULT_ESCALATE;
+use crate::completions::{Shell, manifest_completer, target_completer};
+use crate::config::{BackupPolicy, ColorMode, Config, OutputFormat};
+use homage::fs::OsFs;
+use homage::selection::Selection;
+use homage::utils::{Layout, discover_manifest};
+
 #[derive(Debug, Parser, Clone)]
 #[command(author, version, about, long_about)]
 pub struct Args {
@@ -27,6 +35,43 @@ pub struct Args {
     /// Whether to only print error messages, disables the 'verbosity' arg.
     #[arg(long, global = true)]
     pub quiet: bool,
+
+    /// Places every target path and the cache below this directory, for staging an install.
+    /// Symlinks still point to the real source files.
+    #[arg(long, value_name = "DIR", global = true)]
+    root: Option<PathBuf>,
+
+    /// Uses this directory instead of $HOME when expanding '~' in target paths.
+    #[arg(long, value_name = "DIR", global = true)]
+    home: Option<PathBuf>,
+
+    /// When to use colored output, defaults to the configured value or 'auto'.
+    #[arg(long, value_enum, global = true)]
+    color: Option<ColorMode>,
+
+    /// The age identity file to decrypt secrets with, defaults to the configured identity.
+    /// Without an identity the passphrase is read from $HOMAGE_PASSPHRASE or prompted for.
+    #[arg(long, value_name = "FILE", global = true, value_hint = clap::ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+
+    /// The command `[system]` entries are installed through, defaults to the configured command or 'sudo'.
+    /// Any command running its arguments works, such as "doas" or "sudo -n".
+    #[arg(long, value_name = "CMD", global = true)]
+    pub escalate: Option<String>,
+
+    /// Selects the entries tagged by this profile from the config when no '--tag' is given,
+    /// defaults to the configured profile.
+    #[arg(long, value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
+    /// What an install does with targets occupied by other files, defaults to the configured
+    /// policy or 'never'. Ignored with '--interactive'.
+    #[arg(long, value_enum, value_name = "POLICY", global = true)]
+    pub backup: Option<BackupPolicy>,
+
+    /// How 'status' prints the entries, defaults to the configured format or 'text'.
+    #[arg(long, value_enum, value_name = "FORMAT", global = true)]
+    pub output_format: Option<OutputFormat>,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -34,15 +79,321 @@ pub enum ActionType {
     /// Installs dotfiles by symlinking files specified in the manifest.
     Install {
         /// Path to the manifest TOML file.
-        manifest: PathBuf,
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer(), conflicts_with = "bundle")]
+        manifest: Option<PathBuf>,
+
+        /// Installs from a bundle created by 'homage bundle' instead of a manifest.
+        /// The bundle is verified and unpacked into the local store first.
+        #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        bundle: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Prompts how to resolve each target that is occupied by another file.
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Replaces targets that are identical to their source with a link.
+        #[arg(long)]
+        replace_identical: bool,
+    },
+    /// Shows the changes an install would make and the targets occupied by other files.
+    Diff {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Shows a diff between each occupied target and its source.
+        #[arg(long)]
+        content: bool,
+
+        /// Replaces targets that are identical to their source with a link.
+        #[arg(long)]
+        replace_identical: bool,
+    },
+    /// Writes the operations an install would run as a shell script for review.
+    /// Without '--script' this is an alias of 'diff'.
+    Plan {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Writes a POSIX shell script running the plan, where every step first checks that its
+        /// target is still as planned. Occupied targets are backed up.
+        #[arg(long)]
+        script: bool,
+
+        /// Where to write the script, defaults to standard output.
+        #[arg(short, long, value_name = "FILE", requires = "script", value_hint = clap::ValueHint::FilePath)]
+        output: Option<PathBuf>,
+
+        /// Replaces targets that are identical to their source with a link.
+        #[arg(long)]
+        replace_identical: bool,
     },
     /// Uninstalls all managed dotfiles referenced by the supplied manifest.
     Uninstall {
         /// Path to the manifest TOML file.
-        manifest: PathBuf,
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer(), conflicts_with = "all")]
+        manifest: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Uninstalls every link recorded in the state without loading a manifest,
+        /// for when the repository was moved, deleted or the manifest no longer parses.
+        #[arg(long, visible_alias = "from-state")]
+        all: bool,
+    },
+    /// Replaces the installed links with copies of their sources and stops managing them,
+    /// so the targets keep working without the repository.
+    Eject {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+    },
+    /// Shows the state of each target, including secrets that changed since they were installed.
+    Status {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+    },
+    /// Packs the sources of a manifest into a tar archive, for installing with 'install --bundle'
+    /// on machines without access to the repository.
+    Bundle {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        /// The archive to write.
+        #[arg(short, long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        output: PathBuf,
+    },
+    /// Writes the resolved entries of a manifest in the format of another system.
+    Export {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+
+        /// The format to write.
+        #[arg(short, long, value_enum)]
+        format: ExportFormat,
+
+        /// Where to write the export, defaults to standard output.
+        /// Required for 'stow', which writes a directory.
+        #[arg(short, long, value_name = "PATH", required_if_eq("format", "stow"))]
+        output: Option<PathBuf>,
+    },
+    /// Moves the installed links along with a moved dotfiles repository,
+    /// re-pointing every link below the old root at the same path below the new root.
+    Relocate {
+        /// The directory the sources were moved from.
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        from: PathBuf,
+
+        /// The directory the sources were moved to.
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        to: PathBuf,
+    },
+    /// Generates a manifest from the setup of another dotfiles tool, reporting what could not be
+    /// translated.
+    Import {
+        #[clap(subcommand)]
+        action: ImportAction,
+    },
+    /// Inspects and repairs the record of installed links.
+    State {
+        #[clap(subcommand)]
+        action: StateAction,
+    },
+    /// Encrypts and edits secret files.
+    Secret {
+        #[clap(subcommand)]
+        action: SecretAction,
+    },
+    /// Checks a manifest and its includes, reporting every problem found.
+    /// Exits with a non-zero status if any problem was found.
+    Check {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+    },
+    /// Prints the completion script for the given shell.
+    Completions {
+        /// Shell to generate completions for.
+        shell: Shell,
+    },
+    /// Generates man pages for homage and all of its subcommands.
+    Man {
+        /// Directory to write the man pages to.
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        out_dir: PathBuf,
+    },
+    /// Inspects the user configuration.
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
     },
 }
 
+/// Filters restricting an action to a subset of the manifest entries.
+#[derive(Debug, Clone, ClapArgs)]
+pub struct SelectionArgs {
+    /// Only act on entries with this tag, may be given multiple times.
+    /// Replaces the tags of the profile.
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Only act on entries whose target is, or is located below, this path.
+    #[arg(long = "target", visible_alias = "only", value_name = "PATH", add = target_completer())]
+    targets: Vec<String>,
+
+    /// Only act on entries whose source is, or is located below, this path.
+    #[arg(long = "source", value_name = "PATH")]
+    sources: Vec<PathBuf>,
+}
+
+impl SelectionArgs {
+    /// Resolves the filters into a selection, using the tags of the profile unless tags were
+    /// given. Targets are expanded like manifest targets and sources are resolved against the
+    /// current directory.
+    pub fn selection(&self, layout: &Layout, profile: &[String]) -> anyhow::Result<Selection> {
+        let targets = self
+            .targets
+            .iter()
+            .map(|target| layout.target(target))
+            .collect::<homage::Result<_>>()?;
+
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| {
+                source
+                    .canonicalize()
+                    .or_else(|_| std::path::absolute(source))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        let tags = match self.tags.is_empty() {
+            true => profile.to_vec(),
+            false => self.tags.clone(),
+        };
+        Ok(Selection::new(tags, targets, sources))
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum SecretAction {
+    /// Encrypts a file with the identity or a passphrase, to be added as an encrypted entry.
+    Encrypt {
+        /// The file to encrypt.
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        file: PathBuf,
+
+        /// Where to write the encrypted file, defaults to the file with an '.age' extension.
+        #[arg(short, long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Decrypts a secret into a private temporary file, opens it in $VISUAL or $EDITOR
+    /// and encrypts it again once the editor exits.
+    Edit {
+        /// The encrypted file to edit.
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum StateAction {
+    /// Rebuilds a lost or corrupted state by scanning the targets of the manifest, and the
+    /// directories holding them, for links into the source tree.
+    Rebuild {
+        /// Path to the manifest TOML file.
+        /// Defaults to the configured manifests or a discovered 'manifest.toml'/'homage.toml'.
+        #[arg(add = manifest_completer())]
+        manifest: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ImportAction {
+    /// Imports a GNU Stow directory, tagging the files of each package with its name.
+    Stow {
+        /// The stow directory holding the packages.
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        dir: PathBuf,
+
+        /// The directory the packages are linked into. Defaults to the parent of the stow directory.
+        #[arg(short, long, value_name = "DIR")]
+        target: Option<String>,
+
+        /// The manifest to write. Defaults to 'manifest.toml' in the stow directory.
+        #[arg(short, long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Imports the links of a Dotbot config.
+    Dotbot {
+        /// The Dotbot config, such as 'install.conf.yaml'.
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        config: PathBuf,
+
+        /// The manifest to write. Defaults to 'manifest.toml' next to the config.
+        #[arg(short, long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Imports the plain files of a chezmoi source directory.
+    Chezmoi {
+        /// The chezmoi source directory, see 'chezmoi source-path'.
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        dir: PathBuf,
+
+        /// The manifest to write. Defaults to 'manifest.toml' in the source directory.
+        #[arg(short, long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// The formats entries are exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// A home-manager module setting 'home.file'.
+    Nix,
+    /// A GNU Stow directory with a package per tag.
+    Stow,
+    /// A POSIX shell script creating the links.
+    Sh,
+    /// A JSON array of the entries with all of their settings.
+    Json,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigAction {
+    /// Prints the effective settings after merging command line flags over the config file.
+    Show,
+}
+
 impl Args {
     pub fn parse_args() -> Args {
         let mut cli = Args::parse();
@@ -50,6 +401,93 @@ impl Args {
         cli
     }
 
+    /// Fills in every setting not given on the command line from the config.
+    pub fn merge(&mut self, config: &Config) {
+        self.no_confirm = self.no_confirm || config.no_confirm.unwrap_or(false);
+
+        if self.verbosity == 0 {
+            self.verbosity = std::cmp::min(3, config.verbosity.unwrap_or(0));
+        }
+
+        self.color = self.color.or(config.color);
+        self.identity = self.identity.take().or_else(|| config.identity.clone());
+        self.escalate = self.escalate.take().or_else(|| config.escalate.clone());
+        self.profile = self.profile.take().or_else(|| config.profile.clone());
+        self.backup = self.backup.or(config.backup);
+        self.output_format = self.output_format.or(config.output_format);
+    }
+
+    /// Returns the tags selected by the active profile, none without a profile.
+    pub fn profile_tags(&self, config: &Config) -> anyhow::Result<Vec<String>> {
+        match &self.profile {
+            Some(profile) => config.profile_tags(profile),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Resolves which manifests to operate on. An explicitly supplied manifest wins,
+    /// followed by the manifests from the config and finally a manifest discovered
+    /// in the current directory or its git root.
+    pub fn manifests(
+        &self,
+        manifest: Option<PathBuf>,
+        config: &Config,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        if let Some(manifest) = manifest {
+            return Ok(vec![manifest]);
+        }
+
+        if !config.manifests.is_empty() {
+            return Ok(config.manifests.clone());
+        }
+
+        let cwd = std::env::current_dir()?;
+        discover_manifest(&OsFs, &cwd).map(|path| vec![path]).ok_or_else(|| {
+            anyhow!(
+                "No manifest specified, none configured and no 'manifest.toml' or 'homage.toml' found in {}",
+                cwd.display()
+            )
+        })
+    }
+
+    /// Returns the target layout described by the '--root' and '--home' flags.
+    pub fn layout(&self) -> anyhow::Result<Layout> {
+        let root = self.root.as_deref().map(std::path::absolute).transpose()?;
+        let home = self.home.as_deref().map(std::path::absolute).transpose()?;
+        Ok(Layout::new(root, home))
+    }
+
+    /// Returns the effective settings as a config, used for displaying them.
+    pub fn effective_config(&self, manifests: Vec<PathBuf>, config: &Config) -> Config {
+        Config {
+            manifests,
+            color: Some(self.color.unwrap_or_default()),
+            no_confirm: Some(self.no_confirm),
+            verbosity: Some(self.verbosity),
+            identity: self.identity.clone(),
+            escalate: Some(self.escalation_command()),
+            profile: self.profile.clone(),
+            profiles: config.profiles.clone(),
+            backup: Some(self.backup.unwrap_or_default()),
+            output_format: Some(self.output_format.unwrap_or_default()),
+        }
+    }
+
+    /// Returns the command `[system]` entries are installed through.
+    pub fn escalation_command(&self) -> String {
+        self.escalate
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ESCALATE.to_string())
+    }
+
+    pub fn init_color(&self) {
+        match self.color.unwrap_or_default() {
+            ColorMode::Auto => {}
+            ColorMode::Always => colored::control::set_override(true),
+            ColorMode::Never => colored::control::set_override(false),
+        }
+    }
+
     pub fn init_logger(&self) {
         env_logger::builder()
             .filter_level(self.log_level())
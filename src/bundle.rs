@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+use crate::manifest::{Entry, Manifest};
+use crate::secret::hash;
+
+/// Name of the normalized manifest within a bundle.
+pub const MANIFEST: &str = "manifest.toml";
+
+/// Name of the checksum index within a bundle, in the format written by `sha256sum`.
+pub const CHECKSUMS: &str = "SHA256SUMS";
+
+/// Directory holding the source files within a bundle.
+const SOURCES: &str = "sources";
+
+/// A file packed into a bundle.
+struct Member {
+    content: Vec<u8>,
+    mode: u32,
+}
+
+/// Packs the sources of a manifest into a tar archive at `output`, for installing on machines
+/// without access to the repository. The archive holds the sources below a `sources`
+/// directory, a normalized manifest mapping them to their targets and a checksum index of both.
+/// Targets are written as resolved, load the manifest with [`Layout::portable`] to keep `~`.
+/// Entries with a path that is not valid UTF-8 can not be listed in the manifest and are left
+/// out. Returns the entries left out, with the reason.
+///
+/// [`Layout::portable`]: crate::utils::Layout::portable
+pub fn pack(fs: &dyn Fs, manifest: &Manifest, output: &Path) -> Result<Vec<String>> {
+    let root = common_root(manifest.entries.keys());
+
+    let mut members = BTreeMap::new();
+    let mut skipped = Vec::new();
+    let mut files = toml::Table::new();
+    let mut system = toml::Table::new();
+    for (source, entry) in &manifest.entries {
+        let rel = source.strip_prefix(&root).unwrap_or(source);
+        let name = Path::new(SOURCES).join(rel);
+        let (Some(name), Some(target)) = (name.to_str(), entry.target.to_str()) else {
+            skipped.push(format!(
+                "{}: the path is not valid UTF-8",
+                entry.target.display()
+            ));
+            continue;
+        };
+
+        let content = fs
+            .read(source)
+            .map_err(|err| Error::io("Failed to read source", source, err))?;
+        let mode = fs
+            .metadata(source)
+            .map_err(|err| Error::io("Failed to read source", source, err))?
+            .mode
+            & 0o7777;
+        members.insert(name.to_string(), Member { content, mode });
+
+        let table = if entry.system {
+            &mut system
+        } else {
+            &mut files
+        };
+        table.insert(name.to_string(), normalize(entry, target));
+    }
+
+    let mut normalized = toml::Table::new();
+    normalized.insert("files".to_string(), files.into());
+    if !system.is_empty() {
+        normalized.insert("system".to_string(), system.into());
+    }
+    let content = toml::to_string_pretty(&normalized)?;
+    members.insert(
+        MANIFEST.to_string(),
+        Member {
+            content: content.into_bytes(),
+            mode: 0o644,
+        },
+    );
+
+    let checksums: String = members
+        .iter()
+        .map(|(name, member)| format!("{}  {}\n", hash(&member.content), name))
+        .collect();
+
+    let archive = || {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, CHECKSUMS, checksums.as_bytes(), 0o644)?;
+        for (name, member) in &members {
+            append(&mut builder, name, &member.content, member.mode)?;
+        }
+        builder.into_inner()
+    };
+    let archive = archive().map_err(|err| Error::io("Failed to create bundle", output, err))?;
+
+    fs.write(output, &archive)
+        .map_err(|err| Error::io("Failed to write bundle", output, err))?;
+    Ok(skipped)
+}
+
+/// Verifies the bundle at `path` against its checksum index without unpacking it.
+/// Returns the number of sources it holds.
+pub fn verify(fs: &dyn Fs, path: &Path) -> Result<usize> {
+    Ok(verified_members(fs, path)?.len() - 1)
+}
+
+/// Verifies the bundle at `path` against its checksum index and unpacks it into `dir`.
+/// Nothing is written unless every file matches the index and the index covers every file,
+/// which rejects truncated and modified bundles. Returns the path of the unpacked manifest.
+pub fn unpack(fs: &dyn Fs, path: &Path, dir: &Path) -> Result<PathBuf> {
+    let members = verified_members(fs, path)?;
+
+    for (name, member) in &members {
+        let file = dir.join(name);
+        if let Some(parent) = file.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create directory", parent, err))?;
+        }
+        fs.write(&file, &member.content)
+            .map_err(|err| Error::io("Failed to unpack", &file, err))?;
+        fs.set_mode(&file, member.mode)
+            .map_err(|err| Error::io("Failed to unpack", &file, err))?;
+    }
+
+    Ok(dir.join(MANIFEST))
+}
+
+// Reads the files of the bundle, failing unless they match the checksum index exactly.
+// The index itself is not returned.
+fn verified_members(fs: &dyn Fs, path: &Path) -> Result<BTreeMap<String, Member>> {
+    let invalid = |message: String| Error::InvalidBundle {
+        path: path.to_path_buf(),
+        message,
+    };
+
+    let archive = fs
+        .read(path)
+        .map_err(|err| Error::io("Failed to read bundle", path, err))?;
+    let mut members = read_members(&archive).map_err(|err| invalid(err.to_string()))?;
+
+    let checksums = members
+        .remove(CHECKSUMS)
+        .ok_or_else(|| invalid(format!("missing checksum index {CHECKSUMS}")))?;
+    let checksums = String::from_utf8(checksums.content)
+        .map_err(|_| invalid(format!("{CHECKSUMS} is not valid UTF-8")))?;
+
+    let mut listed = BTreeSet::new();
+    for line in checksums.lines().filter(|line| !line.is_empty()) {
+        let (checksum, name) = line
+            .split_once("  ")
+            .ok_or_else(|| invalid(format!("malformed line in {CHECKSUMS}: {line}")))?;
+        if !listed.insert(name) {
+            return Err(invalid(format!("{name} is listed twice in {CHECKSUMS}")));
+        }
+        let member = members
+            .get(name)
+            .ok_or_else(|| invalid(format!("missing {name}")))?;
+        if hash(&member.content) != checksum {
+            return Err(invalid(format!("checksum mismatch for {name}")));
+        }
+    }
+
+    if !listed
+        .iter()
+        .copied()
+        .eq(members.keys().map(String::as_str))
+    {
+        return Err(invalid(format!("files not listed in {CHECKSUMS}")));
+    }
+    if !members.contains_key(MANIFEST) {
+        return Err(invalid(format!("missing {MANIFEST}")));
+    }
+
+    Ok(members)
+}
+
+// Reads every regular file of the archive. Paths leaving the unpack directory are refused.
+fn read_members(archive: &[u8]) -> std::io::Result<BTreeMap<String, Member>> {
+    let mut members = BTreeMap::new();
+
+    for entry in tar::Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let safe = path.components().all(|c| matches!(c, Component::Normal(_)));
+        let name = path
+            .to_str()
+            .filter(|_| safe)
+            .ok_or_else(|| std::io::Error::other(format!("unexpected path {}", path.display())))?;
+        let name = name.to_string();
+        if !entry.header().entry_type().is_file() {
+            return Err(std::io::Error::other(format!("{name} is not a file")));
+        }
+
+        let mode = entry.header().mode()? & 0o7777;
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        members.insert(name, Member { content, mode });
+    }
+
+    Ok(members)
+}
+
+fn append(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    content: &[u8],
+    mode: u32,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(mode);
+    header.set_cksum();
+    builder.append_data(&mut header, name, content)
+}
+
+// Returns the manifest value of an entry, a plain target unless it has settings.
+fn normalize(entry: &Entry, target: &str) -> toml::Value {
+    let plain = entry.tags.is_empty()
+        && !entry.encrypted
+        && !entry.hardlink
+        && entry.modes.file.is_none()
+        && entry.modes.dir.is_none();
+    if plain {
+        return target.into();
+    }
+
+    let mut table = toml::Table::new();
+    table.insert("target".to_string(), target.into());
+    if !entry.tags.is_empty() {
+        let tags: Vec<toml::Value> = entry.tags.iter().map(|tag| tag.as_str().into()).collect();
+        table.insert("tags".to_string(), tags.into());
+    }
+    if entry.encrypted {
+        table.insert("encrypted".to_string(), true.into());
+    }
+    if entry.hardlink {
+        table.insert("link".to_string(), "hardlink".into());
+    }
+    if let Some(mode) = entry.modes.file {
+        table.insert("mode".to_string(), format!("{mode:04o}").into());
+    }
+    if let Some(mode) = entry.modes.dir {
+        table.insert("dir_mode".to_string(), format!("{mode:04o}").into());
+    }
+
+    table.into()
+}
+
+// Returns the deepest directory containing all of the given files.
+fn common_root<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> PathBuf {
+    let mut root: Option<PathBuf> = None;
+
+    for path in paths {
+        let parent = path.parent().unwrap_or(Path::new("/"));
+        root = Some(match root {
+            None => parent.to_path_buf(),
+            Some(root) => root
+                .ancestors()
+                .find(|ancestor| parent.starts_with(ancestor))
+                .unwrap_or(Path::new("/"))
+                .to_path_buf(),
+        });
+    }
+
+    root.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+    use crate::utils::Layout;
+
+    fn bundle(fs: &MemFs) -> PathBuf {
+        fs.add_file("/src/dotfiles/nvim/init.lua", "vim.opt.number = true");
+        fs.add_file("/src/dotfiles/ssh/config", "ssh");
+        fs.add_file("/src/dotfiles/keyd.conf", "keyd");
+        let path = fs.add_file(
+            "/src/dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = \"~/.config/nvim\"\n\
+             \"ssh/config\" = { target = \"~/.ssh/config\", mode = \"0600\", tags = [\"ssh\"] }\n\
+             [system]\n\"keyd.conf\" = \"/etc/keyd/default.conf\"",
+        );
+        let manifest = Manifest::load(fs, &path, &Layout::portable()).unwrap();
+
+        let output = Path::new("/out/dotfiles.tar");
+        fs.create_dir_all(Path::new("/out")).unwrap();
+        assert!(pack(fs, &manifest, output).unwrap().is_empty());
+        output.to_path_buf()
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let fs = MemFs::new();
+        let output = bundle(&fs);
+
+        let path = unpack(&fs, &output, Path::new("/store")).unwrap();
+        assert_eq!(path, Path::new("/store/manifest.toml"));
+
+        let layout = Layout::new(None, Some(PathBuf::from("/home/lab")));
+        let manifest = Manifest::load(&fs, &path, &layout).unwrap();
+        let entry = &manifest.entries[Path::new("/store/sources/ssh/config")];
+        assert_eq!(entry.target, Path::new("/home/lab/.ssh/config"));
+        assert_eq!(entry.modes.file, Some(0o600));
+        assert!(entry.tags.contains("ssh"));
+        assert_eq!(
+            manifest.entries[Path::new("/store/sources/nvim/init.lua")].target,
+            Path::new("/home/lab/.config/nvim/init.lua")
+        );
+        assert!(manifest.entries[Path::new("/store/sources/keyd.conf")].system);
+        assert_eq!(
+            fs.read_to_string(Path::new("/store/sources/nvim/init.lua"))
+                .unwrap(),
+            "vim.opt.number = true"
+        );
+    }
+
+    #[test]
+    fn non_utf8_sources_are_left_out() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fs = MemFs::new();
+        fs.add_file("/src/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file(
+            Path::new("/src/dotfiles/nvim").join(OsStr::from_bytes(b"caf\xe9.lua")),
+            "latin1",
+        );
+        let path = fs.add_file(
+            "/src/dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = \"~/.config/nvim\"",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::portable()).unwrap();
+
+        let output = Path::new("/out/dotfiles.tar");
+        fs.create_dir_all(Path::new("/out")).unwrap();
+        let skipped = pack(&fs, &manifest, output).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].ends_with(": the path is not valid UTF-8"));
+        assert_eq!(verify(&fs, output).unwrap(), 1);
+    }
+
+    #[test]
+    fn tampered_bundles_are_rejected() {
+        let fs = MemFs::new();
+        let output = bundle(&fs);
+        let archive = fs.read(&output).unwrap();
+
+        let position = archive.windows(7).position(|w| w == b"vim.opt").unwrap();
+        let mut tampered = archive.clone();
+        tampered[position] = b'N';
+        fs.write(&output, &tampered).unwrap();
+        let err = unpack(&fs, &output, Path::new("/store")).unwrap_err();
+        assert!(
+            matches!(&err, Error::InvalidBundle { message, .. } if message.contains("checksum")),
+            "{err}"
+        );
+
+        fs.write(&output, &archive).unwrap();
+        assert_eq!(verify(&fs, &output).unwrap(), 3);
+
+        fs.write(&output, &archive[..archive.len() / 2]).unwrap();
+        assert!(matches!(
+            unpack(&fs, &output, Path::new("/store")),
+            Err(Error::InvalidBundle { .. })
+        ));
+        assert!(!fs.exists(Path::new("/store")));
+    }
+
+    #[test]
+    fn duplicated_index_lines_do_not_cover_extra_files() {
+        let manifest = b"[files]";
+        let listed = b"listed";
+        let index = format!(
+            "{0}  {MANIFEST}\n{1}  sources/listed\n{1}  sources/listed\n",
+            hash(manifest),
+            hash(listed)
+        );
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, MANIFEST, manifest, 0o644).unwrap();
+        append(&mut builder, "sources/listed", listed, 0o644).unwrap();
+        append(&mut builder, "sources/extra", b"extra", 0o644).unwrap();
+        append(&mut builder, CHECKSUMS, index.as_bytes(), 0o644).unwrap();
+
+        let fs = MemFs::new();
+        let output = Path::new("/out/dotfiles.tar");
+        fs.create_dir_all(Path::new("/out")).unwrap();
+        fs.write(output, &builder.into_inner().unwrap()).unwrap();
+
+        let err = unpack(&fs, output, Path::new("/store")).unwrap_err();
+        assert!(
+            matches!(&err, Error::InvalidBundle { message, .. } if message.contains("twice")),
+            "{err}"
+        );
+        assert!(!fs.exists(Path::new("/store")));
+    }
+}
@@ -1,67 +1,215 @@
-use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 
-use crate::symlink::Symlink;
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+use crate::manifest::Manifest;
+use crate::plan::backup_path;
+use crate::secret::SecretHashes;
+use crate::symlink::{Kind, Symlink};
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+/// Version of the cache file format. Caches without a version were written before paths were
+/// encoded and hold them verbatim.
+const VERSION: u32 = 2;
+
+/// Record of the installed symlinks, mapping each source path to its target path.
+/// Paths are stored as written by [`encode_path`], which keeps paths that are not valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct Cache {
     #[serde(default)]
     pub files: BTreeMap<String, String>,
+    /// Hashes of the installed secrets by source path, plaintext is never stored.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secrets: BTreeMap<String, SecretHashes>,
+    /// Inode of each hard linked source when it was installed, identifying the link after
+    /// the source was replaced.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hardlinks: BTreeMap<String, u64>,
+}
+
+/// The cache as read from its file, along with the format version.
+#[derive(Deserialize)]
+struct StoredCache {
+    version: Option<u32>,
+    #[serde(flatten)]
+    cache: Cache,
+}
+
+/// The cache as written to its file, always in the current format.
+#[derive(Serialize)]
+struct StoredCacheRef<'a> {
+    version: u32,
+    #[serde(flatten)]
+    cache: &'a Cache,
 }
 
 impl Cache {
     /// Load cache from the given path. Returns an empty cache if the file does not exist.
-    pub fn load(path: &Path) -> anyhow::Result<Cache> {
-        if !path.exists() {
+    /// The paths of a cache written before they were encoded are read verbatim.
+    pub fn load(fs: &dyn Fs, path: &Path) -> Result<Cache> {
+        if !fs.exists(path) {
             return Ok(Cache::default());
         }
 
-        let content = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read cache: {}", path.display()))?;
+        let content = fs
+            .read_to_string(path)
+            .map_err(|err| Error::io("Failed to read cache", path, err))?;
+
+        let stored: StoredCache = toml::from_str(&content).map_err(|source| Error::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
 
-        toml::from_str(&content)
-            .with_context(|| format!("Failed to parse cache: {}", path.display()))
+        Ok(match stored.version {
+            Some(_) => stored.cache,
+            None => stored.cache.encode_legacy(),
+        })
+    }
+
+    // Encodes the verbatim paths of a cache in the format before `VERSION` 2.
+    fn encode_legacy(self) -> Cache {
+        let encode = |path: String| encode_path(Path::new(&path));
+        Cache {
+            files: self
+                .files
+                .into_iter()
+                .map(|(src, target)| (encode(src), encode(target)))
+                .collect(),
+            secrets: self
+                .secrets
+                .into_iter()
+                .map(|(src, hashes)| (encode(src), hashes))
+                .collect(),
+            hardlinks: self
+                .hardlinks
+                .into_iter()
+                .map(|(src, ino)| (encode(src), ino))
+                .collect(),
+        }
     }
 
     /// Save cache to the given path, creating parent directories as needed.
-    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+    pub fn save(&self, fs: &dyn Fs, path: &Path) -> Result<()> {
         if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent).with_context(|| {
-                format!("Failed to create cache directory: {}", parent.display())
-            })?;
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create cache directory", parent, err))?;
         }
 
-        let content = toml::to_string_pretty(self).context("Failed to serialize cache")?;
+        let content = self.to_toml()?;
+
+        fs.write(path, content.as_bytes())
+            .map_err(|err| Error::io("Failed to write cache", path, err))
+    }
 
-        std::fs::write(path, content)
-            .with_context(|| format!("Failed to write cache: {}", path.display()))
+    /// Serializes the cache as it is written to its file.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(&StoredCacheRef {
+            version: VERSION,
+            cache: self,
+        })?)
     }
 
     /// Delete the cache file if it exists.
-    pub fn delete(path: &Path) -> anyhow::Result<()> {
-        if path.exists() {
-            std::fs::remove_file(path)
-                .with_context(|| format!("Failed to delete cache: {}", path.display()))?;
+    pub fn delete(fs: &dyn Fs, path: &Path) -> Result<()> {
+        if fs.exists(path) {
+            fs.remove_file(path)
+                .map_err(|err| Error::io("Failed to delete cache", path, err))?;
         }
         Ok(())
     }
 
+    /// Moves the cache kept at `from` by earlier versions to `to`, unless there already is one.
+    /// The file is copied rather than renamed, as both may be on different filesystems.
+    /// Returns whether the cache was moved.
+    pub fn migrate(fs: &dyn Fs, from: &Path, to: &Path) -> Result<bool> {
+        if fs.exists(to) || !fs.exists(from) {
+            return Ok(false);
+        }
+
+        let content = fs
+            .read(from)
+            .map_err(|err| Error::io("Failed to read cache", from, err))?;
+
+        if let Some(parent) = to.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create state directory", parent, err))?;
+        }
+        fs.write(to, &content)
+            .map_err(|err| Error::io("Failed to write state", to, err))?;
+        fs.remove_file(from)
+            .map_err(|err| Error::io("Failed to delete cache", from, err))?;
+
+        Ok(true)
+    }
+
+    /// Moves a corrupted cache file aside to `<file>.bak`, so a fresh one can be written.
+    /// Returns the path it was moved to.
+    pub fn move_aside(fs: &dyn Fs, path: &Path) -> Result<PathBuf> {
+        let backup = backup_path(fs, path);
+        fs.rename(path, &backup)
+            .map_err(|err| Error::io("Failed to move cache aside", path, err))?;
+        Ok(backup)
+    }
+
+    /// Reconstructs a lost cache from the links found on disk. Every target of the manifest and
+    /// every entry of the directories holding them is checked for a symlink into one of the
+    /// `roots`, which includes links to sources since removed from the manifest. Hard links are
+    /// recognized by their inode, installed secrets can not be told apart from other files.
+    pub fn rebuild(fs: &dyn Fs, manifest: &Manifest, roots: &[PathBuf]) -> Cache {
+        let mut cache = Cache::default();
+        let symlinks = manifest.to_symlinks();
+
+        for symlink in &symlinks {
+            if symlink.is_hardlink() && symlink.is_installed(fs) {
+                let src = encode_path(&symlink.source);
+                if let Ok(metadata) = fs.metadata(&symlink.source) {
+                    cache.hardlinks.insert(src.clone(), metadata.ino);
+                }
+                cache.files.insert(src, encode_path(&symlink.target));
+            }
+        }
+
+        let mut candidates: BTreeSet<PathBuf> = symlinks
+            .iter()
+            .map(|symlink| symlink.target.clone())
+            .collect();
+        let dirs: BTreeSet<&Path> = symlinks
+            .iter()
+            .filter_map(|symlink| symlink.target.parent())
+            .collect();
+        for dir in dirs {
+            candidates.extend(fs.read_dir(dir).unwrap_or_default());
+        }
+
+        for path in candidates {
+            if !fs.is_symlink(&path) {
+                continue;
+            }
+            let Ok(source) = fs.read_link(&path) else {
+                continue;
+            };
+            if roots.iter().any(|root| source.starts_with(root)) {
+                cache.files.insert(encode_path(&source), encode_path(&path));
+            }
+        }
+
+        cache
+    }
+
     /// Returns entries that are in the cache but not in the new manifest,
     /// or whose target path has changed compared to the new manifest.
     pub fn stale_entries(&self, new_entries: &BTreeMap<PathBuf, PathBuf>) -> Vec<Symlink> {
         self.files
             .iter()
-            .filter(|(src, target)| {
-                let src_path = PathBuf::from(src);
-                let target_path = PathBuf::from(target);
-                match new_entries.get(&src_path) {
-                    None => true,
-                    Some(new_target) => *new_target != target_path,
-                }
+            .filter(|(src, target)| match new_entries.get(&decode_path(src)) {
+                None => true,
+                Some(new_target) => *new_target != decode_path(target),
             })
-            .map(|(src, target)| Symlink::new(PathBuf::from(src), PathBuf::from(target)))
+            .map(|(src, target)| self.symlink(src, target))
             .collect()
     }
 
@@ -69,38 +217,152 @@ impl Cache {
     pub fn all_entries(&self) -> Vec<Symlink> {
         self.files
             .iter()
-            .map(|(src, target)| Symlink::new(PathBuf::from(src), PathBuf::from(target)))
+            .map(|(src, target)| self.symlink(src, target))
             .collect()
     }
 
+    fn symlink(&self, src: &str, target: &str) -> Symlink {
+        let (source, target) = (decode_path(src), decode_path(target));
+        if let Some(hashes) = self.secrets.get(src) {
+            return Symlink::secret(source, target, Some(hashes.clone()));
+        }
+
+        match self.hardlinks.get(src) {
+            Some(ino) => Symlink::hardlink(source, target, Some(*ino)),
+            None => Symlink::new(source, target),
+        }
+    }
+
+    /// Attaches the recorded hashes of a secret, or inode of a hard link.
+    /// Other symlinks are returned as is.
+    pub fn with_state(&self, mut symlink: Symlink) -> Symlink {
+        let src = encode_path(&symlink.source);
+        match &mut symlink.kind {
+            Kind::Secret(hashes) => *hashes = self.secrets.get(&src).cloned(),
+            Kind::Hardlink(ino) => *ino = self.hardlinks.get(&src).copied(),
+            Kind::Link => {}
+        }
+        symlink
+    }
+
+    /// Whether the target was installed but is now a regular file, most likely replaced by an
+    /// editor saving it. Secrets are files anyway and never count as replaced.
+    pub fn is_replaced(&self, fs: &dyn Fs, symlink: &Symlink) -> bool {
+        !symlink.is_secret()
+            && !symlink.is_installed(fs)
+            && self.files.get(&encode_path(&symlink.source)) == Some(&encode_path(&symlink.target))
+            && fs
+                .symlink_metadata(&symlink.target)
+                .is_ok_and(|metadata| metadata.is_file())
+    }
+
     /// Replace all cache entries with the given manifest entries.
     pub fn update(&mut self, entries: &BTreeMap<PathBuf, PathBuf>) {
         self.files.clear();
+        self.insert(entries);
+
+        let files = &self.files;
+        self.secrets.retain(|src, _| files.contains_key(src));
+        self.hardlinks.retain(|src, _| files.contains_key(src));
+    }
+
+    /// Add or replace the given manifest entries, keeping all other cache entries.
+    pub fn insert(&mut self, entries: &BTreeMap<PathBuf, PathBuf>) {
         for (src, target) in entries {
-            self.files.insert(
-                src.to_string_lossy().to_string(),
-                target.to_string_lossy().to_string(),
-            );
+            self.files.insert(encode_path(src), encode_path(target));
+        }
+    }
+
+    /// Moves the entry of a source, along with its recorded state, to another source path.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        let from = encode_path(from);
+        let to = encode_path(to);
+
+        if let Some(target) = self.files.remove(&from) {
+            self.files.insert(to.clone(), target);
+        }
+        if let Some(hashes) = self.secrets.remove(&from) {
+            self.secrets.insert(to.clone(), hashes);
+        }
+        if let Some(ino) = self.hardlinks.remove(&from) {
+            self.hardlinks.insert(to, ino);
+        }
+    }
+
+    /// Remove the entries of the given symlinks from the cache.
+    pub fn remove(&mut self, symlinks: &[Symlink]) {
+        for symlink in symlinks {
+            let src = encode_path(&symlink.source);
+            self.files.remove(&src);
+            self.secrets.remove(&src);
+            self.hardlinks.remove(&src);
         }
     }
 }
 
+/// Encodes a path for the cache file, which only holds UTF-8 strings. Bytes that are not valid
+/// UTF-8 are written as `\xNN` and backslashes as `\\`, so every path survives the round trip.
+pub fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        encoded.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            let _ = write!(encoded, "\\x{byte:02x}");
+        }
+    }
+    encoded
+}
+
+/// Decodes a path written by [`encode_path`]. Any other backslash is kept as is.
+pub fn decode_path(encoded: &str) -> PathBuf {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+
+    while let [byte, tail @ ..] = rest {
+        rest = tail;
+        if *byte == b'\\' {
+            match rest {
+                [b'\\', tail @ ..] => {
+                    bytes.push(b'\\');
+                    rest = tail;
+                    continue;
+                }
+                [b'x', hi, lo, tail @ ..] => {
+                    let hi = char::from(*hi).to_digit(16);
+                    let lo = char::from(*lo).to_digit(16);
+                    if let (Some(hi), Some(lo)) = (hi, lo) {
+                        bytes.push((hi * 16 + lo) as u8);
+                        rest = tail;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+        }
+        bytes.push(*byte);
+    }
+
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::tests::tests::test_dir;
+    use crate::fs::MemFs;
 
     #[test]
     fn load_missing_cache_returns_default() {
-        let dir = test_dir("missing");
-        let cache = Cache::load(&dir.join("nonexistent.toml")).unwrap();
+        let fs = MemFs::new();
+        let dir = Path::new("/state");
+        let cache = Cache::load(&fs, &dir.join("nonexistent.toml")).unwrap();
         assert!(cache.files.is_empty());
     }
 
     #[test]
     fn save_and_load_roundtrip() {
-        let dir = test_dir("roundtrip");
+        let fs = MemFs::new();
+        let dir = Path::new("/state");
         let path = dir.join("cache.toml");
 
         let mut cache = Cache::default();
@@ -110,9 +372,9 @@ mod tests {
         cache
             .files
             .insert("/src/b".to_string(), "/target/b".to_string());
-        cache.save(&path).unwrap();
+        cache.save(&fs, &path).unwrap();
 
-        let loaded = Cache::load(&path).unwrap();
+        let loaded = Cache::load(&fs, &path).unwrap();
         assert_eq!(loaded.files.len(), 2);
         assert_eq!(loaded.files.get("/src/a").unwrap(), "/target/a");
         assert_eq!(loaded.files.get("/src/b").unwrap(), "/target/b");
@@ -183,23 +445,49 @@ mod tests {
         assert!(cache.files.contains_key("/new/src"));
     }
 
+    #[test]
+    fn insert_and_remove_keep_other_entries() {
+        let mut cache = Cache::default();
+        cache
+            .files
+            .insert("/src/a".to_string(), "/target/a".to_string());
+        cache
+            .files
+            .insert("/src/b".to_string(), "/target/b".to_string());
+
+        let mut new_entries = BTreeMap::new();
+        new_entries.insert(PathBuf::from("/src/c"), PathBuf::from("/target/c"));
+        cache.insert(&new_entries);
+
+        cache.remove(&[Symlink::new(
+            PathBuf::from("/src/a"),
+            PathBuf::from("/target/a"),
+        )]);
+
+        assert_eq!(cache.files.len(), 2);
+        assert!(cache.files.contains_key("/src/b"));
+        assert!(cache.files.contains_key("/src/c"));
+    }
+
     #[test]
     fn delete_nonexistent_is_ok() {
-        let dir = test_dir("delete");
-        assert!(Cache::delete(&dir.join("no_such_file.toml")).is_ok());
+        let fs = MemFs::new();
+        let dir = Path::new("/state");
+        assert!(Cache::delete(&fs, &dir.join("no_such_file.toml")).is_ok());
     }
 
     #[test]
     fn delete_existing_removes_file() {
-        let dir = test_dir("delete_existing");
+        let fs = MemFs::new();
+        let dir = Path::new("/state");
         let path = dir.join("cache.toml");
 
         let cache = Cache::default();
-        cache.save(&path).unwrap();
-        assert!(path.exists());
+        cache.save(&fs, &path).unwrap();
+        assert!(fs.exists(&path));
 
-        Cache::delete(&path).unwrap();
-        assert!(!path.exists());
+        Cache::delete(&fs, &path).unwrap();
+        assert!(!fs.exists(&path));
     }
 
     #[test]
@@ -215,4 +503,145 @@ mod tests {
         let entries = cache.all_entries();
         assert_eq!(entries.len(), 2);
     }
+
+    #[test]
+    fn save_reports_full_disk() {
+        let fs = MemFs::new();
+        let path = Path::new("/home/user/.cache/homage/cache.toml");
+        fs.fail("/home/user/.cache", 28);
+
+        let err = Cache::default().save(&fs, path).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Io { source, .. } if source.kind() == std::io::ErrorKind::StorageFull
+        ));
+        assert!(Cache::load(&MemFs::new(), path).unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn migrate_moves_legacy_cache() {
+        let fs = MemFs::new();
+        let legacy = fs.add_file(
+            "/home/user/.cache/homage/cache.toml",
+            "[files]\n\"/src/a\" = \"/target/a\"\n",
+        );
+        let state = Path::new("/home/user/.local/state/homage/state.toml");
+
+        assert!(Cache::migrate(&fs, &legacy, state).unwrap());
+        assert!(!fs.exists(&legacy));
+        assert_eq!(Cache::load(&fs, state).unwrap().files.len(), 1);
+
+        // An existing state is never replaced
+        fs.add_file(&legacy, "");
+        assert!(!Cache::migrate(&fs, &legacy, state).unwrap());
+        assert!(fs.exists(&legacy));
+    }
+
+    #[test]
+    fn rebuild_finds_links_into_the_sources() {
+        use crate::utils::Layout;
+
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/zshrc", "zsh");
+        fs.add_file("/dotfiles/app.conf", "app");
+        let manifest_path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"zshrc\" = \"/home/user/.zshrc\"\n\
+             \"app.conf\" = { target = \"/home/user/app.conf\", link = \"hardlink\" }",
+        );
+        let manifest = Manifest::load(&fs, &manifest_path, &Layout::default()).unwrap();
+
+        fs.add_symlink("/dotfiles/zshrc", "/home/user/.zshrc");
+        fs.hard_link(
+            Path::new("/dotfiles/app.conf"),
+            Path::new("/home/user/app.conf"),
+        )
+        .unwrap();
+        // A link to a source removed from the manifest and a link elsewhere
+        fs.add_symlink("/dotfiles/bashrc", "/home/user/.bashrc");
+        fs.add_symlink("/usr/share/vim", "/home/user/.vim");
+
+        let cache = Cache::rebuild(&fs, &manifest, &[PathBuf::from("/dotfiles")]);
+        assert_eq!(
+            cache.files,
+            BTreeMap::from([
+                (
+                    "/dotfiles/app.conf".to_string(),
+                    "/home/user/app.conf".to_string()
+                ),
+                (
+                    "/dotfiles/bashrc".to_string(),
+                    "/home/user/.bashrc".to_string()
+                ),
+                (
+                    "/dotfiles/zshrc".to_string(),
+                    "/home/user/.zshrc".to_string()
+                ),
+            ])
+        );
+        assert_eq!(cache.hardlinks.len(), 1);
+    }
+
+    #[test]
+    fn corrupted_cache_is_moved_aside() {
+        let fs = MemFs::new();
+        let path = fs.add_file("/state/state.toml", "files = [");
+        assert!(matches!(Cache::load(&fs, &path), Err(Error::Parse { .. })));
+
+        let backup = Cache::move_aside(&fs, &path).unwrap();
+        assert_eq!(backup, Path::new("/state/state.toml.bak"));
+        assert!(Cache::load(&fs, &path).unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        use std::ffi::OsStr;
+
+        let source = Path::new(OsStr::from_bytes(b"/src/caf\xe9\\x41.txt"));
+        let target = Path::new(OsStr::from_bytes(b"/target/caf\xe9\\x41.txt"));
+        assert_eq!(encode_path(source), "/src/caf\\xe9\\\\x41.txt");
+        assert_eq!(decode_path(&encode_path(source)), source);
+
+        let fs = MemFs::new();
+        let path = Path::new("/state/state.toml");
+        let mut cache = Cache::default();
+        cache.insert(&BTreeMap::from([(
+            source.to_path_buf(),
+            target.to_path_buf(),
+        )]));
+        cache.save(&fs, path).unwrap();
+
+        let loaded = Cache::load(&fs, path).unwrap();
+        assert_eq!(loaded.all_entries()[0].source, source);
+        assert_eq!(loaded.all_entries()[0].target, target);
+        let entries = BTreeMap::from([(source.to_path_buf(), target.to_path_buf())]);
+        assert!(loaded.stale_entries(&entries).is_empty());
+    }
+
+    #[test]
+    fn legacy_paths_are_read_verbatim() {
+        let fs = MemFs::new();
+        let path = fs.add_file(
+            "/state/state.toml",
+            "[files]\n'/src/a\\x41\\\\b' = '/target/a\\x41\\\\b'\n\
+             [hardlinks]\n'/src/a\\x41\\\\b' = 7",
+        );
+
+        let cache = Cache::load(&fs, &path).unwrap();
+        let entry = &cache.all_entries()[0];
+        assert_eq!(entry.source, Path::new("/src/a\\x41\\\\b"));
+        assert_eq!(entry.target, Path::new("/target/a\\x41\\\\b"));
+        assert!(entry.is_hardlink());
+
+        cache.save(&fs, &path).unwrap();
+        let content = fs.read_to_string(&path).unwrap();
+        assert!(content.starts_with("version = 2\n"));
+        assert_eq!(Cache::load(&fs, &path).unwrap(), cache);
+    }
+
+    #[test]
+    fn unencoded_backslashes_are_kept() {
+        assert_eq!(decode_path("/src/a\\b\\xzz"), Path::new("/src/a\\b\\xzz"));
+        assert_eq!(decode_path("/src/trailing\\"), Path::new("/src/trailing\\"));
+    }
 }
@@ -0,0 +1,593 @@
+use colored::Colorize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use toml::Spanned;
+use toml::de::{DeTable, DeValue};
+
+use crate::fs::Fs;
+use crate::manifest::Manifest;
+use crate::utils::{Layout, git_root, parse_mode};
+
+const MANIFEST_KEYS: [&str; 3] = ["includes", "files", "system"];
+const ENTRY_KEYS: [&str; 6] = ["target", "tags", "encrypted", "mode", "dir_mode", "link"];
+
+/// A single problem found in a manifest, optionally pointing at the offending part of the file.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    location: Option<Location>,
+    help: Option<String>,
+}
+
+/// Position of a diagnostic within a manifest file, along with the line it refers to.
+#[derive(Debug, Clone)]
+struct Location {
+    file: PathBuf,
+    line: usize,
+    column: usize,
+    width: usize,
+    snippet: String,
+}
+
+impl Location {
+    fn new(file: &Path, content: &str, span: Range<usize>) -> Location {
+        let start = span.start.min(content.len());
+        let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = content[start..]
+            .find('\n')
+            .map_or(content.len(), |i| start + i);
+
+        Location {
+            file: file.to_path_buf(),
+            line: content[..start].matches('\n').count() + 1,
+            column: content[line_start..start].chars().count() + 1,
+            width: content[start..span.end.clamp(start, line_end)]
+                .chars()
+                .count()
+                .max(1),
+            snippet: content[line_start..line_end].to_string(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {}", "error".red().bold(), self.message)?;
+
+        if let Some(location) = &self.location {
+            let gutter = " ".repeat(location.line.to_string().len());
+            writeln!(
+                f,
+                "{}{} {}:{}:{}",
+                gutter,
+                "-->".blue(),
+                location.file.display(),
+                location.line,
+                location.column
+            )?;
+            writeln!(f, "{} {}", gutter, "|".blue())?;
+            writeln!(
+                f,
+                "{} {} {}",
+                location.line.to_string().blue(),
+                "|".blue(),
+                location.snippet
+            )?;
+            writeln!(
+                f,
+                "{} {} {}{}",
+                gutter,
+                "|".blue(),
+                " ".repeat(location.column - 1),
+                "^".repeat(location.width).red()
+            )?;
+        }
+
+        if let Some(help) = &self.help {
+            writeln!(f, "  = help: {}", help)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks a manifest and all of its includes, collecting every problem instead of stopping at the first.
+pub fn check(fs: &dyn Fs, path: &Path, layout: &Layout) -> Vec<Diagnostic> {
+    let dir = fs
+        .canonicalize(path)
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
+    let mut checker = Checker {
+        fs,
+        layout,
+        repo_root: git_root(fs, &dir).unwrap_or(dir),
+        stack: Vec::new(),
+        visited: HashSet::new(),
+        targets: BTreeMap::new(),
+        diagnostics: Vec::new(),
+    };
+    checker.check_file(path, None);
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    fs: &'a dyn Fs,
+    layout: &'a Layout,
+    repo_root: PathBuf,
+    /// The manifests currently being checked, from the root down to the innermost include.
+    stack: Vec<PathBuf>,
+    /// Every manifest checked so far, each is only checked once.
+    visited: HashSet<PathBuf>,
+    targets: BTreeMap<PathBuf, PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, message: String, location: Option<Location>, help: Option<String>) {
+        self.diagnostics.push(Diagnostic {
+            message,
+            location,
+            help,
+        });
+    }
+
+    fn check_file(&mut self, path: &Path, origin: Option<Location>) {
+        let Ok(canonical) = self.fs.canonicalize(path) else {
+            self.report(
+                format!("Manifest not found: {}", path.display()),
+                origin,
+                None,
+            );
+            return;
+        };
+
+        if self.stack.contains(&canonical) {
+            self.report(
+                format!("Circular include detected: {}", canonical.display()),
+                origin,
+                None,
+            );
+            return;
+        }
+        // A manifest included from several others is fine, its problems are reported once
+        if !self.visited.insert(canonical.clone()) {
+            return;
+        }
+
+        let content = match self.fs.read_to_string(&canonical) {
+            Ok(content) => content,
+            Err(err) => {
+                self.report(
+                    format!("Failed to read manifest {}: {}", canonical.display(), err),
+                    origin,
+                    None,
+                );
+                return;
+            }
+        };
+
+        let (document, errors) = DeTable::parse_recoverable(&content);
+        if !errors.is_empty() {
+            for error in errors {
+                let location = error
+                    .span()
+                    .map(|span| Location::new(&canonical, &content, span));
+                self.report(error.message().trim().to_string(), location, None);
+            }
+            return;
+        }
+
+        let file = ManifestSource {
+            path: canonical.clone(),
+            dir: canonical.parent().unwrap_or(Path::new("/")).to_path_buf(),
+            content: &content,
+        };
+
+        self.stack.push(canonical);
+        for (key, value) in document.get_ref() {
+            match key.get_ref().as_ref() {
+                "includes" => self.check_includes(&file, value),
+                "files" => self.check_files(&file, value, false),
+                "system" => self.check_files(&file, value, true),
+                other => self.report_unknown_key(&file, key, other, "manifest", &MANIFEST_KEYS),
+            }
+        }
+        self.stack.pop();
+    }
+
+    fn check_includes(&mut self, file: &ManifestSource, value: &Spanned<DeValue>) {
+        let Some(includes) = value.get_ref().as_array() else {
+            self.report_type(file, value, "'includes' must be an array of paths");
+            return;
+        };
+
+        for include in includes {
+            match include.get_ref().as_str() {
+                Some(include_path) => {
+                    let location = file.location(include.span());
+                    self.check_file(&file.dir.join(include_path), Some(location));
+                }
+                None => self.report_type(file, include, "Include must be a path"),
+            }
+        }
+    }
+
+    fn check_files(&mut self, file: &ManifestSource, value: &Spanned<DeValue>, system: bool) {
+        let Some(files) = value.get_ref().as_table() else {
+            let message = match system {
+                true => "'system' must be a table",
+                false => "'files' must be a table",
+            };
+            self.report_type(file, value, message);
+            return;
+        };
+
+        for (source, entry) in files {
+            let Some(target) = self.check_entry(file, entry) else {
+                continue;
+            };
+            // System files live outside of the home directory of the invoking user
+            if system && !target.starts_with('/') {
+                self.report(
+                    format!("System target '{}' must be an absolute path", target),
+                    Some(file.location(entry.span())),
+                    None,
+                );
+            }
+            self.check_paths(file, source, &target);
+        }
+    }
+
+    // Validates the shape of a single entry, returning its target path if it has one.
+    fn check_entry(&mut self, file: &ManifestSource, entry: &Spanned<DeValue>) -> Option<String> {
+        if let Some(target) = entry.get_ref().as_str() {
+            return Some(target.to_string());
+        }
+
+        let Some(table) = entry.get_ref().as_table() else {
+            self.report_type(file, entry, "Entry must be a target path or a table");
+            return None;
+        };
+
+        let mut target = None;
+        for (key, value) in table {
+            match key.get_ref().as_ref() {
+                "target" => match value.get_ref().as_str() {
+                    Some(path) => target = Some(path.to_string()),
+                    None => self.report_type(file, value, "'target' must be a path"),
+                },
+                "tags" => {
+                    let valid = value
+                        .get_ref()
+                        .as_array()
+                        .is_some_and(|tags| tags.iter().all(|tag| tag.get_ref().is_str()));
+                    if !valid {
+                        self.report_type(file, value, "'tags' must be an array of strings");
+                    }
+                }
+                "encrypted" => {
+                    if !value.get_ref().is_bool() {
+                        self.report_type(file, value, "'encrypted' must be a boolean");
+                    }
+                }
+                "link" => {
+                    if !matches!(value.get_ref().as_str(), Some("symlink" | "hardlink")) {
+                        self.report_type(file, value, "'link' must be \"symlink\" or \"hardlink\"");
+                    }
+                }
+                name @ ("mode" | "dir_mode") => {
+                    let mode = value.get_ref().as_str();
+                    if mode.and_then(parse_mode).is_none() {
+                        self.report_type(
+                            file,
+                            value,
+                            &format!("'{}' must be an octal mode such as \"0600\"", name),
+                        );
+                    }
+                }
+                other => self.report_unknown_key(file, key, other, "entry", &ENTRY_KEYS),
+            }
+        }
+
+        if target.is_none() {
+            self.report(
+                "Entry is missing a 'target'".to_string(),
+                Some(file.location(entry.span())),
+                None,
+            );
+        }
+
+        target
+    }
+
+    fn check_paths(&mut self, file: &ManifestSource, source: &Spanned<DeString>, target: &str) {
+        let location = file.location(source.span());
+        let abs_source = file.dir.join(source.get_ref().as_ref());
+
+        let Ok(abs_source) = self.fs.canonicalize(&abs_source) else {
+            self.report(
+                format!("Source not found: {}", abs_source.display()),
+                Some(location),
+                None,
+            );
+            return;
+        };
+
+        if !abs_source.starts_with(&self.repo_root) {
+            self.report(
+                format!(
+                    "Source {} is outside of the repository {}",
+                    abs_source.display(),
+                    self.repo_root.display()
+                ),
+                Some(location.clone()),
+                None,
+            );
+        }
+
+        let abs_target = match self.layout.target(target) {
+            Ok(abs_target) => abs_target,
+            Err(err) => {
+                self.report(err.to_string(), Some(location), None);
+                return;
+            }
+        };
+
+        if abs_target.starts_with(&self.repo_root) {
+            self.report(
+                format!(
+                    "Target {} is inside of the repository {}",
+                    abs_target.display(),
+                    self.repo_root.display()
+                ),
+                Some(location.clone()),
+                None,
+            );
+        }
+
+        let files = match Manifest::expand(self.fs, &abs_source, abs_target) {
+            Ok(files) => files,
+            Err(err) => {
+                self.report(err.to_string(), Some(location), None);
+                return;
+            }
+        };
+
+        for (file_source, file_target) in files {
+            match self.targets.get(&file_target) {
+                Some(previous) if *previous != file_source => {
+                    let message = format!(
+                        "Duplicate target {}, already targeted by {}",
+                        file_target.display(),
+                        previous.display()
+                    );
+                    self.report(message, Some(location.clone()), None);
+                }
+                _ => {
+                    self.targets.insert(file_target, file_source);
+                }
+            }
+        }
+    }
+
+    fn report_unknown_key(
+        &mut self,
+        file: &ManifestSource,
+        key: &Spanned<DeString>,
+        name: &str,
+        context: &str,
+        known: &[&str],
+    ) {
+        let help = suggest(name, known).map(|candidate| format!("did you mean '{}'?", candidate));
+        self.report(
+            format!("Unknown {} key '{}'", context, name),
+            Some(file.location(key.span())),
+            help,
+        );
+    }
+
+    fn report_type(&mut self, file: &ManifestSource, value: &Spanned<DeValue>, message: &str) {
+        self.report(
+            format!("{}, found {}", message, value.get_ref().type_str()),
+            Some(file.location(value.span())),
+            None,
+        );
+    }
+}
+
+type DeString<'i> = std::borrow::Cow<'i, str>;
+
+struct ManifestSource<'a> {
+    path: PathBuf,
+    dir: PathBuf,
+    content: &'a str,
+}
+
+impl ManifestSource<'_> {
+    fn location(&self, span: Range<usize>) -> Location {
+        Location::new(&self.path, self.content, span)
+    }
+}
+
+/// Returns the known key closest to `name`, if it is close enough to be a likely typo.
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, candidate)| *distance <= candidate.len() / 3 + 1)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::fs::{MemFs, OsFs};
+    use crate::tests::{test_dir, write_file};
+
+    fn messages(diagnostics: &[Diagnostic]) -> Vec<String> {
+        diagnostics.iter().map(|d| d.message.clone()).collect()
+    }
+
+    #[test]
+    fn valid_manifest_has_no_diagnostics() {
+        let dir = test_dir("check_valid");
+        fs::create_dir_all(dir.join("repo/.git")).unwrap();
+        write_file(&dir, "repo/a.conf", "a");
+
+        let manifest = write_file(
+            &dir,
+            "repo/manifest.toml",
+            &format!("[files]\n\"a.conf\" = \"{}/home/a.conf\"", dir.display()),
+        );
+
+        assert!(check(&OsFs, &manifest, &Layout::default()).is_empty());
+    }
+
+    #[test]
+    fn shared_include_is_checked_once() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        fs.add_file(
+            "/dotfiles/shared.toml",
+            "[files]\n\"a.conf\" = \"/home/a.conf\"\ntags = 1",
+        );
+        fs.add_file("/dotfiles/left.toml", "includes = [\"shared.toml\"]");
+        fs.add_file("/dotfiles/right.toml", "includes = [\"shared.toml\"]");
+        fs.add_file("/dotfiles/cycle.toml", "includes = [\"manifest.toml\"]");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "includes = [\"left.toml\", \"right.toml\", \"cycle.toml\"]",
+        );
+
+        let messages = messages(&check(&fs, &path, &Layout::default()));
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(messages[1].starts_with("Circular include detected: /dotfiles/manifest.toml"));
+    }
+
+    #[test]
+    fn syntax_error_reports_location() {
+        let dir = test_dir("check_syntax");
+        let manifest = write_file(&dir, "manifest.toml", "[files]\n\"a.conf\" = \n");
+
+        let diagnostics = check(&OsFs, &manifest, &Layout::default());
+        assert_eq!(diagnostics.len(), 1);
+
+        let location = diagnostics[0].location.as_ref().unwrap();
+        assert_eq!(location.line, 2);
+    }
+
+    #[test]
+    fn reports_all_problems_in_one_pass() {
+        let dir = test_dir("check_all");
+        fs::create_dir_all(dir.join("repo/.git")).unwrap();
+        write_file(&dir, "repo/a.conf", "a");
+        write_file(&dir, "repo/b.conf", "b");
+        write_file(&dir, "outside.conf", "outside");
+
+        let content = format!(
+            "includes = [\"missing.toml\"]\n\
+             file = []\n\
+             [files]\n\
+             \"a.conf\" = \"{h}/same.conf\"\n\
+             \"b.conf\" = {{ target = \"{h}/same.conf\", tag = [\"gui\"], mode = \"0900\" }}\n\
+             \"nope.conf\" = \"{h}/nope.conf\"\n\
+             \"../outside.conf\" = \"{h}/outside.conf\"\n",
+            h = dir.join("home").display()
+        );
+        let manifest = write_file(&dir, "repo/manifest.toml", &content);
+
+        let diagnostics = check(&OsFs, &manifest, &Layout::default());
+        let messages = messages(&diagnostics);
+        assert_eq!(messages.len(), 7, "{:?}", messages);
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.contains("'mode' must be an octal mode"))
+        );
+        assert!(messages.iter().any(|m| m.contains("Manifest not found")));
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.contains("Unknown entry key 'tag'"))
+        );
+        assert!(messages.iter().any(|m| m.contains("Duplicate target")));
+        assert!(messages.iter().any(|m| m.contains("Source not found")));
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.contains("outside of the repository"))
+        );
+
+        let unknown = diagnostics
+            .iter()
+            .find(|d| d.message.contains("Unknown manifest key 'file'"))
+            .unwrap();
+        assert_eq!(unknown.help.as_deref(), Some("did you mean 'files'?"));
+        assert_eq!(unknown.location.as_ref().unwrap().line, 2);
+    }
+
+    #[test]
+    fn relative_system_targets_are_reported() {
+        let dir = test_dir("check_system");
+        write_file(&dir, "keyd.conf", "keyd");
+
+        let manifest = write_file(
+            &dir,
+            "manifest.toml",
+            "[system]\n\"keyd.conf\" = \"~/keyd.conf\"",
+        );
+
+        let messages = messages(&check(&OsFs, &manifest, &Layout::default()));
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(messages[0].contains("must be an absolute path"));
+    }
+
+    #[test]
+    fn target_inside_repository_is_reported() {
+        let dir = test_dir("check_inside");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        write_file(&dir, "a.conf", "a");
+
+        let manifest = write_file(
+            &dir,
+            "manifest.toml",
+            &format!("[files]\n\"a.conf\" = \"{}/inside.conf\"", dir.display()),
+        );
+
+        let messages = messages(&check(&OsFs, &manifest, &Layout::default()));
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("inside of the repository"));
+    }
+
+    #[test]
+    fn suggestions_for_typos() {
+        assert_eq!(suggest("include", &MANIFEST_KEYS), Some("includes"));
+        assert_eq!(suggest("tag", &ENTRY_KEYS), Some("tags"));
+        assert_eq!(suggest("colour", &ENTRY_KEYS), None);
+    }
+}
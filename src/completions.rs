@@ -0,0 +1,170 @@
+use anyhow::Context;
+use clap::{CommandFactory, ValueEnum};
+use clap_complete::env::{Bash, Elvish, EnvCompleter, Fish, Zsh};
+use clap_complete::{
+    ArgValueCandidates, ArgValueCompleter, CompleteEnv, CompletionCandidate, PathCompleter,
+};
+use std::io::Write;
+use std::path::Path;
+
+use crate::args::Args;
+use homage::cache::Cache;
+use homage::fs::OsFs;
+use homage::utils::Layout;
+
+const BIN: &str = "homage";
+const COMPLETE_VAR: &str = "COMPLETE";
+
+/// Shells for which completion scripts can be generated.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Elvish,
+}
+
+impl Shell {
+    fn completer(self) -> &'static dyn EnvCompleter {
+        match self {
+            Shell::Bash => &Bash,
+            Shell::Zsh => &Zsh,
+            Shell::Fish => &Fish,
+            Shell::Elvish => &Elvish,
+        }
+    }
+}
+
+/// Answers completion requests issued by the registered shell scripts.
+/// Returns without doing anything unless the program was invoked by a completion script.
+pub fn handle_completion_request() {
+    CompleteEnv::with_factory(Args::command)
+        .var(COMPLETE_VAR)
+        .bin(BIN)
+        .completer(BIN)
+        .complete();
+}
+
+/// Writes the completion script for the given shell.
+/// The script calls back into homage, so completions stay in sync with the installed version.
+pub fn write_completions(shell: Shell, buf: &mut dyn Write) -> anyhow::Result<()> {
+    shell
+        .completer()
+        .write_registration(COMPLETE_VAR, BIN, BIN, BIN, buf)
+        .context("Failed to write completion script")
+}
+
+/// Generates a man page for homage and one for every subcommand into the given directory.
+pub fn write_man_pages(out_dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create directory: {}", out_dir.display()))?;
+
+    clap_mangen::generate_to(Args::command(), out_dir)
+        .with_context(|| format!("Failed to write man pages to {}", out_dir.display()))
+}
+
+/// Completes manifest arguments with directories and TOML files.
+pub fn manifest_completer() -> ArgValueCompleter {
+    ArgValueCompleter::new(PathCompleter::any().filter(|path| {
+        path.is_dir()
+            || path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+    }))
+}
+
+/// Completes target arguments with the targets recorded in the install state. The state is
+/// looked up below the `--root` and `--home` already typed on the command line, if any.
+pub fn target_completer() -> ArgValueCandidates {
+    ArgValueCandidates::new(|| {
+        let words: Vec<String> = std::env::args().collect();
+        let Ok(cache_path) = typed_layout(&words).state_path() else {
+            return vec![];
+        };
+
+        Cache::load(&OsFs, &cache_path)
+            .map(|cache| {
+                cache
+                    .all_entries()
+                    .into_iter()
+                    .map(|entry| CompletionCandidate::new(entry.target))
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+// Builds the layout from the '--root' and '--home' values among the words being completed.
+// Unlike the parsed arguments the command line may be incomplete, so anything else is ignored.
+fn typed_layout(words: &[String]) -> Layout {
+    let value = |flag: &str| {
+        let prefix = format!("{flag}=");
+        let mut value = None;
+        for (i, word) in words.iter().enumerate() {
+            if word == flag {
+                value = words.get(i + 1).cloned();
+            } else if let Some(rest) = word.strip_prefix(&prefix) {
+                value = Some(rest.to_string());
+            }
+        }
+        value
+            .filter(|value| !value.is_empty())
+            .and_then(|value| std::path::absolute(value).ok())
+    };
+
+    Layout::new(value("--root"), value("--home"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::test_dir;
+
+    #[test]
+    fn completion_scripts_reference_binary() {
+        for shell in Shell::value_variants() {
+            let mut buf = Vec::new();
+            write_completions(*shell, &mut buf).unwrap();
+            let script = String::from_utf8(buf).unwrap();
+            assert!(script.contains(BIN));
+            assert!(script.contains(COMPLETE_VAR));
+        }
+    }
+
+    #[test]
+    fn typed_root_and_home_are_used() {
+        let words = [
+            "homage",
+            "--",
+            "homage",
+            "--root",
+            "/stage",
+            "--home=/home/user",
+        ];
+        let words: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+        assert_eq!(
+            typed_layout(&words).state_path().unwrap(),
+            Path::new("/stage/home/user/.local/state/homage/state.toml")
+        );
+
+        let words = vec!["homage".to_string(), "--root".to_string()];
+        assert_eq!(
+            typed_layout(&words).state_path().unwrap(),
+            Layout::default().state_path().unwrap()
+        );
+    }
+
+    #[test]
+    fn man_pages_cover_subcommands() {
+        let dir = test_dir("man");
+        write_man_pages(&dir).unwrap();
+
+        assert!(dir.join("homage.1").exists());
+        assert!(dir.join("homage-install.1").exists());
+        assert!(dir.join("homage-uninstall.1").exists());
+
+        let page = std::fs::read_to_string(dir.join("homage-install.1")).unwrap();
+        assert!(page.contains("dry\\-run"));
+    }
+}
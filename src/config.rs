@@ -0,0 +1,170 @@
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use homage::utils::Layout;
+
+/// When to emit colored output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Let the terminal environment decide.
+    #[default]
+    Auto,
+    /// Always emit colors.
+    Always,
+    /// Never emit colors.
+    Never,
+}
+
+/// What an install does with targets occupied by other files, unless run interactively.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupPolicy {
+    /// Stop the install without changing anything.
+    #[default]
+    Never,
+    /// Move the occupying files aside to '<file>.bak' and install the links in their place.
+    Always,
+}
+
+/// How results are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Aligned plain text.
+    #[default]
+    Text,
+    /// JSON, for scripts.
+    Json,
+}
+
+/// User settings read from `$XDG_CONFIG_HOME/homage/config.toml`.
+/// Every value is optional, command line flags take precedence over the values set here.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Manifests used when no manifest is given on the command line.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub manifests: Vec<PathBuf>,
+    pub color: Option<ColorMode>,
+    pub no_confirm: Option<bool>,
+    pub verbosity: Option<u8>,
+    /// The age identity file used to decrypt secrets.
+    pub identity: Option<PathBuf>,
+    /// The command `[system]` entries are installed through, such as "sudo" or "doas".
+    pub escalate: Option<String>,
+    /// The profile selecting the entries to act on when no tag is given.
+    pub profile: Option<String>,
+    /// The tags selected by each profile.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Vec<String>>,
+    pub backup: Option<BackupPolicy>,
+    pub output_format: Option<OutputFormat>,
+}
+
+impl Config {
+    /// Load the config from the given path. Returns an empty config if the file does not exist.
+    /// Relative manifest and identity paths are resolved against the directory of the config file.
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+
+        let mut config: Config = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config: {}", path.display()))?;
+
+        // The paths refer to this machine, so `~` is expanded without any root or home override
+        let layout = Layout::default();
+        let config_dir = path.parent().unwrap_or(Path::new(""));
+        let resolve =
+            |path: &Path| anyhow::Ok(config_dir.join(layout.target(&path.to_string_lossy())?));
+        config.manifests = config
+            .manifests
+            .iter()
+            .map(|manifest| resolve(manifest))
+            .collect::<anyhow::Result<_>>()?;
+        config.identity = config.identity.as_deref().map(resolve).transpose()?;
+
+        Ok(config)
+    }
+
+    /// Returns the tags selected by the named profile.
+    pub fn profile_tags(&self, profile: &str) -> anyhow::Result<Vec<String>> {
+        self.profiles.get(profile).cloned().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown profile '{}', expected one of the [profiles] in the config",
+                profile
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{test_dir, write_file};
+
+    #[test]
+    fn load_missing_config_returns_default() {
+        let dir = test_dir("config_missing");
+        let config = Config::load(&dir.join("config.toml")).unwrap();
+        assert!(config.manifests.is_empty());
+        assert!(config.color.is_none());
+    }
+
+    #[test]
+    fn load_resolves_relative_manifests() {
+        let dir = test_dir("config_relative");
+        let path = write_file(
+            &dir,
+            "config.toml",
+            "manifests = [\"dotfiles/manifest.toml\", \"/abs/homage.toml\", \"~/homage.toml\"]\ncolor = \"never\"\nidentity = \"keys/age.txt\"\nescalate = \"doas\"",
+        );
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(
+            config.manifests,
+            vec![
+                dir.join("dotfiles/manifest.toml"),
+                PathBuf::from("/abs/homage.toml"),
+                PathBuf::from(std::env::var("HOME").unwrap()).join("homage.toml"),
+            ]
+        );
+        assert_eq!(config.color, Some(ColorMode::Never));
+        assert_eq!(config.identity, Some(dir.join("keys/age.txt")));
+        assert_eq!(config.escalate.as_deref(), Some("doas"));
+    }
+
+    #[test]
+    fn profiles_select_tags() {
+        let dir = test_dir("config_profiles");
+        let path = write_file(
+            &dir,
+            "config.toml",
+            "profile = \"work\"\nbackup = \"always\"\noutput_format = \"json\"\n\
+             [profiles]\nwork = [\"shell\", \"work\"]",
+        );
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("work"));
+        assert_eq!(config.backup, Some(BackupPolicy::Always));
+        assert_eq!(config.output_format, Some(OutputFormat::Json));
+        assert_eq!(config.profile_tags("work").unwrap(), ["shell", "work"]);
+        assert!(config.profile_tags("home").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = test_dir("config_unknown");
+        let path = write_file(&dir, "config.toml", "colour = \"never\"");
+
+        assert!(Config::load(&path).is_err());
+    }
+}
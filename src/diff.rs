@@ -0,0 +1,233 @@
+use std::path::Path;
+
+use crate::fs::Fs;
+
+// Number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+// Upper bound on the size of the comparison table, larger files are only reported as different.
+const MAX_CELLS: usize = 16 * 1024 * 1024;
+
+/// Returns a unified diff turning the contents of `old` into the contents of `new`,
+/// or `None` when both are byte-identical. Paths that cannot be read or do not hold text
+/// are described instead of diffed.
+pub fn diff_files(fs: &dyn Fs, old: &Path, new: &Path) -> Option<String> {
+    let read = |path: &Path| {
+        if fs.is_dir(path) {
+            return Err(format!("{} is a directory", path.display()));
+        }
+        fs.read(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))
+    };
+
+    let (old_bytes, new_bytes) = match (read(old), read(new)) {
+        (Ok(old_bytes), Ok(new_bytes)) => (old_bytes, new_bytes),
+        (Err(err), _) | (_, Err(err)) => return Some(format!("{}\n", err)),
+    };
+
+    if old_bytes == new_bytes {
+        return None;
+    }
+
+    match (
+        std::str::from_utf8(&old_bytes),
+        std::str::from_utf8(&new_bytes),
+    ) {
+        (Ok(old_text), Ok(new_text)) => Some(unified_diff(
+            &old.display().to_string(),
+            &new.display().to_string(),
+            old_text,
+            new_text,
+        )),
+        _ => Some(format!(
+            "Binary files {} and {} differ\n",
+            old.display(),
+            new.display()
+        )),
+    }
+}
+
+/// Whether both paths are regular files with the same contents.
+pub fn is_identical(fs: &dyn Fs, a: &Path, b: &Path) -> bool {
+    fs.is_file(a) && fs.is_file(b) && diff_files(fs, a, b).is_none()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Returns a unified diff between two texts, labelled with the given names.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+
+    let Some(lines) = diff_lines(&old_lines, &new_lines) else {
+        out.push_str("Files are too large to compare\n");
+        return out;
+    };
+
+    for hunk in hunks(&lines) {
+        let (mut old_start, mut new_start) = (1, 1);
+        for line in &lines[..hunk.start] {
+            match line {
+                Line::Same(_) => {
+                    old_start += 1;
+                    new_start += 1;
+                }
+                Line::Removed(_) => old_start += 1,
+                Line::Added(_) => new_start += 1,
+            }
+        }
+
+        let body = &lines[hunk];
+        let old_len = body.iter().filter(|l| !matches!(l, Line::Added(_))).count();
+        let new_len = body
+            .iter()
+            .filter(|l| !matches!(l, Line::Removed(_)))
+            .count();
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len)
+        ));
+
+        for line in body {
+            let (prefix, text) = match line {
+                Line::Same(text) => (' ', text),
+                Line::Removed(text) => ('-', text),
+                Line::Added(text) => ('+', text),
+            };
+            out.push(prefix);
+            out.push_str(text);
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+// Formats a hunk range, an empty range starts at the line before it.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start - 1),
+        1 => start.to_string(),
+        _ => format!("{},{}", start, len),
+    }
+}
+
+// Computes the line changes from the longest common subsequence of both texts.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<Line<'a>>> {
+    let (n, m) = (old.len(), new.len());
+    if (n + 1).saturating_mul(m + 1) > MAX_CELLS {
+        return None;
+    }
+
+    // lcs[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[at(i, j)] = if old[i] == new[j] {
+                lcs[at(i + 1, j + 1)] + 1
+            } else {
+                lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut lines = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[at(i + 1, j)] >= lcs[at(i, j + 1)] {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|line| Line::Removed(line)));
+    lines.extend(new[j..].iter().map(|line| Line::Added(line)));
+
+    Some(lines)
+}
+
+// Groups changed lines with their surrounding context, merging groups that overlap.
+fn hunks(lines: &[Line]) -> Vec<std::ops::Range<usize>> {
+    let mut hunks: Vec<std::ops::Range<usize>> = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if matches!(line, Line::Same(_)) {
+            continue;
+        }
+
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.end => last.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+
+    #[test]
+    fn unified_diff_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\n";
+
+        assert_eq!(
+            unified_diff("old", "new", old, new),
+            "--- old\n+++ new\n\
+             @@ -1,8 +1,9 @@\n a\n b\n c\n-d\n+D\n e\n f\n g\n h\n+i\n"
+        );
+    }
+
+    #[test]
+    fn separate_hunks_for_distant_changes() {
+        let old: String = (1..=20).map(|i| format!("{i}\n")).collect();
+        let new: String = (1..=20)
+            .filter(|i| *i != 19)
+            .map(|i| match i {
+                2 => "two\n".to_string(),
+                _ => format!("{i}\n"),
+            })
+            .collect();
+
+        let diff = unified_diff("old", "new", &old, &new);
+        assert!(diff.contains("@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n"));
+        assert!(diff.contains("@@ -16,5 +16,4 @@\n 16\n 17\n 18\n-19\n 20\n"));
+    }
+
+    #[test]
+    fn identical_and_binary_files() {
+        let fs = MemFs::new();
+        let a = fs.add_file("/a", "same");
+        let b = fs.add_file("/b", "same");
+        fs.write(Path::new("/c"), &[0xff, 0x00]).unwrap();
+
+        assert!(diff_files(&fs, &a, &b).is_none());
+        assert!(is_identical(&fs, &a, &b));
+        assert!(!is_identical(&fs, &a, Path::new("/")));
+        assert_eq!(
+            diff_files(&fs, &a, Path::new("/c")).unwrap(),
+            "Binary files /a and /c differ\n"
+        );
+    }
+}
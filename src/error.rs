@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+/// Errors returned by the homage library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A filesystem operation failed, `context` describes what was attempted on `path`.
+    #[error("{context}: {}", path.display())]
+    Io {
+        context: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A manifest, cache or config file is not valid TOML or does not match the expected format.
+    #[error("Failed to parse {}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    /// The cache could not be serialized.
+    #[error("Failed to serialize cache")]
+    Serialize(#[from] toml::ser::Error),
+
+    /// An export could not be serialized to JSON.
+    #[error("Failed to serialize JSON")]
+    Json(#[from] serde_json::Error),
+
+    /// A manifest includes itself, directly or through other manifests.
+    #[error("Circular include detected: {}", .0.display())]
+    CircularInclude(PathBuf),
+
+    /// A manifest entry has a mode that is not an octal permission mode.
+    #[error("Invalid mode '{0}', expected an octal mode such as \"0600\"")]
+    InvalidMode(String),
+
+    /// A hard link cannot be created since its source and target are on different filesystems.
+    #[error("Cannot hard link {}, its source is on a different filesystem", .0.display())]
+    CrossDevice(PathBuf),
+
+    /// A manifest source is neither a regular file nor a directory.
+    #[error("Source path is neither a file nor directory: {}", .0.display())]
+    InvalidSource(PathBuf),
+
+    /// A path can not be written to a manifest since it is not valid UTF-8.
+    #[error("Path is not valid UTF-8: {}", .0.display())]
+    NonUtf8Path(PathBuf),
+
+    /// A bundle is truncated, was modified after it was created or is not a bundle at all.
+    #[error("Invalid bundle {}: {message}", path.display())]
+    InvalidBundle { path: PathBuf, message: String },
+
+    /// The setup of another dotfiles tool could not be read.
+    #[error("Failed to import {}: {message}", path.display())]
+    Import { path: PathBuf, message: String },
+
+    /// Several sources map to the same target path.
+    #[error("Conflicting target paths detected:\n{}", fmt_duplicates(.0))]
+    DuplicateTargets(Vec<DuplicateTarget>),
+
+    /// Two of the manifests loaded together list the same source.
+    #[error("{} is listed by both {} and {}", path.display(), first.display(), second.display())]
+    DuplicateSource {
+        path: PathBuf,
+        first: PathBuf,
+        second: PathBuf,
+    },
+
+    /// Targets that should be installed are occupied by files not managed by homage.
+    #[error("Cannot install, the following target files already exist:\n{}", fmt_paths(.0))]
+    Conflict(Vec<PathBuf>),
+
+    /// An age identity file does not contain valid identities.
+    #[error("Invalid identity file: {}", .0.display())]
+    InvalidIdentity(PathBuf),
+
+    /// A secret could not be encrypted.
+    #[error("Failed to encrypt secret: {0}")]
+    Encrypt(String),
+
+    /// A secret could not be decrypted with the given key.
+    #[error("Failed to decrypt {}: {message}", path.display())]
+    Decrypt { path: PathBuf, message: String },
+
+    /// Secrets need to be decrypted but no key was given.
+    #[error("No key to decrypt {}, configure an identity file or a passphrase", .0.display())]
+    MissingKey(PathBuf),
+
+    /// A required environment variable is not set.
+    #[error("Could not determine ${0}")]
+    MissingEnv(&'static str),
+}
+
+/// Two sources that both map to `target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateTarget {
+    pub target: PathBuf,
+    pub first: PathBuf,
+    pub second: PathBuf,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn io(
+        context: &'static str,
+        path: impl Into<PathBuf>,
+        source: std::io::Error,
+    ) -> Error {
+        Error::Io {
+            context,
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+fn fmt_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("  {}", path.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn fmt_duplicates(duplicates: &[DuplicateTarget]) -> String {
+    duplicates
+        .iter()
+        .map(|d| {
+            format!(
+                "  {} and {} both target {}",
+                d.first.display(),
+                d.second.display(),
+                d.target.display()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,424 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+use crate::manifest::{Entry, Manifest};
+
+/// Name of the stow package holding the untagged entries.
+const STOW_PACKAGE: &str = "dotfiles";
+
+/// Helpers of the exported shell script. Links already in place are skipped, anything else
+/// occupying a target stops the script. Missing directories are created with the directory mode
+/// of the entry, if any, which is also set on the directory holding the target.
+const SH_PREAMBLE: &str = r#"#!/bin/sh
+# Generated by homage
+set -eu
+
+fail() {
+  printf '%s\n' "$*" >&2
+  exit 1
+}
+
+inode() {
+  ls -di "$1" | awk '{ print $1 }'
+}
+
+# Creates the directory and its missing parents, with the mode if one is given
+make_dir() {
+  if [ -d "$1" ]; then return; fi
+  make_dir "$(dirname "$1")" "$2"
+  if [ -n "$2" ]; then mkdir -m "$2" "$1"; else mkdir "$1"; fi
+}
+
+parent() {
+  make_dir "$(dirname "$1")" "$2"
+  if [ -n "$2" ]; then chmod "$2" "$(dirname "$1")"; fi
+}
+
+link() {
+  parent "$2" "${3:-}"
+  if [ -L "$2" ] && [ "$(readlink "$2")" = "$1" ]; then return; fi
+  if [ -e "$2" ] || [ -L "$2" ]; then fail "$2 already exists"; fi
+  ln -s "$1" "$2"
+}
+
+hardlink() {
+  parent "$2" "${3:-}"
+  if [ -f "$2" ] && [ ! -L "$2" ] && [ "$(inode "$2")" = "$(inode "$1")" ]; then return; fi
+  if [ -e "$2" ] || [ -L "$2" ]; then fail "$2 already exists"; fi
+  ln "$1" "$2"
+}
+
+"#;
+
+/// A manifest rendered for another system.
+#[derive(Debug, Default)]
+pub struct Export {
+    pub content: String,
+    /// Entries the other system has no equivalent for, which were left out, with the reason.
+    pub skipped: Vec<String>,
+}
+
+/// An entry as exported to JSON.
+#[derive(Debug, Serialize)]
+struct JsonEntry<'a> {
+    source: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    tags: &'a BTreeSet<String>,
+    link: &'static str,
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dir_mode: Option<String>,
+    system: bool,
+}
+
+/// Exports every entry with all of its settings as a JSON array. Entries with a path that is
+/// not valid UTF-8 are left out, as JSON strings can not hold it.
+pub fn json(manifest: &Manifest) -> Result<Export> {
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for (source, entry) in &manifest.entries {
+        let (Some(source), Some(target)) = (source.to_str(), entry.target.to_str()) else {
+            skip_non_utf8(entry, &mut skipped);
+            continue;
+        };
+        entries.push(JsonEntry {
+            source,
+            target,
+            tags: &entry.tags,
+            link: if entry.hardlink {
+                "hardlink"
+            } else {
+                "symlink"
+            },
+            encrypted: entry.encrypted,
+            mode: entry.modes.file.map(|mode| format!("{mode:04o}")),
+            dir_mode: entry.modes.dir.map(|mode| format!("{mode:04o}")),
+            system: entry.system,
+        });
+    }
+
+    let mut content = serde_json::to_string_pretty(&entries)?;
+    content.push('\n');
+    Ok(Export { content, skipped })
+}
+
+/// Exports the entries below `~` as a home-manager module setting `home.file`. Sources are
+/// referenced by their absolute path. Encrypted entries, targets outside of the home directory
+/// and paths that are not valid UTF-8 are left out. Targets are expected as loaded with
+/// [`Layout::portable`].
+///
+/// [`Layout::portable`]: crate::utils::Layout::portable
+pub fn nix(manifest: &Manifest) -> Result<Export> {
+    let mut export = Export::default();
+    let mut files = String::new();
+
+    for (source, entry) in &manifest.entries {
+        let Some(target) = home_relative(entry, &mut export.skipped) else {
+            continue;
+        };
+
+        let (Some(source), Some(target)) = (source.to_str(), target.to_str()) else {
+            skip_non_utf8(entry, &mut export.skipped);
+            continue;
+        };
+
+        let mut settings = format!("source = {};", nix_string(source));
+        if entry.modes.file.is_some_and(|mode| mode & 0o111 != 0) {
+            settings.push_str(" executable = true;");
+        }
+        let _ = writeln!(files, "    {} = {{ {} }};", nix_string(target), settings);
+    }
+
+    export.content = format!("{{\n  home.file = {{\n{files}  }};\n}}\n");
+    Ok(export)
+}
+
+/// Exports the entries as a POSIX shell script creating the links. Hard links and directory
+/// modes are kept, while encrypted entries are left out. File modes are reported as skipped,
+/// since setting them would change the sources. Targets are expected as loaded with
+/// [`Layout::portable`], `~` is written as `$HOME`. The script stops at the first target
+/// occupied by another file instead of replacing it.
+///
+/// [`Layout::portable`]: crate::utils::Layout::portable
+pub fn sh(manifest: &Manifest) -> Result<Export> {
+    let mut export = Export::default();
+    let mut script = String::from(SH_PREAMBLE);
+
+    for (source, entry) in &manifest.entries {
+        if entry.encrypted {
+            export
+                .skipped
+                .push(format!("{}: encrypted", entry.target.display()));
+            continue;
+        }
+
+        let source = sh_quote_path(source);
+        let target = match entry.target.strip_prefix("~") {
+            Ok(rel) if rel.as_os_str().is_empty() => "\"$HOME\"".to_string(),
+            Ok(rel) => format!("\"$HOME\"/{}", sh_quote_path(rel)),
+            Err(_) => sh_quote_path(&entry.target),
+        };
+
+        let command = if entry.hardlink { "hardlink" } else { "link" };
+        let dir_mode = entry
+            .modes
+            .dir
+            .map(|mode| format!(" {mode:04o}"))
+            .unwrap_or_default();
+        let _ = writeln!(script, "{command} {source} {target}{dir_mode}");
+        if let Some(mode) = entry.modes.file {
+            export.skipped.push(format!(
+                "{}: mode {mode:04o} is not set, as it would change the source",
+                entry.target.display()
+            ));
+        }
+    }
+
+    export.content = script;
+    Ok(export)
+}
+
+/// Exports the entries below `~` as a tree for GNU Stow in `dir`, to be stowed into the home
+/// directory. Every entry is copied into the package named after its alphabetically first tag, untagged
+/// entries into `dotfiles`. Encrypted entries and targets outside of the home directory are
+/// left out. Returns the entries left out, with the reason.
+pub fn stow(fs: &dyn Fs, manifest: &Manifest, dir: &Path) -> Result<Vec<String>> {
+    let mut skipped = Vec::new();
+
+    for (source, entry) in &manifest.entries {
+        let Some(target) = home_relative(entry, &mut skipped) else {
+            continue;
+        };
+
+        let package = entry
+            .tags
+            .first()
+            .map(String::as_str)
+            .unwrap_or(STOW_PACKAGE);
+        let file = dir.join(package).join(target);
+
+        let content = fs
+            .read(source)
+            .map_err(|err| Error::io("Failed to read source", source, err))?;
+        let mode = fs
+            .metadata(source)
+            .map_err(|err| Error::io("Failed to read source", source, err))?
+            .mode;
+        if let Some(parent) = file.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create directory", parent, err))?;
+        }
+        fs.write(&file, &content)
+            .map_err(|err| Error::io("Failed to write file", &file, err))?;
+        fs.set_mode(&file, entry.modes.file.unwrap_or(mode & 0o7777))
+            .map_err(|err| Error::io("Failed to set mode", &file, err))?;
+    }
+
+    Ok(skipped)
+}
+
+// Returns the target relative to the home directory, or records why the entry is left out.
+fn home_relative<'a>(entry: &'a Entry, skipped: &mut Vec<String>) -> Option<&'a Path> {
+    if entry.encrypted {
+        skipped.push(format!("{}: encrypted", entry.target.display()));
+        return None;
+    }
+
+    match entry.target.strip_prefix("~") {
+        Ok(rel) if !rel.as_os_str().is_empty() => Some(rel),
+        _ => {
+            skipped.push(format!(
+                "{}: outside of the home directory",
+                entry.target.display()
+            ));
+            None
+        }
+    }
+}
+
+// Records an entry left out as one of its paths is not valid UTF-8.
+fn skip_non_utf8(entry: &Entry, skipped: &mut Vec<String>) {
+    skipped.push(format!(
+        "{}: the path is not valid UTF-8",
+        entry.target.display()
+    ));
+}
+
+/// Quotes a string for a POSIX shell.
+pub fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Quotes a path for a POSIX shell. Bytes that are not valid UTF-8 can not be part of the
+/// script text, they are written as octal escapes expanded by `printf` instead.
+pub fn sh_quote_path(path: &Path) -> String {
+    let mut quoted = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted.push_str(&sh_quote(chunk.valid()));
+        }
+        if !chunk.invalid().is_empty() {
+            // The bytes are never newlines, which the command substitution would strip
+            let escaped: String = chunk
+                .invalid()
+                .iter()
+                .map(|byte| format!("\\{byte:03o}"))
+                .collect();
+            let _ = write!(quoted, "\"$(printf '{escaped}')\"");
+        }
+    }
+    if quoted.is_empty() {
+        quoted = sh_quote("");
+    }
+    quoted
+}
+
+// Quotes a string for a Nix expression.
+fn nix_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+    use crate::utils::Layout;
+
+    fn manifest(fs: &MemFs) -> Manifest {
+        fs.add_file("/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file("/dotfiles/bin/backup", "#!/bin/sh");
+        fs.add_file("/dotfiles/it's.conf", "quote");
+        fs.add_file("/dotfiles/token.age", "secret");
+        fs.add_file("/dotfiles/keyd.conf", "keyd");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = { target = \"~/.config/nvim\", tags = [\"editor\"] }\n\
+             \"bin/backup\" = { target = \"~/.local/bin/backup\", mode = \"0755\" }\n\
+             \"it's.conf\" = \"~/it's.conf\"\n\
+             \"token.age\" = { target = \"~/.token\", encrypted = true }\n\
+             [system]\n\"keyd.conf\" = \"/etc/keyd/default.conf\"",
+        );
+        Manifest::load(fs, &path, &Layout::portable()).unwrap()
+    }
+
+    #[test]
+    fn json_lists_every_entry() {
+        let export = json(&manifest(&MemFs::new())).unwrap();
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&export.content).unwrap();
+        assert_eq!(entries.len(), 5);
+
+        let nvim = entries
+            .iter()
+            .find(|e| e["source"] == "/dotfiles/nvim/init.lua")
+            .unwrap();
+        assert_eq!(nvim["target"], "~/.config/nvim/init.lua");
+        assert_eq!(nvim["tags"][0], "editor");
+        assert_eq!(nvim["link"], "symlink");
+    }
+
+    #[test]
+    fn nix_sets_home_files() {
+        let export = nix(&manifest(&MemFs::new())).unwrap();
+        assert!(export.content.contains(
+            "    \".config/nvim/init.lua\" = { source = \"/dotfiles/nvim/init.lua\"; };\n"
+        ));
+        assert!(export.content.contains(
+            "    \".local/bin/backup\" = { source = \"/dotfiles/bin/backup\"; executable = true; };\n"
+        ));
+        assert_eq!(export.skipped.len(), 2);
+    }
+
+    #[test]
+    fn sh_quotes_paths() {
+        let export = sh(&manifest(&MemFs::new())).unwrap();
+        assert!(
+            export
+                .content
+                .contains("link '/dotfiles/it'\\''s.conf' \"$HOME\"/'it'\\''s.conf'\n")
+        );
+        assert!(
+            export
+                .content
+                .contains("link '/dotfiles/keyd.conf' '/etc/keyd/default.conf'\n")
+        );
+        assert!(!export.content.contains("chmod 0755"));
+        assert!(
+            export
+                .skipped
+                .iter()
+                .any(|skipped| skipped.contains("mode 0755"))
+        );
+        assert!(!export.content.contains("token"));
+    }
+
+    #[test]
+    fn non_utf8_paths_are_quoted_or_skipped() {
+        use std::ffi::OsStr;
+
+        let fs = MemFs::new();
+        let name = OsStr::from_bytes(b"caf\xe9'\xff.conf");
+        let source = fs.add_file(Path::new("/dotfiles/latin1").join(name), "latin1");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"latin1\" = \"~/.config/latin1\"",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::portable()).unwrap();
+
+        let quoted = sh_quote_path(&source);
+        assert_eq!(
+            quoted,
+            "'/dotfiles/latin1/caf'\"$(printf '\\351')\"''\\'''\"$(printf '\\377')\"'.conf'"
+        );
+        let export = sh(&manifest).unwrap();
+        assert!(export.content.ends_with(&format!(
+            "link {quoted} \"$HOME\"/{}\n",
+            sh_quote_path(&Path::new(".config/latin1").join(name))
+        )));
+        let output = std::process::Command::new("sh")
+            .args(["-c", &format!("printf %s {quoted}")])
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, source.as_os_str().as_bytes());
+
+        for export in [json(&manifest).unwrap(), nix(&manifest).unwrap()] {
+            assert!(!export.content.contains("caf"));
+            assert_eq!(
+                export.skipped,
+                [format!(
+                    "~/.config/latin1/{}: the path is not valid UTF-8",
+                    Path::new(name).display()
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn stow_tree_has_a_package_per_tag() {
+        let fs = MemFs::new();
+        let manifest = manifest(&fs);
+        let skipped = stow(&fs, &manifest, Path::new("/stow")).unwrap();
+
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(
+            fs.read_to_string(Path::new("/stow/editor/.config/nvim/init.lua"))
+                .unwrap(),
+            "nvim"
+        );
+        let backup = Path::new("/stow/dotfiles/.local/bin/backup");
+        assert_eq!(fs.metadata(backup).unwrap().mode & 0o7777, 0o755);
+    }
+}
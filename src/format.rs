@@ -17,3 +17,28 @@ pub fn fmt_link(path: &Path) -> ColoredString {
 pub fn fmt_error(path: &Path) -> ColoredString {
     path.display().to_string().red()
 }
+
+/// Marks an operation run through the escalation command, such as `[sudo]`.
+pub fn fmt_privileged(command: &str) -> ColoredString {
+    format!("[{}]", command).yellow().bold()
+}
+
+/// Colors the lines of a unified diff.
+pub fn fmt_diff(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let colored = if line.starts_with("---") || line.starts_with("+++") {
+                line.bold()
+            } else if line.starts_with("@@") {
+                line.cyan()
+            } else if line.starts_with('-') {
+                line.red()
+            } else if line.starts_with('+') {
+                line.green()
+            } else {
+                line.normal()
+            };
+            format!("{}\n", colored)
+        })
+        .collect()
+}
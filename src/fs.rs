@@ -0,0 +1,969 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use walkdir::WalkDir;
+
+/// The kind of a filesystem entry, symlinks are only reported when they are not followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// The subset of file metadata homage relies on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileKind,
+    /// Permission bits of the entry.
+    pub mode: u32,
+    /// Size of the entry in bytes.
+    pub len: u64,
+    /// Device of the filesystem holding the entry.
+    pub dev: u64,
+    /// Inode number of the entry, shared by all hard links to it.
+    pub ino: u64,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+}
+
+/// The filesystem operations homage performs. All modules go through this trait,
+/// so they can run against the real filesystem or the in-memory [`MemFs`].
+pub trait Fs {
+    /// Reads the contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Writes a file, replacing it if it already exists. The parent directory must exist.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a symlink at `target` pointing to `source`.
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()>;
+
+    /// Creates a hard link at `target` to the file at `source`, both must be on the same filesystem.
+    fn hard_link(&self, source: &Path, target: &Path) -> io::Result<()>;
+
+    /// Returns the path a symlink points to.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Returns the metadata of a path, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Returns the metadata of a path without following a symlink at the path itself.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Returns the absolute path with all symlinks and relative components resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Returns all regular files below a directory, recursively and without following symlinks.
+    fn walk(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns the paths of all entries directly inside a directory, including symlinks.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Sets the permission bits of a file or directory, following symlinks.
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Moves a file or directory, replacing any file at the destination.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file or symlink.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes a directory and everything below it, without following symlinks.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Reads the contents of a file as UTF-8.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Whether the path exists, following symlinks.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Whether the path is a directory, following symlinks.
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_dir())
+    }
+
+    /// Whether the path is a regular file, following symlinks.
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_file())
+    }
+
+    /// Whether the path itself is a symlink, which may be broken.
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+    }
+}
+
+/// The real filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFs;
+
+impl Fs for OsFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        std::fs::write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(source, target)
+    }
+
+    fn hard_link(&self, source: &Path, target: &Path) -> io::Result<()> {
+        std::fs::hard_link(source, target)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        std::fs::metadata(path).map(os_metadata)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        std::fs::symlink_metadata(path).map(os_metadata)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn walk(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        // Unreadable entries below the directory are skipped, like a failed walk of the root
+        let mut walker = WalkDir::new(path).into_iter();
+        match walker.next() {
+            Some(Err(err)) => return Err(err.into()),
+            Some(Ok(root)) if root.file_type().is_file() => return Ok(vec![root.into_path()]),
+            _ => {}
+        }
+
+        Ok(walker
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+}
+
+fn os_metadata(metadata: std::fs::Metadata) -> Metadata {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else {
+        FileKind::File
+    };
+
+    Metadata {
+        kind,
+        mode: metadata.permissions().mode() & 0o7777,
+        len: metadata.size(),
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    }
+}
+
+/// The real filesystem, changed through an escalation command such as `sudo` or `doas`.
+/// Reads go to the filesystem directly, every change runs a standard Unix utility
+/// like `ln` or `mkdir` prefixed with the escalation command.
+#[derive(Debug, Clone)]
+pub struct EscalatedFs {
+    command: Vec<String>,
+}
+
+impl EscalatedFs {
+    /// Creates the filesystem from a command line such as `"sudo"` or `"doas -n"`.
+    /// Any command that runs its arguments works, `"env"` runs them without escalating.
+    pub fn new(command: &str) -> EscalatedFs {
+        EscalatedFs {
+            command: command.split_whitespace().map(String::from).collect(),
+        }
+    }
+
+    /// Returns the name of the escalation command.
+    pub fn name(&self) -> &str {
+        self.command.first().map(String::as_str).unwrap_or_default()
+    }
+
+    /// Returns the escalation command along with its options.
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    fn run(&self, args: &[&OsStr], stdin: Option<&[u8]>) -> io::Result<()> {
+        let Some((program, options)) = self.command.split_first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No escalation command configured",
+            ));
+        };
+
+        let mut child = Command::new(program)
+            .args(options)
+            .args(args)
+            .stdin(match stdin {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let (Some(contents), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(contents)?;
+        }
+
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        Err(io::Error::other(format!(
+            "'{}' {}: {}",
+            self.command.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+impl Fs for EscalatedFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        OsFs.read(path)
+    }
+
+    // New files are created with mode 0600 rather than the default umask, so they are not
+    // readable by others until the caller sets the intended mode. Existing files keep theirs.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.run(
+            &[
+                "sh".as_ref(),
+                "-c".as_ref(),
+                "umask 077 && cat > \"$1\"".as_ref(),
+                "sh".as_ref(),
+                path.as_ref(),
+            ],
+            Some(contents),
+        )
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.run(
+            &[
+                "mkdir".as_ref(),
+                "-p".as_ref(),
+                "--".as_ref(),
+                path.as_ref(),
+            ],
+            None,
+        )
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        self.run(
+            &[
+                "ln".as_ref(),
+                "-s".as_ref(),
+                "--".as_ref(),
+                source.as_ref(),
+                target.as_ref(),
+            ],
+            None,
+        )
+    }
+
+    fn hard_link(&self, source: &Path, target: &Path) -> io::Result<()> {
+        self.run(
+            &[
+                "ln".as_ref(),
+                "--".as_ref(),
+                source.as_ref(),
+                target.as_ref(),
+            ],
+            None,
+        )
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        OsFs.read_link(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        OsFs.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        OsFs.symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        OsFs.canonicalize(path)
+    }
+
+    fn walk(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        OsFs.walk(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        OsFs.read_dir(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mode = format!("{:o}", mode);
+        self.run(
+            &[
+                "chmod".as_ref(),
+                mode.as_ref(),
+                "--".as_ref(),
+                path.as_ref(),
+            ],
+            None,
+        )
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.run(
+            &[
+                "mv".as_ref(),
+                "-f".as_ref(),
+                "--".as_ref(),
+                from.as_ref(),
+                to.as_ref(),
+            ],
+            None,
+        )
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.run(&["rm".as_ref(), "--".as_ref(), path.as_ref()], None)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.run(
+            &["rm".as_ref(), "-r".as_ref(), "--".as_ref(), path.as_ref()],
+            None,
+        )
+    }
+}
+
+/// An in-memory filesystem for tests. Paths are absolute and the root directory always exists.
+/// Failures can be injected for a path with [`MemFs::fail`], and directories can be placed
+/// on a separate device with [`MemFs::mount`].
+#[derive(Debug, Default)]
+pub struct MemFs {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+    failures: RefCell<BTreeMap<PathBuf, i32>>,
+    mounts: RefCell<BTreeSet<PathBuf>>,
+    last_ino: Cell<u64>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    /// Hard links to a file share its data.
+    File(Rc<RefCell<FileData>>),
+    Dir {
+        mode: u32,
+    },
+    Symlink(PathBuf),
+}
+
+#[derive(Debug)]
+struct FileData {
+    contents: Vec<u8>,
+    mode: u32,
+    ino: u64,
+}
+
+// Maximum number of symlinks followed while resolving a path, like Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+impl MemFs {
+    pub fn new() -> MemFs {
+        MemFs::default()
+    }
+
+    /// Creates a file with the given contents, along with any missing parent directories.
+    pub fn add_file(&self, path: impl AsRef<Path>, contents: &str) -> PathBuf {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent).unwrap();
+        }
+        self.write(path, contents.as_bytes()).unwrap();
+        path.to_path_buf()
+    }
+
+    /// Creates a symlink, along with any missing parent directories of the link.
+    pub fn add_symlink(&self, source: impl AsRef<Path>, target: impl AsRef<Path>) {
+        if let Some(parent) = target.as_ref().parent() {
+            self.create_dir_all(parent).unwrap();
+        }
+        self.symlink(source.as_ref(), target.as_ref()).unwrap();
+    }
+
+    /// Makes reading, writing, creating, moving and removing the path, or anything below it, fail
+    /// with the given OS error such as `EACCES` (13) or `ENOSPC` (28). Metadata stays accessible.
+    pub fn fail(&self, path: impl AsRef<Path>, errno: i32) {
+        self.failures
+            .borrow_mut()
+            .insert(path.as_ref().to_path_buf(), errno);
+    }
+
+    /// Places the directory and everything below it on a separate device, so hard links
+    /// across it fail with `EXDEV` (18).
+    pub fn mount(&self, path: impl AsRef<Path>) {
+        self.mounts.borrow_mut().insert(path.as_ref().to_path_buf());
+    }
+
+    /// Removes all injected failures.
+    pub fn clear_failures(&self) {
+        self.failures.borrow_mut().clear();
+    }
+
+    // Devices are numbered from 1 for the root, followed by the mounts in order.
+    fn device(&self, resolved: &Path) -> u64 {
+        let mounts = self.mounts.borrow();
+        resolved
+            .ancestors()
+            .find_map(|ancestor| mounts.iter().position(|mount| mount == ancestor))
+            .map_or(1, |index| index as u64 + 2)
+    }
+
+    fn check_failure(&self, path: &Path) -> io::Result<()> {
+        let failures = self.failures.borrow();
+        match path.ancestors().find_map(|ancestor| failures.get(ancestor)) {
+            Some(errno) => Err(io::Error::from_raw_os_error(*errno)),
+            None => Ok(()),
+        }
+    }
+
+    // Resolves symlinks in every component of the path, and in the final component if `follow_last` is set.
+    // The final component does not need to exist.
+    fn resolve(&self, path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+        let nodes = self.nodes.borrow();
+        let mut pending: VecDeque<OsString> = components(path);
+        let mut resolved = PathBuf::from("/");
+        let mut hops = 0;
+
+        while let Some(part) = pending.pop_front() {
+            if part == ".." {
+                resolved.pop();
+                continue;
+            }
+
+            let candidate = resolved.join(&part);
+            let is_last = pending.is_empty();
+
+            match nodes.get(&candidate) {
+                Some(Node::Symlink(dest)) if !is_last || follow_last => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(io::Error::from_raw_os_error(40));
+                    }
+
+                    let dest = resolved.join(dest);
+                    let mut next = components(&dest);
+                    next.extend(pending);
+                    pending = next;
+                    resolved = PathBuf::from("/");
+                }
+                Some(Node::File(_)) if !is_last => {
+                    return Err(io::Error::from_raw_os_error(20));
+                }
+                None if !is_last => return Err(io::ErrorKind::NotFound.into()),
+                _ => resolved = candidate,
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    fn node(&self, path: &Path, follow_last: bool) -> io::Result<(PathBuf, Node)> {
+        let resolved = self.resolve(path, follow_last)?;
+        if resolved == Path::new("/") {
+            return Ok((resolved, Node::Dir { mode: 0o755 }));
+        }
+
+        let node = self.nodes.borrow().get(&resolved).cloned();
+        node.map(|node| (resolved, node))
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    // Resolves the parent of a path that is about to be created and checks that it is a directory.
+    fn prepare_create(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.resolve(path, false)?;
+        self.check_failure(path)?;
+        self.check_failure(&resolved)?;
+        let parent = resolved.parent().unwrap_or(Path::new("/"));
+        match self.node(parent, true)? {
+            (_, Node::Dir { .. }) => Ok(resolved),
+            _ => Err(io::Error::from_raw_os_error(20)),
+        }
+    }
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+// Only files are numbered, directories and symlinks have inode 0.
+fn node_metadata(node: &Node, dev: u64) -> Metadata {
+    match node {
+        Node::File(data) => {
+            let data = data.borrow();
+            Metadata {
+                kind: FileKind::File,
+                mode: data.mode,
+                len: data.contents.len() as u64,
+                dev,
+                ino: data.ino,
+            }
+        }
+        Node::Dir { mode } => Metadata {
+            kind: FileKind::Dir,
+            mode: *mode,
+            len: 0,
+            dev,
+            ino: 0,
+        },
+        Node::Symlink(dest) => Metadata {
+            kind: FileKind::Symlink,
+            mode: 0o777,
+            len: dest.as_os_str().len() as u64,
+            dev,
+            ino: 0,
+        },
+    }
+}
+
+impl Fs for MemFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.check_failure(path)?;
+        match self.node(path, true)? {
+            (_, Node::File(data)) => Ok(data.borrow().contents.clone()),
+            _ => Err(io::Error::from_raw_os_error(21)),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let resolved = self.prepare_create(&self.resolve(path, true)?)?;
+        let mut nodes = self.nodes.borrow_mut();
+        match nodes.get(&resolved) {
+            Some(Node::Dir { .. }) => Err(io::Error::from_raw_os_error(21)),
+            // Writing replaces the contents of the existing file, which hard links share
+            Some(Node::File(data)) => {
+                data.borrow_mut().contents = contents.to_vec();
+                Ok(())
+            }
+            _ => {
+                self.last_ino.set(self.last_ino.get() + 1);
+                let data = FileData {
+                    contents: contents.to_vec(),
+                    mode: 0o644,
+                    ino: self.last_ino.get(),
+                };
+                nodes.insert(resolved, Node::File(Rc::new(RefCell::new(data))));
+                Ok(())
+            }
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut ancestors: Vec<&Path> = path.ancestors().collect();
+        ancestors.reverse();
+
+        for ancestor in ancestors {
+            match self.node(ancestor, true) {
+                Ok((_, Node::Dir { .. })) => {}
+                Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    let resolved = self.prepare_create(ancestor)?;
+                    self.nodes
+                        .borrow_mut()
+                        .insert(resolved, Node::Dir { mode: 0o755 });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        let resolved = self.prepare_create(target)?;
+        let mut nodes = self.nodes.borrow_mut();
+        if nodes.contains_key(&resolved) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(resolved, Node::Symlink(source.to_path_buf()));
+        Ok(())
+    }
+
+    fn hard_link(&self, source: &Path, target: &Path) -> io::Result<()> {
+        let (source, node) = self.node(source, false)?;
+        let Node::File(data) = node else {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        };
+        let resolved = self.prepare_create(target)?;
+        if self.device(&source) != self.device(&resolved) {
+            return Err(io::Error::from_raw_os_error(18));
+        }
+
+        let mut nodes = self.nodes.borrow_mut();
+        if nodes.contains_key(&resolved) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(resolved, Node::File(data));
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.node(path, false)? {
+            (_, Node::Symlink(dest)) => Ok(dest),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.node(path, true)
+            .map(|(resolved, node)| node_metadata(&node, self.device(&resolved)))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.node(path, false)
+            .map(|(resolved, node)| node_metadata(&node, self.device(&resolved)))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.node(path, true).map(|(resolved, _)| resolved)
+    }
+
+    fn walk(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let (resolved, node) = self.node(path, true)?;
+        if let Node::File(_) = node {
+            return Ok(vec![path.to_path_buf()]);
+        }
+
+        let nodes = self.nodes.borrow();
+        Ok(nodes
+            .iter()
+            .filter(|(p, node)| p.starts_with(&resolved) && matches!(node, Node::File(_)))
+            .map(|(p, _)| path.join(p.strip_prefix(&resolved).unwrap()))
+            .collect())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.check_failure(path)?;
+        let (resolved, node) = self.node(path, true)?;
+        if !matches!(node, Node::Dir { .. }) {
+            return Err(io::Error::from_raw_os_error(20));
+        }
+
+        let nodes = self.nodes.borrow();
+        Ok(nodes
+            .keys()
+            .filter(|p| p.parent() == Some(resolved.as_path()))
+            .map(|p| path.join(p.file_name().unwrap()))
+            .collect())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let (resolved, _) = self.node(path, true)?;
+        self.check_failure(&resolved)?;
+
+        match self.nodes.borrow_mut().get_mut(&resolved) {
+            Some(Node::File(data)) => {
+                data.borrow_mut().mode = mode & 0o7777;
+                Ok(())
+            }
+            Some(Node::Dir { mode: current }) => {
+                *current = mode & 0o7777;
+                Ok(())
+            }
+            _ => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, _) = self.node(from, false)?;
+        self.check_failure(&from)?;
+        let to = self.prepare_create(to)?;
+
+        let mut nodes = self.nodes.borrow_mut();
+        if let Some(Node::Dir { .. }) = nodes.get(&to) {
+            return Err(io::Error::from_raw_os_error(21));
+        }
+
+        let moved: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|p| p.starts_with(&from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            nodes.insert(to.join(path.strip_prefix(&from).unwrap()), node);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check_failure(path)?;
+        match self.node(path, false)? {
+            (_, Node::Dir { .. }) => Err(io::Error::from_raw_os_error(21)),
+            (resolved, _) => {
+                self.nodes.borrow_mut().remove(&resolved);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check_failure(path)?;
+        let (resolved, node) = self.node(path, false)?;
+        if !matches!(node, Node::Dir { .. }) {
+            return Err(io::Error::from_raw_os_error(20));
+        }
+
+        let removed: Vec<PathBuf> = self
+            .nodes
+            .borrow()
+            .keys()
+            .filter(|p| p.starts_with(&resolved))
+            .cloned()
+            .collect();
+        for path in &removed {
+            self.check_failure(path)?;
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        for path in removed {
+            nodes.remove(&path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_fs_files_and_directories() {
+        let fs = MemFs::new();
+        fs.add_file("/home/user/.config/app.conf", "content");
+
+        assert!(fs.is_dir(Path::new("/home/user/.config")));
+        assert!(fs.is_file(Path::new("/home/user/.config/app.conf")));
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/.config/app.conf"))
+                .unwrap(),
+            "content"
+        );
+        assert_eq!(
+            fs.walk(Path::new("/home")).unwrap(),
+            vec![PathBuf::from("/home/user/.config/app.conf")]
+        );
+
+        let err = fs.write(Path::new("/missing/file"), b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn mem_fs_symlinks_are_resolved() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/nvim/init.lua", "nvim");
+        fs.add_symlink("/dotfiles/nvim", "/home/user/.config/nvim");
+        fs.add_symlink("/missing", "/home/user/broken");
+
+        let link = Path::new("/home/user/.config/nvim");
+        assert!(fs.is_symlink(link));
+        assert!(fs.is_dir(link));
+        assert_eq!(fs.read_link(link).unwrap(), PathBuf::from("/dotfiles/nvim"));
+        assert_eq!(
+            fs.canonicalize(&link.join("init.lua")).unwrap(),
+            PathBuf::from("/dotfiles/nvim/init.lua")
+        );
+
+        let broken = Path::new("/home/user/broken");
+        assert!(fs.is_symlink(broken));
+        assert!(!fs.exists(broken));
+
+        fs.remove_file(broken).unwrap();
+        assert!(!fs.is_symlink(broken));
+    }
+
+    #[test]
+    fn mem_fs_set_mode() {
+        let fs = MemFs::new();
+        let path = fs.add_file("/home/user/.ssh/config", "Host *");
+        assert_eq!(fs.metadata(&path).unwrap().mode, 0o644);
+
+        fs.set_mode(&path, 0o600).unwrap();
+        assert_eq!(fs.metadata(&path).unwrap().mode, 0o600);
+    }
+
+    #[test]
+    fn mem_fs_hard_links_share_data() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/app.conf", "old");
+        fs.create_dir_all(Path::new("/home/user")).unwrap();
+        let target = Path::new("/home/user/app.conf");
+
+        fs.hard_link(&source, target).unwrap();
+        fs.write(&source, b"new").unwrap();
+        assert_eq!(fs.read(target).unwrap(), b"new");
+        assert_eq!(
+            fs.metadata(target).unwrap().ino,
+            fs.metadata(&source).unwrap().ino
+        );
+
+        // A replaced source is a new file, the link keeps the old one
+        fs.remove_file(&source).unwrap();
+        fs.add_file(&source, "replaced");
+        assert_eq!(fs.read(target).unwrap(), b"new");
+        assert_ne!(
+            fs.metadata(target).unwrap().ino,
+            fs.metadata(&source).unwrap().ino
+        );
+
+        fs.mount("/mnt");
+        fs.create_dir_all(Path::new("/mnt/home")).unwrap();
+        let err = fs
+            .hard_link(&source, Path::new("/mnt/home/app.conf"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(18));
+    }
+
+    #[test]
+    fn mem_fs_rename_moves_subtree() {
+        let fs = MemFs::new();
+        fs.add_file("/old/a/b.conf", "b");
+        fs.create_dir_all(Path::new("/new")).unwrap();
+
+        fs.rename(Path::new("/old/a"), Path::new("/new/a")).unwrap();
+        assert!(!fs.exists(Path::new("/old/a")));
+        assert!(fs.is_file(Path::new("/new/a/b.conf")));
+    }
+
+    #[test]
+    fn mem_fs_remove_dir_all_keeps_siblings() {
+        let fs = MemFs::new();
+        fs.add_file("/store/a/b.conf", "b");
+        fs.add_file("/store.old/c.conf", "c");
+        fs.add_symlink("/store.old", "/store/link");
+
+        fs.remove_dir_all(Path::new("/store")).unwrap();
+        assert!(!fs.exists(Path::new("/store")));
+        assert!(fs.is_file(Path::new("/store.old/c.conf")));
+
+        let err = fs
+            .remove_dir_all(Path::new("/store.old/c.conf"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(20));
+    }
+
+    #[test]
+    fn mem_fs_injected_failures() {
+        let fs = MemFs::new();
+        fs.add_file("/home/user/file", "content");
+        fs.fail("/home/user", 13);
+
+        let err = fs
+            .symlink(Path::new("/src"), Path::new("/home/user/link"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(13));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        fs.clear_failures();
+        fs.fail("/home/user/file", 28);
+        let err = fs.write(Path::new("/home/user/file"), b"more").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn escalated_fs_runs_commands() {
+        let dir = crate::tests::test_dir("escalated_fs");
+        let fs = EscalatedFs::new("env");
+        let file = dir.join("etc/app.conf");
+        let link = dir.join("etc/app.link");
+
+        fs.create_dir_all(&dir.join("etc")).unwrap();
+        fs.write(&file, b"content").unwrap();
+        assert_eq!(fs.metadata(&file).unwrap().mode, 0o600);
+        fs.set_mode(&file, 0o640).unwrap();
+        fs.write(&file, b"content").unwrap();
+        fs.symlink(&file, &link).unwrap();
+
+        assert_eq!(fs.read_to_string(&link).unwrap(), "content");
+        assert_eq!(fs.metadata(&file).unwrap().mode, 0o640);
+
+        fs.remove_file(&link).unwrap();
+        assert!(!fs.is_symlink(&link));
+
+        fs.remove_dir_all(&dir.join("etc")).unwrap();
+        assert!(!fs.exists(&dir.join("etc")));
+
+        let err = fs.remove_file(&link).unwrap_err();
+        assert!(err.to_string().contains("'env'"));
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+
+use serde_norway::Value;
+
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+use crate::symlink::Modes;
+
+/// Names stow ignores anywhere in a package by default.
+const STOW_IGNORED: [&str; 11] = [
+    ".git",
+    ".gitignore",
+    ".gitmodules",
+    ".cvsignore",
+    ".svn",
+    ".hg",
+    ".bzr",
+    "_darcs",
+    "CVS",
+    "RCS",
+    ".stow-local-ignore",
+];
+
+/// Dotbot link options homage has no equivalent for.
+const DOTBOT_UNSUPPORTED: [&str; 5] = ["glob", "if", "relative", "exclude", "prefix"];
+
+/// A manifest entry translated from the setup of another tool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Imported {
+    pub source: PathBuf,
+    /// The target as written to the manifest, which may start with `~`.
+    pub target: String,
+    pub tags: BTreeSet<String>,
+    pub modes: Modes,
+}
+
+/// The outcome of translating the setup of another dotfiles tool into a manifest.
+#[derive(Debug, Default)]
+pub struct Import {
+    /// The translated entries by source path.
+    pub entries: BTreeMap<PathBuf, Imported>,
+    /// Descriptions of the constructs without an equivalent, which were left out.
+    pub untranslated: Vec<String>,
+}
+
+impl Import {
+    fn add(&mut self, source: PathBuf, target: String, tags: BTreeSet<String>, modes: Modes) {
+        let imported = Imported {
+            source: source.clone(),
+            target,
+            tags,
+            modes,
+        };
+        self.entries.insert(source, imported);
+    }
+
+    fn skip(&mut self, message: String) {
+        self.untranslated.push(message);
+    }
+
+    /// Renders the entries as a manifest placed in `dir`. Sources below it are written relative
+    /// to it, others as absolute paths.
+    pub fn to_manifest(&self, dir: &Path) -> Result<String> {
+        let mut manifest = String::from("[files]\n");
+
+        for imported in self.entries.values() {
+            let source = imported
+                .source
+                .strip_prefix(dir)
+                .unwrap_or(&imported.source);
+            let source = source
+                .to_str()
+                .ok_or_else(|| Error::NonUtf8Path(imported.source.clone()))?;
+
+            let mut settings = vec![format!("target = {}", quote(&imported.target))];
+            if !imported.tags.is_empty() {
+                let tags: Vec<_> = imported.tags.iter().map(|tag| quote(tag)).collect();
+                settings.push(format!("tags = [{}]", tags.join(", ")));
+            }
+            if let Some(mode) = imported.modes.file {
+                settings.push(format!("mode = \"{mode:04o}\""));
+            }
+            if let Some(mode) = imported.modes.dir {
+                settings.push(format!("dir_mode = \"{mode:04o}\""));
+            }
+
+            let value = match settings.len() {
+                1 => quote(&imported.target),
+                _ => format!("{{ {} }}", settings.join(", ")),
+            };
+            manifest.push_str(&format!("{} = {}\n", quote(source), value));
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// Translates a GNU Stow directory. Every package, a directory below `dir`, is linked into
+/// `target` and its files are tagged with the package name. Stow's default ignore list is
+/// applied and `dot-` prefixes are turned into dots, like `stow --dotfiles` does.
+pub fn stow(fs: &dyn Fs, dir: &Path, target: &str) -> Result<Import> {
+    let mut import = Import::default();
+    let mut packages = fs
+        .read_dir(dir)
+        .map_err(|err| Error::io("Failed to read stow directory", dir, err))?;
+    packages.sort();
+
+    for package in packages {
+        let Some(name) = package.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !fs.is_dir(&package) || name.starts_with('.') {
+            continue;
+        }
+        if fs.exists(&package.join(".stow-local-ignore")) {
+            import.skip(format!(
+                "{name}/.stow-local-ignore: custom ignore lists are not translated, \
+                 the default list was applied"
+            ));
+        }
+
+        let files = fs
+            .walk(&package)
+            .map_err(|err| Error::io("Failed to read stow package", &package, err))?;
+        for file in files {
+            let rel = file.strip_prefix(&package).unwrap_or(&file);
+            if is_stow_ignored(rel) {
+                continue;
+            }
+
+            let mut target = target.trim_end_matches('/').to_string();
+            for component in rel.components() {
+                let component = component.as_os_str().to_string_lossy();
+                let component = match component.strip_prefix("dot-") {
+                    Some(rest) => format!(".{rest}"),
+                    None => component.into_owned(),
+                };
+                target.push('/');
+                target.push_str(&component);
+            }
+
+            let tags = BTreeSet::from([name.to_string()]);
+            import.add(file, target, tags, Modes::default());
+        }
+    }
+
+    Ok(import)
+}
+
+fn is_stow_ignored(rel: &Path) -> bool {
+    let top_level = rel.components().count() == 1;
+    rel.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        STOW_IGNORED.contains(&name.as_ref())
+            || name.starts_with(".#")
+            || name.ends_with('~')
+            || (name.starts_with('#') && name.ends_with('#'))
+            || (top_level
+                && (name.starts_with("README") || name.starts_with("LICENSE") || name == "COPYING"))
+    })
+}
+
+/// Translates the `link` directives of a Dotbot config, either YAML or JSON. Sources are
+/// resolved against the directory of the config. Other directives, and link options changing
+/// what gets linked, are reported as untranslated.
+pub fn dotbot(fs: &dyn Fs, config: &Path) -> Result<Import> {
+    let invalid = |message: String| Error::Import {
+        path: config.to_path_buf(),
+        message,
+    };
+
+    let content = fs
+        .read_to_string(config)
+        .map_err(|err| Error::io("Failed to read Dotbot config", config, err))?;
+    let directives: Vec<Value> =
+        serde_norway::from_str(&content).map_err(|err| invalid(err.to_string()))?;
+    let base = config.parent().unwrap_or(Path::new("/"));
+
+    let mut import = Import::default();
+    let mut defaults = serde_norway::Mapping::new();
+    for directive in &directives {
+        let Some(directive) = directive.as_mapping() else {
+            return Err(invalid("expected a list of directives".to_string()));
+        };
+
+        for (name, value) in directive {
+            match name.as_str().unwrap_or_default() {
+                "defaults" => {
+                    if let Some(link) = value.get("link").and_then(Value::as_mapping) {
+                        defaults.extend(link.clone());
+                    }
+                }
+                "link" => {
+                    let links = value
+                        .as_mapping()
+                        .ok_or_else(|| invalid("expected a mapping of links".to_string()))?;
+                    for (target, link) in links {
+                        dotbot_link(&mut import, base, &defaults, target, link);
+                    }
+                }
+                "clean" => import.skip(
+                    "clean: homage only cleans up the stale links it installed itself".to_string(),
+                ),
+                "create" => import.skip(
+                    "create: homage only creates the directories holding targets".to_string(),
+                ),
+                "shell" => import.skip("shell: homage does not run commands".to_string()),
+                other => import.skip(format!("{other}: unknown directive or plugin")),
+            }
+        }
+    }
+
+    Ok(import)
+}
+
+fn dotbot_link(
+    import: &mut Import,
+    base: &Path,
+    defaults: &serde_norway::Mapping,
+    target: &Value,
+    link: &Value,
+) {
+    let Some(target) = target.as_str() else {
+        import.skip(format!("link: target {target:?} is not a path"));
+        return;
+    };
+
+    let mut options = defaults.clone();
+    let path = match link {
+        Value::Null => None,
+        Value::String(path) => Some(path.clone()),
+        Value::Mapping(mapping) => {
+            options.extend(mapping.clone());
+            mapping
+                .get("path")
+                .and_then(Value::as_str)
+                .map(String::from)
+        }
+        _ => {
+            import.skip(format!("link {target}: unexpected value"));
+            return;
+        }
+    };
+
+    let unsupported: Vec<_> = DOTBOT_UNSUPPORTED
+        .iter()
+        .filter(|option| {
+            options
+                .get(**option)
+                .is_some_and(|value| !matches!(value, Value::Null | Value::Bool(false)))
+        })
+        .collect();
+    if !unsupported.is_empty() {
+        let options: Vec<_> = unsupported
+            .iter()
+            .map(|option| option.to_string())
+            .collect();
+        import.skip(format!(
+            "link {target}: unsupported option {}",
+            options.join(", ")
+        ));
+        return;
+    }
+
+    // Without a path the source is the name of the target without its leading dot
+    let path = path.unwrap_or_else(|| {
+        let name = Path::new(target)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        name.trim_start_matches('.').to_string()
+    });
+
+    import.add(
+        normalize(&base.join(path)),
+        target.trim_end_matches('/').to_string(),
+        BTreeSet::new(),
+        Modes::default(),
+    );
+}
+
+/// Translates the plain files of a chezmoi source directory, decoding the attributes in their
+/// names such as `dot_` and `private_` into targets below `~` and modes. Templates, scripts,
+/// encrypted files and other special entries are reported as untranslated.
+pub fn chezmoi(fs: &dyn Fs, dir: &Path) -> Result<Import> {
+    let mut import = Import::default();
+    let files = fs
+        .walk(dir)
+        .map_err(|err| Error::io("Failed to read chezmoi source directory", dir, err))?;
+
+    // Private directories only have their mode enforced for the files directly inside them
+    let mut private_dirs = BTreeSet::new();
+    let mut enforced_dirs = BTreeSet::new();
+
+    'files: for file in files {
+        let rel = file.strip_prefix(dir).unwrap_or(&file);
+        let components: Vec<_> = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let Some((name, dirs)) = components.split_last() else {
+            continue;
+        };
+
+        if let Some(special) = components.iter().find(|c| c.starts_with(".chezmoi")) {
+            if !import.untranslated.iter().any(|s| s.starts_with(special)) {
+                import.skip(format!(
+                    "{special}: chezmoi configuration is not translated"
+                ));
+            }
+            continue;
+        }
+        // Like chezmoi, other entries starting with a dot are ignored
+        if components.iter().any(|c| c.starts_with('.')) {
+            continue;
+        }
+
+        let mut target = String::from("~");
+        let mut private_parent = false;
+        for (depth, component) in dirs.iter().enumerate() {
+            let attributes = ChezmoiName::parse(component, true);
+            if let Some(attribute) = attributes.unsupported {
+                let dir_path = components[..=depth].join("/");
+                match attribute {
+                    "exact_" => {
+                        if !import.untranslated.iter().any(|s| s.starts_with(&dir_path)) {
+                            import.skip(format!(
+                                "{dir_path}: exact_ directories are linked without removing \
+                                 other files"
+                            ));
+                        }
+                    }
+                    _ => {
+                        import.skip(format!("{}: {attribute} is not supported", rel.display()));
+                        continue 'files;
+                    }
+                }
+            }
+            target.push('/');
+            target.push_str(&attributes.name);
+
+            private_parent = attributes.private;
+            if attributes.private {
+                private_dirs.insert(target.clone());
+            }
+        }
+
+        let attributes = ChezmoiName::parse(name, false);
+        if let Some(attribute) = attributes.unsupported {
+            import.skip(format!("{}: {attribute} is not supported", rel.display()));
+            continue;
+        }
+        let parent = target.clone();
+        target.push('/');
+        target.push_str(&attributes.name);
+
+        let modes = Modes {
+            file: attributes.mode(),
+            dir: private_parent.then_some(0o700),
+        };
+        if private_parent {
+            enforced_dirs.insert(parent);
+        }
+        import.add(file.clone(), target, BTreeSet::new(), modes);
+    }
+
+    for dir in private_dirs.difference(&enforced_dirs) {
+        import.skip(format!(
+            "{dir}: the private mode is only enforced for directories directly holding files"
+        ));
+    }
+
+    Ok(import)
+}
+
+/// The target name and attributes decoded from the name of a chezmoi source entry.
+#[derive(Debug, Default)]
+struct ChezmoiName {
+    name: String,
+    private: bool,
+    readonly: bool,
+    executable: bool,
+    /// The first attribute homage has no equivalent for.
+    unsupported: Option<&'static str>,
+}
+
+impl ChezmoiName {
+    fn parse(name: &str, dir: bool) -> ChezmoiName {
+        const DIR_UNSUPPORTED: [&str; 3] = ["exact_", "external_", "remove_"];
+        const FILE_UNSUPPORTED: [&str; 10] = [
+            "after_",
+            "before_",
+            "create_",
+            "encrypted_",
+            "modify_",
+            "once_",
+            "onchange_",
+            "remove_",
+            "run_",
+            "symlink_",
+        ];
+        let unsupported: &[&'static str] = if dir {
+            &DIR_UNSUPPORTED
+        } else {
+            &FILE_UNSUPPORTED
+        };
+
+        let mut parsed = ChezmoiName::default();
+        let mut rest = name;
+        loop {
+            if let Some(attribute) = unsupported.iter().find(|a| rest.starts_with(**a)) {
+                parsed.unsupported.get_or_insert(attribute);
+                rest = &rest[attribute.len()..];
+            } else if let Some(stripped) = rest.strip_prefix("private_") {
+                parsed.private = true;
+                rest = stripped;
+            } else if let Some(stripped) = rest.strip_prefix("readonly_") {
+                parsed.readonly = true;
+                rest = stripped;
+            } else if let Some(stripped) = rest.strip_prefix("executable_").filter(|_| !dir) {
+                parsed.executable = true;
+                rest = stripped;
+            } else if let Some(stripped) = rest.strip_prefix("empty_").filter(|_| !dir) {
+                rest = stripped;
+            } else if let Some(stripped) = rest.strip_prefix("literal_") {
+                parsed.name = stripped.to_string();
+                return parsed;
+            } else {
+                break;
+            }
+        }
+
+        // A .literal suffix keeps the suffixes before it, such as .tmpl
+        if let Some(stripped) = rest.strip_suffix(".literal") {
+            rest = stripped;
+        } else if !dir && rest.ends_with(".tmpl") {
+            parsed
+                .unsupported
+                .get_or_insert("the .tmpl template suffix");
+        }
+        parsed.name = match rest.strip_prefix("dot_") {
+            Some(rest) => format!(".{rest}"),
+            None => rest.to_string(),
+        };
+        parsed
+    }
+
+    // The mode of a file with attributes, unset for a plain file.
+    fn mode(&self) -> Option<u32> {
+        if !self.private && !self.readonly && !self.executable {
+            return None;
+        }
+
+        let mut mode = if self.executable { 0o755 } else { 0o644 };
+        if self.private {
+            mode &= 0o700;
+        }
+        if self.readonly {
+            mode &= !0o222;
+        }
+        Some(mode)
+    }
+}
+
+// Resolves `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+// Quotes a string for a TOML document.
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+    use crate::manifest::Manifest;
+    use crate::utils::Layout;
+
+    // Writes the manifest of an import into `dir` and loads it.
+    fn load(fs: &MemFs, import: &Import, dir: &str) -> Manifest {
+        let dir = Path::new(dir);
+        let path = fs.add_file(dir.join("manifest.toml"), &import.to_manifest(dir).unwrap());
+        let layout = Layout::new(None, Some(PathBuf::from("/home/user")));
+        Manifest::load(fs, &path, &layout).unwrap()
+    }
+
+    fn target<'a>(manifest: &'a Manifest, source: &str) -> &'a Path {
+        &manifest.entries[Path::new(source)].target
+    }
+
+    #[test]
+    fn stow_packages_are_imported() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/vim/.vimrc", "vim");
+        fs.add_file("/dotfiles/vim/README.md", "docs");
+        fs.add_file("/dotfiles/bash/dot-bashrc", "bash");
+        fs.add_file("/dotfiles/nvim/.config/nvim/init.lua", "nvim");
+        fs.add_file("/dotfiles/nvim/.config/nvim/init.lua~", "backup");
+        fs.add_file("/dotfiles/nvim/.stow-local-ignore", "\\.md");
+        fs.add_file("/dotfiles/.git/config", "git");
+
+        let import = stow(&fs, Path::new("/dotfiles"), "~").unwrap();
+        assert_eq!(import.entries.len(), 3);
+        assert_eq!(import.untranslated.len(), 1);
+
+        let manifest = load(&fs, &import, "/dotfiles");
+        assert_eq!(
+            target(&manifest, "/dotfiles/vim/.vimrc"),
+            Path::new("/home/user/.vimrc")
+        );
+        assert_eq!(
+            target(&manifest, "/dotfiles/bash/dot-bashrc"),
+            Path::new("/home/user/.bashrc")
+        );
+        let entry = &manifest.entries[Path::new("/dotfiles/nvim/.config/nvim/init.lua")];
+        assert_eq!(entry.target, Path::new("/home/user/.config/nvim/init.lua"));
+        assert!(entry.tags.contains("nvim"));
+    }
+
+    #[test]
+    fn dotbot_links_are_imported() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/vimrc", "vim");
+        fs.add_file("/dotfiles/zshrc", "zsh");
+        fs.add_file("/dotfiles/config/nvim/init.lua", "nvim");
+        let config = fs.add_file(
+            "/dotfiles/install.conf.yaml",
+            "- defaults:\n    link:\n      relink: true\n\
+             - clean: ['~']\n\
+             - link:\n    ~/.vimrc:\n    ~/.zsh/zshrc: ./zshrc\n    ~/.config/nvim/:\n      \
+               path: config/nvim\n      create: true\n    ~/.bin/:\n      glob: true\n      \
+               path: bin/*\n\
+             - shell:\n  - [git submodule update --init, Installing submodules]\n",
+        );
+
+        let import = dotbot(&fs, &config).unwrap();
+        assert_eq!(import.entries.len(), 3);
+        assert_eq!(import.untranslated.len(), 3);
+        assert!(import.untranslated.iter().any(|s| s.contains("glob")));
+
+        let manifest = load(&fs, &import, "/dotfiles");
+        assert_eq!(
+            target(&manifest, "/dotfiles/vimrc"),
+            Path::new("/home/user/.vimrc")
+        );
+        assert_eq!(
+            target(&manifest, "/dotfiles/zshrc"),
+            Path::new("/home/user/.zsh/zshrc")
+        );
+        assert_eq!(
+            target(&manifest, "/dotfiles/config/nvim/init.lua"),
+            Path::new("/home/user/.config/nvim/init.lua")
+        );
+    }
+
+    #[test]
+    fn chezmoi_plain_files_are_imported() {
+        let fs = MemFs::new();
+        fs.add_file("/chezmoi/dot_zshrc", "zsh");
+        fs.add_file("/chezmoi/private_dot_ssh/private_config", "ssh");
+        fs.add_file("/chezmoi/dot_local/bin/executable_backup", "#!/bin/sh");
+        fs.add_file("/chezmoi/dot_gitconfig.tmpl", "{{ .email }}");
+        fs.add_file("/chezmoi/run_once_install.sh", "#!/bin/sh");
+        fs.add_file("/chezmoi/.chezmoiignore", "README.md");
+        fs.add_file("/chezmoi/.git/config", "git");
+
+        let import = chezmoi(&fs, Path::new("/chezmoi")).unwrap();
+        assert_eq!(import.entries.len(), 3);
+        assert_eq!(import.untranslated.len(), 3);
+
+        let manifest = load(&fs, &import, "/chezmoi");
+        assert_eq!(
+            target(&manifest, "/chezmoi/dot_zshrc"),
+            Path::new("/home/user/.zshrc")
+        );
+        let ssh = &manifest.entries[Path::new("/chezmoi/private_dot_ssh/private_config")];
+        assert_eq!(ssh.target, Path::new("/home/user/.ssh/config"));
+        assert_eq!(ssh.modes.file, Some(0o600));
+        assert_eq!(ssh.modes.dir, Some(0o700));
+        let backup = &manifest.entries[Path::new("/chezmoi/dot_local/bin/executable_backup")];
+        assert_eq!(backup.target, Path::new("/home/user/.local/bin/backup"));
+        assert_eq!(backup.modes.file, Some(0o755));
+    }
+
+    #[test]
+    fn chezmoi_attributes_are_decoded() {
+        let name = ChezmoiName::parse("private_readonly_dot_netrc", false);
+        assert_eq!(name.name, ".netrc");
+        assert_eq!(name.mode(), Some(0o400));
+
+        let name = ChezmoiName::parse("literal_dot_file", false);
+        assert_eq!(name.name, "dot_file");
+
+        let name = ChezmoiName::parse("encrypted_private_dot_pgpass.age", false);
+        assert_eq!(name.unsupported, Some("encrypted_"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::io::stdin;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{info, warn};
+
+use crate::prompt::confirm;
+use homage::format::{fmt_error, fmt_file, fmt_number};
+use homage::import::{self, Import};
+use homage::{Layout, Manifest, OsFs};
+
+/// Imports a GNU Stow directory, linking the packages into `target` or the parent of `dir`.
+pub fn stow(dir: &Path, target: Option<String>) -> anyhow::Result<Import> {
+    let dir = canonicalize(dir)?;
+    let target = target.unwrap_or_else(|| home_relative(dir.parent().unwrap_or(Path::new("/"))));
+    Ok(import::stow(&OsFs, &dir, &target)?)
+}
+
+/// Imports the links of a Dotbot config.
+pub fn dotbot(config: &Path) -> anyhow::Result<Import> {
+    Ok(import::dotbot(&OsFs, &canonicalize(config)?)?)
+}
+
+/// Imports the plain files of a chezmoi source directory.
+pub fn chezmoi(dir: &Path) -> anyhow::Result<Import> {
+    Ok(import::chezmoi(&OsFs, &canonicalize(dir)?)?)
+}
+
+/// Writes the manifest of an import to `output`, which defaults to a 'manifest.toml' in `dir`,
+/// and reports what could not be translated. The manifest is loaded before it replaces `output`,
+/// so an existing file is never replaced by an invalid manifest. A dry run prints it instead.
+pub fn write_manifest(
+    import: &Import,
+    dir: &Path,
+    output: Option<PathBuf>,
+    layout: &Layout,
+    dry_run: bool,
+    skip_confirmation: bool,
+) -> anyhow::Result<()> {
+    let output = std::path::absolute(output.unwrap_or_else(|| dir.join("manifest.toml")))?;
+    let manifest_dir = output.parent().unwrap_or(Path::new("/"));
+    let manifest = import.to_manifest(&canonicalize(manifest_dir)?)?;
+
+    for message in &import.untranslated {
+        warn!("Not translated: {}", message);
+    }
+
+    if dry_run {
+        print!("{}", manifest);
+        return Ok(());
+    }
+
+    if output.exists() && !skip_confirmation {
+        println!(
+            "{} already exists, do you want to replace it? (y/n)",
+            fmt_error(&output)
+        );
+        if !confirm(&mut stdin().lock()) {
+            return Ok(());
+        }
+    }
+
+    // The manifest is loaded next to the output, where its sources resolve, and only replaces
+    // the output once it is valid
+    let mut temp = output.as_os_str().to_os_string();
+    temp.push(".homage-import");
+    let temp = PathBuf::from(temp);
+    let replace = || {
+        fs::write(&temp, &manifest)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        Manifest::load(&OsFs, &temp, layout).context("The imported manifest does not load")?;
+        fs::rename(&temp, &output).with_context(|| format!("Failed to write {}", output.display()))
+    };
+    if let Err(err) = replace() {
+        let _ = fs::remove_file(&temp);
+        return Err(err);
+    }
+
+    info!(
+        "Imported {} file(s) into {}",
+        fmt_number(import.entries.len()),
+        fmt_file(&output)
+    );
+    if !import.untranslated.is_empty() {
+        warn!(
+            "{} construct(s) could not be translated, see the warnings above",
+            fmt_number(import.untranslated.len())
+        );
+    }
+    Ok(())
+}
+
+fn canonicalize(path: &Path) -> anyhow::Result<PathBuf> {
+    path.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))
+}
+
+// Writes a directory below the home directory with a leading '~', like manifests do.
+fn home_relative(dir: &Path) -> String {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    match home.as_deref().and_then(|home| dir.strip_prefix(home).ok()) {
+        Some(rel) if rel.as_os_str().is_empty() => "~".to_string(),
+        Some(rel) => format!("~/{}", rel.display()),
+        None => dir.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{test_dir, write_file};
+
+    #[test]
+    fn imported_manifest_is_written_next_to_the_sources() {
+        let dir = test_dir("import_stow");
+        write_file(&dir, "stow/vim/.vimrc", "vim");
+        write_file(&dir, "stow/vim/LICENSE.md", "license");
+
+        let import = stow(&dir.join("stow"), Some("~".to_string())).unwrap();
+        write_manifest(
+            &import,
+            &dir.join("stow"),
+            None,
+            &Layout::default(),
+            false,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("stow/manifest.toml")).unwrap(),
+            "[files]\n\"vim/.vimrc\" = { target = \"~/.vimrc\", tags = [\"vim\"] }\n"
+        );
+    }
+
+    #[test]
+    fn invalid_manifests_replace_nothing() {
+        let dir = test_dir("import_invalid");
+        let output = write_file(&dir, "manifest.toml", "[files]\n");
+
+        // The source is gone by the time the manifest is loaded
+        let mut import = Import::default();
+        import.entries.insert(
+            dir.join("gone"),
+            import::Imported {
+                source: dir.join("gone"),
+                target: "~/.gone".to_string(),
+                tags: Default::default(),
+                modes: Default::default(),
+            },
+        );
+        let result = write_manifest(
+            &import,
+            &dir,
+            Some(output.clone()),
+            &Layout::default(),
+            false,
+            true,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "[files]\n");
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn stow_targets_below_home_keep_the_tilde() {
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        assert_eq!(home_relative(&home), "~");
+        assert_eq!(home_relative(&home.join("src")), "~/src");
+        assert_eq!(home_relative(Path::new("/opt")), "/opt");
+    }
+}
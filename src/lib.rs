@@ -0,0 +1,38 @@
+//! Simple and effective dotfiles manager for your home.
+//!
+//! Dotfiles are declared in a [`Manifest`], which maps source files to target paths. Installing
+//! a manifest is done by computing a [`Plan`] against the [`Cache`] of previously installed
+//! symlinks and executing it, which returns a [`Report`] of the changes made.
+//!
+//! All filesystem access goes through the [`Fs`] trait, use [`OsFs`] for the real filesystem
+//! or [`MemFs`] to run against an in-memory filesystem in tests.
+
+pub mod bundle;
+pub mod cache;
+pub mod check;
+pub mod diff;
+pub mod error;
+pub mod export;
+pub mod format;
+pub mod fs;
+pub mod import;
+pub mod manifest;
+pub mod plan;
+pub mod relocate;
+pub mod script;
+pub mod secret;
+pub mod selection;
+pub mod symlink;
+pub mod utils;
+
+#[cfg(test)]
+mod tests;
+
+pub use cache::Cache;
+pub use error::{Error, Result};
+pub use fs::{EscalatedFs, Fs, MemFs, OsFs};
+pub use manifest::{Entry, Manifest};
+pub use plan::{Operation, Plan, Report};
+pub use selection::Selection;
+pub use symlink::Symlink;
+pub use utils::Layout;
@@ -1,37 +1,344 @@
-use log::warn;
+use anyhow::anyhow;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+use homage::{Selection, check::check, fs::OsFs, utils::config_path};
 
 use crate::{
     action::Action,
-    args::{ActionType, Args},
-    utils::cache_path,
+    args::{ActionType, Args, ConfigAction, ImportAction, SecretAction, StateAction},
+    completions::{handle_completion_request, write_completions, write_man_pages},
+    config::Config,
 };
 
 mod action;
 mod args;
-mod cache;
-mod format;
-mod manifest;
-mod symlink;
+mod completions;
+mod config;
+mod import_cli;
+mod prompt;
+mod secret_cli;
+#[cfg(test)]
 mod tests;
-mod utils;
 
 fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse_args();
+    handle_completion_request();
+
+    let mut args = Args::parse_args();
+    // Without a home directory there is no config to read
+    let config = match config_path() {
+        Ok(path) => Config::load(&path)?,
+        Err(_) => Config::default(),
+    };
+    args.merge(&config);
+    args.init_color();
     args.init_logger();
 
+    run(args, &config)
+}
+
+// Runs the action of the parsed arguments. The state is only located, and moved from where
+// earlier versions kept it, by the actions reading or writing it.
+fn run(args: Args, config: &Config) -> anyhow::Result<()> {
     if args.dry_run {
         warn!("Running in dry-run mode");
     }
 
     let skip_confirmation = args.dry_run || args.no_confirm;
-    let cache_file = cache_path()?;
+    let layout = args.layout()?;
+    let escalate = args.escalation_command();
+    let profile = || args.profile_tags(config);
 
-    match args.action {
-        ActionType::Install { manifest } => {
-            Action::new(manifest, cache_file, args.dry_run, skip_confirmation).install()
+    match args.action.clone() {
+        ActionType::Install {
+            manifest,
+            bundle,
+            selection,
+            interactive,
+            replace_identical,
+        } => {
+            let manifests = match bundle {
+                Some(bundle) => match action::unpack_bundle(&OsFs, &bundle, &layout, args.dry_run)?
+                {
+                    Some(manifest) => vec![manifest],
+                    None => return Ok(()),
+                },
+                None => args.manifests(manifest, config)?,
+            };
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .interactive(interactive)
+            .replace_identical(replace_identical)
+            .backup(args.backup.unwrap_or_default())
+            .identity(args.identity.clone())
+            .escalate(&escalate)
+            .install()
+        }
+        ActionType::Diff {
+            manifest,
+            selection,
+            content,
+            replace_identical,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .replace_identical(replace_identical)
+            .escalate(&escalate)
+            .diff(content)
+        }
+        ActionType::Plan {
+            manifest,
+            selection,
+            script,
+            output,
+            replace_identical,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            let selection = selection.selection(&layout, &profile()?)?;
+            let action = Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .replace_identical(replace_identical)
+            .escalate(&escalate);
+            match script {
+                true => action.script(output.as_deref()),
+                false => action.diff(false),
+            }
+        }
+        ActionType::Uninstall {
+            manifest: _,
+            selection,
+            all: true,
+        } => {
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                vec![],
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .escalate(&escalate)
+            .uninstall_all()
         }
-        ActionType::Uninstall { manifest } => {
-            Action::new(manifest, cache_file, args.dry_run, skip_confirmation).uninstall()
+        ActionType::Uninstall {
+            manifest,
+            selection,
+            all: false,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .escalate(&escalate)
+            .uninstall()
         }
+        ActionType::Eject {
+            manifest,
+            selection,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .escalate(&escalate)
+            .eject()
+        }
+        ActionType::Status {
+            manifest,
+            selection,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            let selection = selection.selection(&layout, &profile()?)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                selection,
+                args.dry_run,
+                skip_confirmation,
+            )
+            .output_format(args.output_format.unwrap_or_default())
+            .status()
+        }
+        ActionType::Bundle { manifest, output } => {
+            let manifests = args.manifests(manifest, config)?;
+            Action::new(
+                manifests,
+                // Bundles and exports never read the state
+                PathBuf::new(),
+                layout,
+                Selection::default(),
+                args.dry_run,
+                skip_confirmation,
+            )
+            .bundle(&output)
+        }
+        ActionType::Export {
+            manifest,
+            format,
+            output,
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            Action::new(
+                manifests,
+                // Bundles and exports never read the state
+                PathBuf::new(),
+                layout,
+                Selection::default(),
+                args.dry_run,
+                skip_confirmation,
+            )
+            .export(format, output.as_deref())
+        }
+        ActionType::Relocate { from, to } => Action::new(
+            vec![],
+            action::state_file(&layout, args.dry_run)?,
+            layout,
+            Selection::default(),
+            args.dry_run,
+            skip_confirmation,
+        )
+        .escalate(&escalate)
+        .relocate(&from, &to),
+        ActionType::State {
+            action: StateAction::Rebuild { manifest },
+        } => {
+            let manifests = args.manifests(manifest, config)?;
+            Action::new(
+                manifests,
+                action::state_file(&layout, args.dry_run)?,
+                layout,
+                Selection::default(),
+                args.dry_run,
+                skip_confirmation,
+            )
+            .escalate(&escalate)
+            .rebuild_state()
+        }
+        ActionType::Import { action } => {
+            let (import, dir, output) = match action {
+                ImportAction::Stow {
+                    dir,
+                    target,
+                    output,
+                } => (import_cli::stow(&dir, target)?, dir, output),
+                ImportAction::Dotbot { config, output } => {
+                    let dir = config.parent().unwrap_or(Path::new(".")).to_path_buf();
+                    (import_cli::dotbot(&config)?, dir, output)
+                }
+                ImportAction::Chezmoi { dir, output } => (import_cli::chezmoi(&dir)?, dir, output),
+            };
+            import_cli::write_manifest(
+                &import,
+                &dir,
+                output,
+                &layout,
+                args.dry_run,
+                skip_confirmation,
+            )
+        }
+        ActionType::Secret {
+            action: SecretAction::Encrypt { file, output },
+        } => secret_cli::encrypt(&file, output, args.identity.as_deref()),
+        ActionType::Secret {
+            action: SecretAction::Edit { file },
+        } => secret_cli::edit(&file, args.identity.as_deref()),
+        ActionType::Check { manifest } => {
+            let manifests = args.manifests(manifest, config)?;
+            let diagnostics: Vec<_> = manifests
+                .iter()
+                .flat_map(|manifest| check(&OsFs, manifest, &layout))
+                .collect();
+
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+
+            if !diagnostics.is_empty() {
+                return Err(anyhow!(
+                    "Found {} problem(s) in manifest",
+                    diagnostics.len()
+                ));
+            }
+
+            info!("No problems found");
+            Ok(())
+        }
+        ActionType::Completions { shell } => write_completions(shell, &mut std::io::stdout()),
+        ActionType::Man { out_dir } => write_man_pages(&out_dir),
+        ActionType::Config {
+            action: ConfigAction::Show,
+        } => {
+            let manifests = args.manifests(None, config).unwrap_or_default();
+            println!("# {}", config_path()?.display());
+            print!(
+                "{}",
+                toml::to_string_pretty(&args.effective_config(manifests, config))?
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod run_tests {
+    use super::*;
+
+    use clap::Parser;
+
+    use crate::tests::{test_dir, write_file};
+
+    #[test]
+    fn completions_and_man_leave_the_legacy_cache() {
+        let dir = test_dir("legacy_cache_untouched");
+        let legacy = write_file(&dir, "home/.cache/homage/cache.toml", "[files]\n");
+        let home = dir.join("home");
+        let man = dir.join("man");
+
+        for command in [
+            vec!["completions", "bash"],
+            vec!["man", man.to_str().unwrap()],
+        ] {
+            let mut words = vec!["homage", "--home", home.to_str().unwrap()];
+            words.extend(command);
+            run(Args::try_parse_from(words).unwrap(), &Config::default()).unwrap();
+        }
+
+        assert!(legacy.exists());
+        assert!(!home.join(".local/state/homage/state.toml").exists());
     }
 }
@@ -1,136 +1,304 @@
-use anyhow::{Context, anyhow};
 use log::{debug, trace};
 use serde::Deserialize;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
 
+use crate::error::{DuplicateTarget, Error, Result};
 use crate::format::{fmt_file, fmt_link};
-use crate::symlink::Symlink;
-use crate::utils::expand_tilde;
+use crate::fs::Fs;
+use crate::selection::Selection;
+use crate::symlink::{Modes, Symlink};
+use crate::utils::{Layout, parse_mode};
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ManifestFile {
     #[serde(default)]
     includes: Vec<String>,
     #[serde(default)]
-    files: BTreeMap<String, String>,
+    files: BTreeMap<String, FileEntry>,
+    #[serde(default)]
+    system: BTreeMap<String, FileEntry>,
+}
+
+/// A `[files]` or `[system]` value, either a plain target path or a table with additional settings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileEntry {
+    Target(String),
+    Table(FileTable),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTable {
+    target: String,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    #[serde(default)]
+    encrypted: bool,
+    mode: Option<String>,
+    dir_mode: Option<String>,
+    link: Option<LinkKind>,
+}
+
+/// How a file entry is linked to its target.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LinkKind {
+    Symlink,
+    Hardlink,
+}
+
+impl FileEntry {
+    fn target(&self) -> &str {
+        match self {
+            FileEntry::Target(target) => target,
+            FileEntry::Table(table) => &table.target,
+        }
+    }
+
+    fn tags(&self) -> BTreeSet<String> {
+        match self {
+            FileEntry::Target(_) => BTreeSet::new(),
+            FileEntry::Table(table) => table.tags.clone(),
+        }
+    }
+
+    fn encrypted(&self) -> bool {
+        match self {
+            FileEntry::Target(_) => false,
+            FileEntry::Table(table) => table.encrypted,
+        }
+    }
+
+    fn hardlink(&self) -> bool {
+        match self {
+            FileEntry::Target(_) => false,
+            FileEntry::Table(table) => table.link == Some(LinkKind::Hardlink),
+        }
+    }
+
+    fn modes(&self) -> Result<Modes> {
+        let FileEntry::Table(table) = self else {
+            return Ok(Modes::default());
+        };
+
+        let parse = |mode: &Option<String>| {
+            mode.as_deref()
+                .map(|mode| parse_mode(mode).ok_or_else(|| Error::InvalidMode(mode.to_string())))
+                .transpose()
+        };
+
+        Ok(Modes {
+            file: parse(&table.mode)?,
+            dir: parse(&table.dir_mode)?,
+        })
+    }
+}
+
+/// A resolved manifest entry for a single source file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub target: PathBuf,
+    pub tags: BTreeSet<String>,
+    /// The source is age encrypted and gets decrypted into a private file at the target.
+    pub encrypted: bool,
+    /// The target is a hard link to the source rather than a symlink, unless it is encrypted.
+    pub hardlink: bool,
+    /// Permission modes enforced on install.
+    pub modes: Modes,
+    /// The entry is declared in `[system]` and installed through the escalation command.
+    pub system: bool,
 }
 
 /// A fully resolved manifest containing absolute source -> target path mappings.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Manifest {
-    pub entries: BTreeMap<PathBuf, PathBuf>,
+    pub entries: BTreeMap<PathBuf, Entry>,
 }
 
 impl Manifest {
     /// Load a manifest file and recursively resolve all includes.
-    /// Detects circular includes and returns an error if found.
+    /// Detects circular includes and returns an error if found. A manifest included from several
+    /// others is only loaded once.
     /// Detects conflicting target paths and returns an error if found.
-    pub fn load(path: &Path) -> anyhow::Result<Manifest> {
+    pub fn load(fs: &dyn Fs, path: &Path, layout: &Layout) -> Result<Manifest> {
         let mut visited = HashSet::new();
         let mut entries = BTreeMap::new();
 
-        Self::load_recursive(path, &mut visited, &mut entries)?;
+        Self::load_recursive(
+            fs,
+            path,
+            layout,
+            &mut Vec::new(),
+            &mut visited,
+            &mut entries,
+        )?;
+        Self::validate_no_duplicate_targets(&entries)?;
+
+        Ok(Manifest { entries })
+    }
+
+    /// Load several manifest files and merge them into a single manifest.
+    /// A source listed by more than one of the manifests is an error, and conflicting target
+    /// paths across the manifests are detected as well.
+    pub fn load_all(fs: &dyn Fs, paths: &[PathBuf], layout: &Layout) -> Result<Manifest> {
+        let mut entries = BTreeMap::new();
+        let mut origins: BTreeMap<PathBuf, &PathBuf> = BTreeMap::new();
+
+        for path in paths {
+            for (source, entry) in Self::load(fs, path, layout)?.entries {
+                if let Some(first) = origins.insert(source.clone(), path) {
+                    return Err(Error::DuplicateSource {
+                        path: source,
+                        first: first.clone(),
+                        second: path.clone(),
+                    });
+                }
+                entries.insert(source, entry);
+            }
+        }
         Self::validate_no_duplicate_targets(&entries)?;
 
         Ok(Manifest { entries })
     }
 
     fn load_recursive(
+        fs: &dyn Fs,
         path: &Path,
+        layout: &Layout,
+        stack: &mut Vec<PathBuf>,
         visited: &mut HashSet<PathBuf>,
-        entries: &mut BTreeMap<PathBuf, PathBuf>,
-    ) -> anyhow::Result<()> {
-        let canonical = path
-            .canonicalize()
-            .with_context(|| format!("Failed to resolve manifest path: {}", path.display()))?;
-
+        entries: &mut BTreeMap<PathBuf, Entry>,
+    ) -> Result<()> {
+        let canonical = fs
+            .canonicalize(path)
+            .map_err(|err| Error::io("Failed to resolve manifest path", path, err))?;
+
+        // Only a manifest including itself through the current chain of includes is a cycle
+        if stack.contains(&canonical) {
+            return Err(Error::CircularInclude(canonical));
+        }
         if !visited.insert(canonical.clone()) {
-            return Err(anyhow!(
-                "Circular include detected: {}",
-                canonical.display()
-            ));
+            return Ok(());
         }
 
-        let content = std::fs::read_to_string(&canonical)
-            .with_context(|| format!("Failed to read manifest: {}", canonical.display()))?;
+        let content = fs
+            .read_to_string(&canonical)
+            .map_err(|err| Error::io("Failed to read manifest", &canonical, err))?;
 
-        let manifest: ManifestFile = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse manifest: {}", canonical.display()))?;
+        let manifest: ManifestFile = toml::from_str(&content).map_err(|source| Error::Parse {
+            path: canonical.clone(),
+            source,
+        })?;
 
-        let manifest_dir = canonical
-            .parent()
-            .ok_or_else(|| anyhow!("Manifest has no parent directory: {}", canonical.display()))?;
+        let manifest_dir = canonical.parent().unwrap_or(Path::new("/"));
 
         // Process includes first
+        stack.push(canonical.clone());
         for include in &manifest.includes {
             let include_path = manifest_dir.join(include);
             debug!("Processing include: {}", fmt_file(&include_path));
-            Self::load_recursive(&include_path, visited, entries)?;
+            Self::load_recursive(fs, &include_path, layout, stack, visited, entries)?;
         }
+        stack.pop();
 
-        // Process file entries
-        for (source, target) in &manifest.files {
-            Self::resolve_entry(manifest_dir, source, target, entries)?;
+        // Process file entries, followed by the privileged system entries
+        for (source, entry) in &manifest.files {
+            Self::resolve_entry(fs, manifest_dir, source, entry, false, layout, entries)?;
+        }
+        for (source, entry) in &manifest.system {
+            Self::resolve_entry(fs, manifest_dir, source, entry, true, layout, entries)?;
         }
 
         Ok(())
     }
 
     fn resolve_entry(
+        fs: &dyn Fs,
         manifest_dir: &Path,
         source: &str,
-        target: &str,
-        entries: &mut BTreeMap<PathBuf, PathBuf>,
-    ) -> anyhow::Result<()> {
+        entry: &FileEntry,
+        system: bool,
+        layout: &Layout,
+        entries: &mut BTreeMap<PathBuf, Entry>,
+    ) -> Result<()> {
         let abs_source = manifest_dir.join(source);
-        let abs_source = abs_source
-            .canonicalize()
-            .with_context(|| format!("Failed to resolve source path: {}", abs_source.display()))?;
-
-        let abs_target = expand_tilde(target)?;
-
-        if abs_source.is_dir() {
-            for entry in WalkDir::new(&abs_source)
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|e| e.file_type().is_file())
-            {
-                let rel = entry.path().strip_prefix(&abs_source).unwrap();
+        let abs_source = fs
+            .canonicalize(&abs_source)
+            .map_err(|err| Error::io("Failed to resolve source path", &abs_source, err))?;
+
+        let abs_target = layout.target(entry.target())?;
+        let tags = entry.tags();
+        let modes = entry.modes()?;
+
+        for (file_source, file_target) in Self::expand(fs, &abs_source, abs_target)? {
+            entries.insert(
+                file_source,
+                Entry {
+                    target: file_target,
+                    tags: tags.clone(),
+                    encrypted: entry.encrypted(),
+                    hardlink: entry.hardlink(),
+                    modes,
+                    system,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Expand a canonical source path into the source -> target mappings of the files it contains.
+    /// Directories are traversed recursively, a single file maps to the target as is.
+    pub(crate) fn expand(
+        fs: &dyn Fs,
+        abs_source: &Path,
+        abs_target: PathBuf,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut files = Vec::new();
+
+        if fs.is_dir(abs_source) {
+            let walked = fs
+                .walk(abs_source)
+                .map_err(|err| Error::io("Failed to read source directory", abs_source, err))?;
+            for path in walked {
+                let rel = path.strip_prefix(abs_source).unwrap();
                 let file_target = abs_target.join(rel);
                 trace!(
                     "Resolved directory entry: {} -> {}",
-                    fmt_file(entry.path()),
+                    fmt_file(&path),
                     fmt_link(&file_target)
                 );
-                entries.insert(entry.path().to_path_buf(), file_target);
+                files.push((path, file_target));
             }
-        } else if abs_source.is_file() {
+        } else if fs.is_file(abs_source) {
             // If the target is an existing directory, place the file inside it
-            let final_target = Self::resolve_final_target(&abs_source, abs_target)?;
+            let final_target = Self::resolve_final_target(fs, abs_source, abs_target)?;
 
             trace!(
                 "Resolved file entry: {} -> {}",
-                fmt_file(&abs_source),
+                fmt_file(abs_source),
                 fmt_link(&final_target)
             );
-            entries.insert(abs_source, final_target);
+            files.push((abs_source.to_path_buf(), final_target));
         } else {
-            return Err(anyhow!(
-                "Source path is neither a file nor directory: {}",
-                abs_source.display()
-            ));
+            return Err(Error::InvalidSource(abs_source.to_path_buf()));
         }
 
-        Ok(())
+        Ok(files)
     }
 
-    fn resolve_final_target(abs_source: &Path, abs_target: PathBuf) -> anyhow::Result<PathBuf> {
-        let final_target = if abs_target.is_dir() {
+    fn resolve_final_target(
+        fs: &dyn Fs,
+        abs_source: &Path,
+        abs_target: PathBuf,
+    ) -> Result<PathBuf> {
+        let final_target = if fs.is_dir(&abs_target) {
             let file_name = abs_source
                 .file_name()
-                .ok_or_else(|| anyhow!("Source has no file name: {}", abs_source.display()))?;
+                .ok_or_else(|| Error::InvalidSource(abs_source.to_path_buf()))?;
             abs_target.join(file_name)
         } else {
             abs_target
@@ -140,36 +308,76 @@ impl Manifest {
     }
 
     /// Validate that no two source files map to the same target path.
-    fn validate_no_duplicate_targets(entries: &BTreeMap<PathBuf, PathBuf>) -> anyhow::Result<()> {
+    fn validate_no_duplicate_targets(entries: &BTreeMap<PathBuf, Entry>) -> Result<()> {
         let mut seen: BTreeMap<&PathBuf, &PathBuf> = BTreeMap::new();
-        let mut conflicts: Vec<String> = Vec::new();
+        let mut conflicts: Vec<DuplicateTarget> = Vec::new();
 
-        for (source, target) in entries {
+        for (source, entry) in entries {
+            let target = &entry.target;
             if let Some(prev_source) = seen.insert(target, source) {
-                conflicts.push(format!(
-                    "  {} and {} both target {}",
-                    prev_source.display(),
-                    source.display(),
-                    target.display()
-                ));
+                conflicts.push(DuplicateTarget {
+                    target: target.clone(),
+                    first: prev_source.clone(),
+                    second: source.clone(),
+                });
             }
         }
 
         if conflicts.is_empty() {
             Ok(())
         } else {
-            Err(anyhow!(
-                "Conflicting target paths detected:\n{}",
-                conflicts.join("\n")
-            ))
+            Err(Error::DuplicateTargets(conflicts))
         }
     }
 
+    /// Returns the source -> target path mappings of all entries.
+    pub fn targets(&self) -> BTreeMap<PathBuf, PathBuf> {
+        self.entries
+            .iter()
+            .map(|(src, entry)| (src.clone(), entry.target.clone()))
+            .collect()
+    }
+
+    /// Returns a manifest containing only the entries matching the selection.
+    pub fn select(&self, selection: &Selection) -> Manifest {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|(src, entry)| selection.matches(src, &entry.target, &entry.tags))
+            .map(|(src, entry)| (src.clone(), entry.clone()))
+            .collect();
+
+        Manifest { entries }
+    }
+
+    /// Splits the manifest into the user entries and the privileged `[system]` entries.
+    pub fn partition_system(&self) -> (Manifest, Manifest) {
+        let (system, user) = self
+            .entries
+            .iter()
+            .map(|(src, entry)| (src.clone(), entry.clone()))
+            .partition(|(_, entry)| entry.system);
+
+        (Manifest { entries: user }, Manifest { entries: system })
+    }
+
     /// Convert all entries into a list of symlinks.
     pub fn to_symlinks(&self) -> Vec<Symlink> {
         self.entries
             .iter()
-            .map(|(src, target)| Symlink::new(src.clone(), target.clone()))
+            .map(|(src, entry)| {
+                let symlink = if entry.encrypted {
+                    Symlink::secret(src.clone(), entry.target.clone(), None)
+                } else if entry.hardlink {
+                    Symlink::hardlink(src.clone(), entry.target.clone(), None)
+                } else {
+                    Symlink::new(src.clone(), entry.target.clone())
+                };
+                Symlink {
+                    modes: entry.modes,
+                    ..symlink
+                }
+            })
             .collect()
     }
 }
@@ -178,7 +386,8 @@ impl Manifest {
 mod tests {
     use super::*;
 
-    use crate::tests::tests::{test_dir, write_file};
+    use crate::fs::{MemFs, OsFs};
+    use crate::tests::{test_dir, write_file};
 
     use std::fs;
 
@@ -193,12 +402,12 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let manifest = Manifest::load(&manifest_path).unwrap();
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
         assert_eq!(manifest.entries.len(), 1);
 
         let source = dir.join("dotfile.conf").canonicalize().unwrap();
         let target = dir.join("target/dotfile.conf");
-        assert_eq!(manifest.entries.get(&source).unwrap(), &target);
+        assert_eq!(manifest.entries.get(&source).unwrap().target, target);
     }
 
     #[test]
@@ -219,10 +428,121 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &parent_content);
 
-        let manifest = Manifest::load(&manifest_path).unwrap();
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+    }
+
+    #[test]
+    fn load_all_merges_manifests() {
+        let dir = test_dir("load_all");
+        write_file(&dir, "a.conf", "a");
+        write_file(&dir, "b.conf", "b");
+
+        let first = write_file(
+            &dir,
+            "first.toml",
+            &format!("[files]\n\"a.conf\" = \"{}/target/a.conf\"", dir.display()),
+        );
+        let second = write_file(
+            &dir,
+            "second.toml",
+            &format!("[files]\n\"b.conf\" = \"{}/target/b.conf\"", dir.display()),
+        );
+
+        let manifest = Manifest::load_all(&OsFs, &[first, second], &Layout::default()).unwrap();
         assert_eq!(manifest.entries.len(), 2);
     }
 
+    #[test]
+    fn load_all_rejects_a_source_in_two_manifests() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        let first = fs.add_file(
+            "/dotfiles/first.toml",
+            "[files]\n\"a.conf\" = \"/home/a.conf\"",
+        );
+        let second = fs.add_file(
+            "/dotfiles/second.toml",
+            "[files]\n\"a.conf\" = \"/home/b.conf\"",
+        );
+
+        let err = Manifest::load_all(&fs, &[first.clone(), second.clone()], &Layout::default())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DuplicateSource { path, first: f, second: s }
+                if path == Path::new("/dotfiles/a.conf") && f == first && s == second
+        ));
+    }
+
+    #[test]
+    fn entry_table_with_tags() {
+        let dir = test_dir("tags");
+        write_file(&dir, "configs/a.conf", "a");
+        write_file(&dir, "b.conf", "b");
+
+        let manifest_content = format!(
+            "[files]\n\"configs\" = {{ target = \"{t}/configs\", tags = [\"gui\", \"shell\"] }}\n\"b.conf\" = \"{t}/b.conf\"",
+            t = dir.join("target").display()
+        );
+        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
+
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+
+        let tagged = dir.join("configs/a.conf").canonicalize().unwrap();
+        let entry = manifest.entries.get(&tagged).unwrap();
+        assert_eq!(entry.target, dir.join("target/configs/a.conf"));
+        assert!(entry.tags.contains("gui") && entry.tags.contains("shell"));
+
+        let plain = dir.join("b.conf").canonicalize().unwrap();
+        assert!(manifest.entries.get(&plain).unwrap().tags.is_empty());
+
+        let selection = Selection::new(vec!["gui".to_string()], vec![], vec![]);
+        let selected = manifest.select(&selection);
+        assert_eq!(selected.entries.len(), 1);
+        assert!(selected.entries.contains_key(&tagged));
+    }
+
+    #[test]
+    fn system_entries_are_partitioned() {
+        let dir = test_dir("system_entries");
+        write_file(&dir, "zshrc", "zsh");
+        write_file(&dir, "keyd.conf", "keyd");
+
+        let manifest_content = format!(
+            "[files]\n\"zshrc\" = \"{d}/home/.zshrc\"\n\n[system]\n\"keyd.conf\" = \"{d}/etc/keyd/default.conf\"",
+            d = dir.display()
+        );
+        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
+
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
+        let (user, system) = manifest.partition_system();
+
+        let source = dir.join("keyd.conf").canonicalize().unwrap();
+        assert_eq!(user.entries.len(), 1);
+        assert_eq!(system.entries.len(), 1);
+        assert!(system.entries[&source].system);
+        assert_eq!(
+            system.entries[&source].target,
+            dir.join("etc/keyd/default.conf")
+        );
+    }
+
+    #[test]
+    fn unknown_keys_error() {
+        let dir = test_dir("unknown_keys");
+        write_file(&dir, "a.conf", "a");
+
+        let manifest_content = format!(
+            "include = [\"other.toml\"]\n[files]\n\"a.conf\" = \"{}/a.conf\"",
+            dir.display()
+        );
+        let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
+
+        assert!(Manifest::load(&OsFs, &manifest_path, &Layout::default()).is_err());
+    }
+
     #[test]
     fn circular_include_detected() {
         let dir = test_dir("circular");
@@ -230,11 +550,30 @@ mod tests {
         write_file(&dir, "a.toml", "includes = [\"b.toml\"]\n[files]\n");
         write_file(&dir, "b.toml", "includes = [\"a.toml\"]\n[files]\n");
 
-        let result = Manifest::load(&dir.join("a.toml"));
+        let result = Manifest::load(&OsFs, &dir.join("a.toml"), &Layout::default());
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("Circular include"));
     }
 
+    #[test]
+    fn shared_include_is_not_circular() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        fs.add_file(
+            "/dotfiles/shared.toml",
+            "[files]\n\"a.conf\" = \"/home/a.conf\"",
+        );
+        fs.add_file("/dotfiles/left.toml", "includes = [\"shared.toml\"]");
+        fs.add_file("/dotfiles/right.toml", "includes = [\"shared.toml\"]");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "includes = [\"left.toml\", \"right.toml\"]",
+        );
+
+        let manifest = Manifest::load(&fs, &path, &Layout::default()).unwrap();
+        assert_eq!(manifest.entries.len(), 1);
+    }
+
     #[test]
     fn directory_source_expands_to_files() {
         let dir = test_dir("dir_source");
@@ -248,11 +587,11 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let manifest = Manifest::load(&manifest_path).unwrap();
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
         assert_eq!(manifest.entries.len(), 2);
 
-        for (_src, target) in &manifest.entries {
-            assert!(target.starts_with(dir.join("target/configs")));
+        for entry in manifest.entries.values() {
+            assert!(entry.target.starts_with(dir.join("target/configs")));
         }
     }
 
@@ -263,7 +602,7 @@ mod tests {
         let manifest_content = format!("[files]\n\"nonexistent\" = \"{}/target\"", dir.display());
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let result = Manifest::load(&manifest_path);
+        let result = Manifest::load(&OsFs, &manifest_path, &Layout::default());
         assert!(result.is_err());
     }
 
@@ -277,7 +616,7 @@ mod tests {
             "includes = [\"nonexistent.toml\"]\n[files]\n",
         );
 
-        let result = Manifest::load(&dir.join("manifest.toml"));
+        let result = Manifest::load(&OsFs, &dir.join("manifest.toml"), &Layout::default());
         assert!(result.is_err());
     }
 
@@ -293,12 +632,15 @@ mod tests {
         let manifest_content = format!("[files]\n\"src/foo.conf\" = \"{}\"", target_dir.display());
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let manifest = Manifest::load(&manifest_path).unwrap();
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
         assert_eq!(manifest.entries.len(), 1);
 
         let source = dir.join("src/foo.conf").canonicalize().unwrap();
         let expected_target = target_dir.join("foo.conf");
-        assert_eq!(manifest.entries.get(&source).unwrap(), &expected_target);
+        assert_eq!(
+            manifest.entries.get(&source).unwrap().target,
+            expected_target
+        );
     }
 
     #[test]
@@ -311,11 +653,11 @@ mod tests {
         let manifest_content = format!("[files]\n\"src/foo.conf\" = \"{}\"", target_path.display());
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let manifest = Manifest::load(&manifest_path).unwrap();
+        let manifest = Manifest::load(&OsFs, &manifest_path, &Layout::default()).unwrap();
         assert_eq!(manifest.entries.len(), 1);
 
         let source = dir.join("src/foo.conf").canonicalize().unwrap();
-        assert_eq!(manifest.entries.get(&source).unwrap(), &target_path);
+        assert_eq!(manifest.entries.get(&source).unwrap().target, target_path);
     }
 
     #[test]
@@ -331,7 +673,7 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let result = Manifest::load(&manifest_path);
+        let result = Manifest::load(&OsFs, &manifest_path, &Layout::default());
         assert!(result.is_err());
         let err = result.unwrap_err().to_string();
         assert!(err.contains("Conflicting target paths"));
@@ -355,7 +697,7 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &parent_content);
 
-        let result = Manifest::load(&manifest_path);
+        let result = Manifest::load(&OsFs, &manifest_path, &Layout::default());
         assert!(result.is_err());
         assert!(
             result
@@ -380,7 +722,7 @@ mod tests {
         );
         let manifest_path = write_file(&dir, "manifest.toml", &manifest_content);
 
-        let result = Manifest::load(&manifest_path);
+        let result = Manifest::load(&OsFs, &manifest_path, &Layout::default());
         assert!(result.is_err());
         assert!(
             result
@@ -0,0 +1,1354 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::cache::{Cache, decode_path, encode_path};
+use crate::diff::is_identical;
+use crate::error::{Error, Result};
+use crate::format::{fmt_file, fmt_link};
+use crate::fs::Fs;
+use crate::manifest::Manifest;
+use crate::secret::{Plaintext, SecretHashes, SecretKey, hash};
+use crate::selection::Selection;
+use crate::symlink::Symlink;
+use crate::utils::Layout;
+
+/// The changes needed to bring the installed symlinks in line with a manifest.
+/// A plan only inspects the filesystem, nothing is changed until it is executed.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// Installed symlinks to remove, these are removed before anything is installed.
+    pub remove: Vec<Symlink>,
+    /// Symlinks to create.
+    pub install: Vec<Symlink>,
+    /// Installed entries to replace with a copy of their source, which are no longer managed
+    /// afterwards. Secrets already are copies, they are only dropped from the cache.
+    pub eject: Vec<Symlink>,
+    /// Symlinks that cannot be created because their target is occupied by another file.
+    pub conflicts: Vec<Symlink>,
+    /// Conflicting targets that were installed links, most likely replaced by an editor
+    /// saving the file by writing a new one and renaming it over the link.
+    pub replaced: BTreeSet<PathBuf>,
+    /// Resolved conflicts, each symlink is created after the operation clearing its target.
+    pub resolved: Vec<(Symlink, Operation)>,
+    /// The cache contents once the plan has been executed.
+    pub cache: Cache,
+    /// Decrypted contents of the secrets to install by source, see [`Plan::decrypt`].
+    pub plaintexts: BTreeMap<PathBuf, Plaintext>,
+    /// Files and directories whose mode is set once everything is installed.
+    pub modes: BTreeMap<PathBuf, u32>,
+}
+
+/// How to resolve a conflicting target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Delete the existing file and link the source in its place.
+    Overwrite,
+    /// Move the existing file aside and link the source in its place.
+    Backup,
+    /// Replace the source with the contents of the existing file, then link it.
+    /// Encrypted sources cannot be adopted, their targets are backed up instead.
+    Adopt,
+    /// Leave the existing file and do not install the symlink.
+    Skip,
+}
+
+/// A single filesystem change of a plan. Operations are applied in order by the executor,
+/// or rendered to show what would be done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Create a directory along with any missing parents.
+    CreateDir(PathBuf),
+    /// Remove the symlink at the target.
+    RemoveLink(Symlink),
+    /// Remove the file at the given path.
+    RemoveFile(PathBuf),
+    /// Move the file at `path` aside to `backup`.
+    Backup { path: PathBuf, backup: PathBuf },
+    /// Copy the contents of the file at the symlink target over its source and remove the file.
+    Adopt(Symlink),
+    /// Create the symlink.
+    CreateLink(Symlink),
+    /// Replace the link or hard link at the target with a copy of its source, keeping its mode.
+    Eject(Symlink),
+    /// Set the permission bits of a file or directory.
+    SetMode { path: PathBuf, mode: u32 },
+    /// Write the decrypted secret to its target, fails if the secret was not decrypted.
+    Decrypt {
+        symlink: Symlink,
+        plaintext: Option<Plaintext>,
+    },
+    /// Save the cache to `path`.
+    WriteCache { path: PathBuf, cache: Cache },
+    /// Delete the cache file at the given path.
+    DeleteCache(PathBuf),
+}
+
+/// The outcome of executing a plan.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub removed: Vec<Symlink>,
+    pub installed: Vec<Symlink>,
+    pub failed: Vec<(Operation, Error)>,
+}
+
+impl Plan {
+    /// Plans installing the selected manifest entries. Stale symlinks, those in the cache
+    /// but no longer in the manifest (or whose target changed), are removed within the selection.
+    pub fn install(fs: &dyn Fs, manifest: &Manifest, cache: &Cache, selection: &Selection) -> Plan {
+        let selected = manifest.select(selection);
+
+        // Find stale entries: in cache but no longer in manifest (or target changed),
+        // limited to the selection so partial runs never touch links outside of it
+        let stale: Vec<_> = cache
+            .stale_entries(&manifest.targets())
+            .into_iter()
+            .filter(|s| is_selected(manifest, selection, s))
+            .collect();
+
+        let remove: Vec<_> = stale
+            .iter()
+            .filter(|s| s.is_installed(fs))
+            .cloned()
+            .collect();
+
+        // Targets occupied by stale symlinks are no conflict since those are removed first
+        let stale_targets: HashSet<&PathBuf> = remove.iter().map(|s| &s.target).collect();
+        // Outdated secrets are reinstalled, while changes made to them at the target are conflicts
+        let symlinks: Vec<_> = selected
+            .to_symlinks()
+            .into_iter()
+            .map(|s| cache.with_state(s))
+            .collect();
+        let (conflicts, install): (Vec<_>, Vec<_>) = symlinks
+            .iter()
+            .filter(|s| !s.is_current(fs))
+            .cloned()
+            .partition(|s| {
+                s.exists(fs) && !stale_targets.contains(&s.target) && !s.is_installed(fs)
+            });
+
+        // A regular file at a target the cache records as linked was put there by someone else
+        let replaced = conflicts
+            .iter()
+            .filter(|s| cache.is_replaced(fs, s))
+            .map(|s| s.target.clone())
+            .collect();
+
+        // Modes are repaired for every entry except the conflicting ones, which are left alone
+        let modes = symlinks
+            .iter()
+            .filter(|s| !conflicts.contains(s))
+            .flat_map(|s| s.mode_changes(fs))
+            .collect();
+
+        let mut new_cache = cache.clone();
+        if selection.is_all() {
+            new_cache.update(&manifest.targets());
+        } else {
+            new_cache.remove(&stale);
+            new_cache.insert(&selected.targets());
+        }
+        new_cache.secrets.retain(|src, _| {
+            manifest
+                .entries
+                .get(&decode_path(src))
+                .is_none_or(|entry| entry.encrypted)
+        });
+        new_cache.hardlinks.retain(|src, _| {
+            manifest
+                .entries
+                .get(&decode_path(src))
+                .is_none_or(|entry| entry.hardlink && !entry.encrypted)
+        });
+        // Hard links get the current inode of their source, which identifies them once replaced
+        for symlink in symlinks.iter().filter(|s| s.is_hardlink()) {
+            if let Ok(metadata) = fs.metadata(&symlink.source) {
+                new_cache
+                    .hardlinks
+                    .insert(encode_path(&symlink.source), metadata.ino);
+            }
+        }
+
+        Plan {
+            remove,
+            install,
+            conflicts,
+            replaced,
+            cache: new_cache,
+            modes,
+            ..Plan::default()
+        }
+    }
+
+    /// Plans removing the selected entries of both the manifest and the cache.
+    pub fn uninstall(
+        fs: &dyn Fs,
+        manifest: &Manifest,
+        cache: &Cache,
+        selection: &Selection,
+    ) -> Plan {
+        let selected = Plan::selected(manifest, cache, selection);
+
+        let mut new_cache = cache.clone();
+        new_cache.remove(&selected);
+
+        Plan {
+            // Filter to entries that are actually installed as symlinks
+            remove: selected
+                .into_iter()
+                .filter(|s| s.is_installed(fs))
+                .collect(),
+            cache: new_cache,
+            ..Plan::default()
+        }
+    }
+
+    /// Returns the selected entries of both the manifest and the cache, along with their
+    /// recorded state.
+    pub fn selected(manifest: &Manifest, cache: &Cache, selection: &Selection) -> Vec<Symlink> {
+        // Collect all selected entries from both manifest and cache (union)
+        let mut all_entries: BTreeMap<PathBuf, Symlink> = manifest
+            .select(selection)
+            .to_symlinks()
+            .into_iter()
+            .map(|s| (s.source.clone(), cache.with_state(s)))
+            .collect();
+        for entry in cache.all_entries() {
+            if is_selected(manifest, selection, &entry) {
+                all_entries.entry(entry.source.clone()).or_insert(entry);
+            }
+        }
+
+        all_entries.into_values().collect()
+    }
+
+    /// Plans replacing the selected installed entries with copies of their sources, so the targets
+    /// keep working once the sources are gone. Targets no longer linked to their recorded source
+    /// are left alone, as are links whose source is already gone.
+    pub fn eject(fs: &dyn Fs, manifest: &Manifest, cache: &Cache, selection: &Selection) -> Plan {
+        let Plan { remove, .. } = Plan::uninstall(fs, manifest, cache, selection);
+        let eject: Vec<_> = remove
+            .into_iter()
+            .filter(|s| s.is_secret() || s.is_hardlink() || fs.exists(&s.source))
+            .collect();
+
+        let mut new_cache = cache.clone();
+        new_cache.remove(&eject);
+
+        Plan {
+            eject,
+            cache: new_cache,
+            ..Plan::default()
+        }
+    }
+
+    /// Plans removing the selected entries recorded in the cache, without needing the manifest.
+    /// Only targets still linked to their recorded source are removed.
+    pub fn uninstall_all(fs: &dyn Fs, cache: &Cache, selection: &Selection) -> Plan {
+        Plan::uninstall(fs, &Manifest::default(), cache, selection)
+    }
+
+    /// Returns the secrets and hard links to remove whose source is gone,
+    /// their targets hold the only remaining copy of the content.
+    pub fn orphaned(&self, fs: &dyn Fs) -> Vec<&Symlink> {
+        self.remove
+            .iter()
+            .filter(|s| (s.is_secret() || s.is_hardlink()) && !fs.exists(&s.source))
+            .collect()
+    }
+
+    /// Keeps the target of an entry to remove as a plain file that is no longer managed.
+    /// Returns false if the target is not going to be removed.
+    pub fn keep(&mut self, target: &Path) -> bool {
+        let count = self.remove.len();
+        self.remove.retain(|s| s.target != target);
+        self.remove.len() != count
+    }
+
+    /// Returns the links and secrets to install in a sensitive location such as `~/.ssh`
+    /// that would be readable by the group or other users.
+    pub fn exposed(&self, fs: &dyn Fs, layout: &Layout) -> Vec<&Symlink> {
+        self.install
+            .iter()
+            .chain(self.resolved.iter().map(|(s, _)| s))
+            .filter(|s| layout.is_sensitive(&s.target))
+            .filter(|s| s.installed_mode(fs).is_some_and(|mode| mode & 0o044 != 0))
+            .collect()
+    }
+
+    /// Whether the plan installs secrets, which need to be decrypted before it is executed.
+    pub fn needs_key(&self) -> bool {
+        self.secrets().next().is_some()
+    }
+
+    /// Decrypts the sources of the secrets to install and records their hashes in the cache.
+    pub fn decrypt(&mut self, fs: &dyn Fs, key: &SecretKey) -> Result<()> {
+        let sources: Vec<PathBuf> = self.secrets().map(|s| s.source.clone()).collect();
+
+        for source in sources {
+            let ciphertext = fs
+                .read(&source)
+                .map_err(|err| Error::io("Failed to read secret", &source, err))?;
+            let plaintext = key.decrypt(&source, &ciphertext)?;
+
+            self.cache.secrets.insert(
+                encode_path(&source),
+                SecretHashes {
+                    source: hash(&ciphertext),
+                    content: hash(&plaintext.0),
+                },
+            );
+            self.plaintexts.insert(source, plaintext);
+        }
+
+        Ok(())
+    }
+
+    fn secrets(&self) -> impl Iterator<Item = &Symlink> {
+        self.install
+            .iter()
+            .chain(self.resolved.iter().map(|(s, _)| s))
+            .filter(|s| s.is_secret())
+    }
+
+    /// Overwrites every conflicting target that is byte-identical to its source, since replacing
+    /// it with a link loses nothing. Returns the number of resolved conflicts.
+    pub fn resolve_identical(&mut self, fs: &dyn Fs) -> usize {
+        let identical: Vec<_> = self
+            .conflicts
+            .iter()
+            .filter(|s| is_identical(fs, &s.target, &s.source))
+            .map(|s| s.target.clone())
+            .collect();
+
+        for target in &identical {
+            self.resolve(fs, target, Resolution::Overwrite);
+        }
+        identical.len()
+    }
+
+    /// Whether the target of a conflict was an installed link replaced by a file.
+    pub fn is_replaced(&self, symlink: &Symlink) -> bool {
+        self.replaced.contains(&symlink.target)
+    }
+
+    /// Whether executing the plan would not change any symlinks.
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty()
+            && self.install.is_empty()
+            && self.eject.is_empty()
+            && self.resolved.is_empty()
+            && self.modes.is_empty()
+    }
+
+    /// Resolves the conflict at the given target. Returns false if the target has no conflict.
+    pub fn resolve(&mut self, fs: &dyn Fs, target: &Path, resolution: Resolution) -> bool {
+        let Some(index) = self.conflicts.iter().position(|s| s.target == target) else {
+            return false;
+        };
+        let symlink = self.conflicts.remove(index);
+        self.replaced.remove(&symlink.target);
+
+        let operation = match resolution {
+            Resolution::Overwrite => Operation::RemoveFile(symlink.target.clone()),
+            Resolution::Adopt if !symlink.is_secret() => Operation::Adopt(symlink.clone()),
+            Resolution::Backup | Resolution::Adopt => Operation::Backup {
+                path: symlink.target.clone(),
+                backup: backup_path(fs, &symlink.target),
+            },
+            Resolution::Skip => {
+                self.cache.remove(std::slice::from_ref(&symlink));
+                return true;
+            }
+        };
+
+        self.modes.extend(symlink.mode_changes(fs));
+        self.resolved.push((symlink, operation));
+        true
+    }
+
+    /// Returns an error listing every conflicting target, if any.
+    pub fn check_conflicts(&self) -> Result<()> {
+        if self.conflicts.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::Conflict(
+            self.conflicts.iter().map(|s| s.target.clone()).collect(),
+        ))
+    }
+
+    /// Returns the ordered operations needed to execute the plan. Stale links are removed and
+    /// ejected entries copied first, then missing directories are created and new links installed,
+    /// modes are set and finally the cache is written.
+    pub fn operations(&self, fs: &dyn Fs, cache_path: &Path) -> Vec<Operation> {
+        let mut operations: Vec<_> = self
+            .remove
+            .iter()
+            .cloned()
+            .map(Operation::RemoveLink)
+            .collect();
+
+        operations.extend(
+            self.eject
+                .iter()
+                .filter(|s| !s.is_secret() && fs.exists(&s.source))
+                .cloned()
+                .map(Operation::Eject),
+        );
+
+        let dirs: BTreeSet<&Path> = self
+            .install
+            .iter()
+            .filter_map(|s| s.target.parent())
+            .filter(|parent| !fs.is_dir(parent))
+            .collect();
+        operations.extend(
+            dirs.into_iter()
+                .map(|dir| Operation::CreateDir(dir.to_path_buf())),
+        );
+
+        for (symlink, operation) in &self.resolved {
+            operations.push(operation.clone());
+            operations.push(self.install_operation(symlink));
+        }
+
+        for symlink in &self.install {
+            // An outdated hard link is replaced
+            if symlink.is_hardlink() && symlink.is_installed(fs) {
+                operations.push(Operation::RemoveFile(symlink.target.clone()));
+            }
+
+            // A broken symlink occupying the target is replaced
+            if !symlink.is_secret() && fs.is_symlink(&symlink.target) && !symlink.exists(fs) {
+                let current = fs.read_link(&symlink.target).unwrap_or_default();
+                operations.push(Operation::RemoveLink(Symlink::new(
+                    current,
+                    symlink.target.clone(),
+                )));
+            }
+            operations.push(self.install_operation(symlink));
+        }
+
+        operations.extend(self.modes.iter().map(|(path, mode)| Operation::SetMode {
+            path: path.clone(),
+            mode: *mode,
+        }));
+
+        if !self.cache.files.is_empty() {
+            operations.push(Operation::WriteCache {
+                path: cache_path.to_path_buf(),
+                cache: self.cache.clone(),
+            });
+        } else if fs.exists(cache_path) {
+            operations.push(Operation::DeleteCache(cache_path.to_path_buf()));
+        }
+
+        operations
+    }
+
+    fn install_operation(&self, symlink: &Symlink) -> Operation {
+        match symlink.is_secret() {
+            true => Operation::Decrypt {
+                symlink: symlink.clone(),
+                plaintext: self.plaintexts.get(&symlink.source).cloned(),
+            },
+            false => Operation::CreateLink(symlink.clone()),
+        }
+    }
+
+    /// Applies the plan and saves the resulting cache, or deletes it once it is empty.
+    /// Fails without changing anything if the plan has conflicts. Failures of individual
+    /// symlinks do not stop the execution, they are collected in the report instead.
+    pub fn execute(&self, fs: &dyn Fs, cache_path: &Path) -> Result<Report> {
+        self.check_conflicts()?;
+        apply(fs, &self.operations(fs, cache_path))
+    }
+}
+
+/// Applies operations in order. Failing link and directory operations are collected in the report,
+/// while failing to update the cache stops the execution.
+pub fn apply(fs: &dyn Fs, operations: &[Operation]) -> Result<Report> {
+    let mut report = Report::default();
+
+    for operation in operations {
+        let result = match operation {
+            Operation::WriteCache { path, cache } => {
+                cache.save(fs, path)?;
+                continue;
+            }
+            Operation::DeleteCache(path) => {
+                Cache::delete(fs, path)?;
+                continue;
+            }
+            Operation::CreateDir(dir) => fs
+                .create_dir_all(dir)
+                .map_err(|err| Error::io("Failed to create directory", dir, err)),
+            Operation::RemoveFile(path) => fs
+                .remove_file(path)
+                .map_err(|err| Error::io("Failed to remove file", path, err)),
+            Operation::Backup { path, backup } => fs
+                .rename(path, backup)
+                .map_err(|err| Error::io("Failed to back up file", path, err)),
+            Operation::Adopt(symlink) => adopt(fs, symlink),
+            Operation::Eject(symlink) => eject(fs, symlink),
+            Operation::RemoveLink(symlink) => symlink.uninstall(fs).inspect(|_| {
+                report.removed.push(symlink.clone());
+            }),
+            Operation::CreateLink(symlink) => symlink.install(fs).inspect(|_| {
+                report.installed.push(symlink.clone());
+            }),
+            Operation::SetMode { path, mode } => fs
+                .set_mode(path, *mode)
+                .map_err(|err| Error::io("Failed to set mode", path, err)),
+            Operation::Decrypt { symlink, plaintext } => match plaintext {
+                Some(plaintext) => symlink.install_secret(fs, plaintext).inspect(|_| {
+                    report.installed.push(symlink.clone());
+                }),
+                None => Err(Error::MissingKey(symlink.source.clone())),
+            },
+        };
+
+        if let Err(err) = result {
+            report.failed.push((operation.clone(), err));
+        }
+    }
+
+    Ok(report)
+}
+
+fn adopt(fs: &dyn Fs, symlink: &Symlink) -> Result<()> {
+    let contents = fs
+        .read(&symlink.target)
+        .map_err(|err| Error::io("Failed to read file to adopt", &symlink.target, err))?;
+    fs.write(&symlink.source, &contents)
+        .map_err(|err| Error::io("Failed to adopt file", &symlink.source, err))?;
+    fs.remove_file(&symlink.target)
+        .map_err(|err| Error::io("Failed to remove adopted file", &symlink.target, err))
+}
+
+// Writes the copy next to the target and renames it over the link, so the target is never missing.
+// A hard link is replaced the same way, which detaches it from the source.
+fn eject(fs: &dyn Fs, symlink: &Symlink) -> Result<()> {
+    let contents = fs
+        .read(&symlink.source)
+        .map_err(|err| Error::io("Failed to read source to eject", &symlink.source, err))?;
+    let mode = fs
+        .metadata(&symlink.source)
+        .map_err(|err| Error::io("Failed to read source to eject", &symlink.source, err))?
+        .mode;
+
+    let mut temp = symlink.target.as_os_str().to_os_string();
+    temp.push(".homage-eject");
+    let temp = PathBuf::from(temp);
+
+    let copy = || {
+        fs.write(&temp, &contents)?;
+        fs.set_mode(&temp, mode & 0o7777)?;
+        fs.rename(&temp, &symlink.target)
+    };
+    copy().map_err(|err| {
+        let _ = fs.remove_file(&temp);
+        Error::io("Failed to eject", &symlink.target, err)
+    })
+}
+
+/// Returns a free path to move the given file aside to, `<file>.bak` or `<file>.bak.<n>`.
+pub fn backup_path(fs: &dyn Fs, path: &Path) -> PathBuf {
+    let with_suffix = |suffix: &str| {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+
+    std::iter::once(with_suffix(".bak"))
+        .chain((1..).map(|n| with_suffix(&format!(".bak.{n}"))))
+        .find(|candidate| fs.symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::CreateDir(dir) => write!(f, "Create directory {}", fmt_file(dir)),
+            Operation::RemoveLink(symlink) => {
+                write!(f, "Remove link {}", fmt_link(&symlink.target))
+            }
+            Operation::RemoveFile(path) => write!(f, "Remove file {}", fmt_link(path)),
+            Operation::Backup { path, backup } => {
+                write!(f, "Back up {} to {}", fmt_file(path), fmt_file(backup))
+            }
+            Operation::Adopt(symlink) => write!(
+                f,
+                "Adopt {} into {}",
+                fmt_link(&symlink.target),
+                fmt_file(&symlink.source)
+            ),
+            Operation::CreateLink(symlink) => write!(f, "Link {}", symlink),
+            Operation::Eject(symlink) => write!(
+                f,
+                "Replace {} with a copy of {}",
+                fmt_link(&symlink.target),
+                fmt_file(&symlink.source)
+            ),
+            Operation::SetMode { path, mode } => {
+                write!(f, "Set mode of {} to {:04o}", fmt_file(path), mode)
+            }
+            Operation::Decrypt { symlink, .. } => write!(f, "Decrypt {}", symlink),
+            Operation::WriteCache { path, .. } => write!(f, "Write cache {}", fmt_file(path)),
+            Operation::DeleteCache(path) => write!(f, "Delete cache {}", fmt_file(path)),
+        }
+    }
+}
+
+// Whether a cached entry falls within the selection. Tags are taken from the manifest,
+// entries no longer in the manifest have unknown tags.
+fn is_selected(manifest: &Manifest, selection: &Selection, symlink: &Symlink) -> bool {
+    match manifest.entries.get(&symlink.source) {
+        Some(entry) => {
+            selection.matches(&symlink.source, &symlink.target, &entry.tags)
+                || selection.matches(&symlink.source, &entry.target, &entry.tags)
+        }
+        None => selection.matches_untagged(&symlink.source, &symlink.target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+    use crate::utils::Layout;
+
+    const MANIFEST: &str = "/dotfiles/manifest.toml";
+    const CACHE: &str = "/state/state.toml";
+
+    fn mem_manifest(fs: &MemFs, files: &str) -> Manifest {
+        let path = fs.add_file(MANIFEST, &format!("[files]\n{files}"));
+        Manifest::load(fs, &path, &Layout::default()).unwrap()
+    }
+
+    // Installs the manifest at /dotfiles/manifest.toml against the saved cache, like an install run.
+    fn mem_install(fs: &MemFs, selection: &Selection) -> Result<Report> {
+        let manifest = Manifest::load(fs, Path::new(MANIFEST), &Layout::default())?;
+        let cache = Cache::load(fs, Path::new(CACHE))?;
+        Plan::install(fs, &manifest, &cache, selection).execute(fs, Path::new(CACHE))
+    }
+
+    fn mem_uninstall(fs: &MemFs, selection: &Selection) -> Result<Report> {
+        let manifest = Manifest::load(fs, Path::new(MANIFEST), &Layout::default())?;
+        let cache = Cache::load(fs, Path::new(CACHE))?;
+        Plan::uninstall(fs, &manifest, &cache, selection).execute(fs, Path::new(CACHE))
+    }
+
+    fn cached_targets(fs: &MemFs) -> Vec<String> {
+        let cache = Cache::load(fs, Path::new(CACHE)).unwrap();
+        cache.files.into_values().collect()
+    }
+
+    #[test]
+    fn conflicts_are_reported_as_typed_error() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        fs.add_file("/dotfiles/b.conf", "b");
+        fs.add_file("/home/user/a.conf", "existing a");
+        fs.add_file("/home/user/b.conf", "existing b");
+        let manifest = mem_manifest(
+            &fs,
+            "\"a.conf\" = \"/home/user/a.conf\"\n\"b.conf\" = \"/home/user/b.conf\"",
+        );
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        assert!(plan.install.is_empty());
+        assert_eq!(plan.conflicts.len(), 2);
+
+        // Nothing is changed, not even the files that could be linked
+        let result = plan.execute(&fs, Path::new(CACHE));
+        assert!(
+            matches!(result, Err(Error::Conflict(targets)) if targets == vec![
+                PathBuf::from("/home/user/a.conf"),
+                PathBuf::from("/home/user/b.conf"),
+            ])
+        );
+        assert!(!fs.exists(Path::new(CACHE)));
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/a.conf")).unwrap(),
+            "existing a"
+        );
+    }
+
+    #[test]
+    fn execute_reports_changes() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        let manifest = mem_manifest(&fs, "\"a.conf\" = \"/home/user/a.conf\"");
+        let target = Path::new("/home/user/a.conf");
+
+        let report = mem_install(&fs, &Selection::default()).unwrap();
+        assert_eq!(report.installed.len(), 1);
+        assert!(report.failed.is_empty());
+        assert!(fs.is_symlink(target));
+
+        let cache = Cache::load(&fs, Path::new(CACHE)).unwrap();
+        let plan = Plan::uninstall(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.remove.len(), 1);
+        assert!(plan.cache.files.is_empty());
+
+        let report = plan.execute(&fs, Path::new(CACHE)).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(!fs.exists(target));
+        assert!(!fs.exists(Path::new(CACHE)));
+    }
+
+    #[test]
+    fn directories_are_linked_file_by_file() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/app/config.toml", "config");
+        fs.add_file("/dotfiles/app/nested/app.conf", "app");
+        mem_manifest(&fs, "\"app\" = \"/home/user/.config/app\"");
+
+        mem_install(&fs, &Selection::default()).unwrap();
+        assert!(fs.is_symlink(Path::new("/home/user/.config/app/config.toml")));
+        assert!(fs.is_symlink(Path::new("/home/user/.config/app/nested/app.conf")));
+        assert_eq!(cached_targets(&fs).len(), 2);
+
+        // Installing again changes nothing
+        let manifest = Manifest::load(&fs, Path::new(MANIFEST), &Layout::default()).unwrap();
+        let cache = Cache::load(&fs, Path::new(CACHE)).unwrap();
+        assert!(Plan::install(&fs, &manifest, &cache, &Selection::default()).is_empty());
+    }
+
+    #[test]
+    fn removed_sources_are_stale() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/app/keep.conf", "keep");
+        fs.add_file("/dotfiles/app/remove.conf", "remove");
+        mem_manifest(&fs, "\"app\" = \"/home/user/app\"");
+        mem_install(&fs, &Selection::default()).unwrap();
+
+        fs.remove_file(Path::new("/dotfiles/app/remove.conf"))
+            .unwrap();
+        let report = mem_install(&fs, &Selection::default()).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(fs.is_symlink(Path::new("/home/user/app/keep.conf")));
+        assert!(!fs.is_symlink(Path::new("/home/user/app/remove.conf")));
+        assert_eq!(cached_targets(&fs), ["/home/user/app/keep.conf"]);
+    }
+
+    #[test]
+    fn stale_targets_can_be_reused() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        fs.add_file("/dotfiles/b.conf", "b");
+        mem_manifest(&fs, "\"a.conf\" = \"/home/user/shared.conf\"");
+        mem_install(&fs, &Selection::default()).unwrap();
+
+        // The old link is stale and removed before the new one is created
+        mem_manifest(&fs, "\"b.conf\" = \"/home/user/shared.conf\"");
+        mem_install(&fs, &Selection::default()).unwrap();
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/shared.conf"))
+                .unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn selective_install_is_scoped_to_selection() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/gui.conf", "gui");
+        fs.add_file("/dotfiles/shell.conf", "shell");
+        fs.add_file("/dotfiles/old.conf", "old");
+        mem_manifest(
+            &fs,
+            "\"old.conf\" = \"/home/user/old.conf\"\n\"shell.conf\" = \"/home/user/shell.conf\"",
+        );
+        mem_install(&fs, &Selection::default()).unwrap();
+
+        // Drop old.conf and tag the remaining entries
+        mem_manifest(
+            &fs,
+            "\"gui.conf\" = { target = \"/home/user/gui.conf\", tags = [\"gui\"] }\n\
+             \"shell.conf\" = { target = \"/home/user/shell.conf\", tags = [\"shell\"] }",
+        );
+        mem_install(
+            &fs,
+            &Selection::new(vec!["gui".to_string()], vec![], vec![]),
+        )
+        .unwrap();
+
+        assert!(fs.is_symlink(Path::new("/home/user/gui.conf")));
+        // The stale entry has no known tags, so a tag filtered run leaves it alone
+        assert!(fs.is_symlink(Path::new("/home/user/old.conf")));
+        assert_eq!(cached_targets(&fs).len(), 3);
+    }
+
+    #[test]
+    fn selective_uninstall_keeps_other_entries() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file("/dotfiles/zshrc", "zsh");
+        mem_manifest(
+            &fs,
+            "\"nvim\" = \"/home/user/.config/nvim\"\n\"zshrc\" = \"/home/user/.zshrc\"",
+        );
+        mem_install(&fs, &Selection::default()).unwrap();
+
+        let selection = Selection::new(
+            vec![],
+            vec![PathBuf::from("/home/user/.config/nvim")],
+            vec![],
+        );
+        mem_uninstall(&fs, &selection).unwrap();
+        assert!(!fs.exists(Path::new("/home/user/.config/nvim/init.lua")));
+        assert!(fs.is_symlink(Path::new("/home/user/.zshrc")));
+        assert_eq!(cached_targets(&fs), ["/home/user/.zshrc"]);
+    }
+
+    #[test]
+    fn mem_fs_install_removes_stale_and_installs_new() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/old.conf", "old");
+        fs.add_file("/dotfiles/new.conf", "new");
+        fs.add_symlink("/dotfiles/old.conf", "/home/user/old.conf");
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/dotfiles/old.conf".to_string(),
+            "/home/user/old.conf".to_string(),
+        );
+
+        let manifest = mem_manifest(&fs, "\"new.conf\" = \"/home/user/new.conf\"");
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.remove.len(), 1);
+        assert_eq!(plan.install.len(), 1);
+
+        let cache_path = Path::new("/home/user/.cache/homage/cache.toml");
+        let report = plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.installed.len(), 1);
+        assert!(!fs.is_symlink(Path::new("/home/user/old.conf")));
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/new.conf")).unwrap(),
+            "new"
+        );
+
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        assert_eq!(
+            cache.files.keys().collect::<Vec<_>>(),
+            vec!["/dotfiles/new.conf"]
+        );
+    }
+
+    #[test]
+    fn mem_fs_uninstall_removes_installed_links() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        let manifest = mem_manifest(&fs, "\"a.conf\" = \"/home/user/a.conf\"");
+        let cache_path = Path::new("/cache.toml");
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        plan.execute(&fs, cache_path).unwrap();
+        assert!(fs.is_symlink(Path::new("/home/user/a.conf")));
+
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        let plan = Plan::uninstall(&fs, &manifest, &cache, &Selection::default());
+        let report = plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(!fs.is_symlink(Path::new("/home/user/a.conf")));
+        assert!(!fs.exists(cache_path));
+    }
+
+    #[test]
+    fn mem_fs_permission_denied_is_reported_per_symlink() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        fs.add_file("/dotfiles/b.conf", "b");
+        let manifest = mem_manifest(
+            &fs,
+            "\"a.conf\" = \"/etc/a.conf\"\n\"b.conf\" = \"/home/user/b.conf\"",
+        );
+        fs.create_dir_all(Path::new("/etc")).unwrap();
+        fs.fail("/etc", 13);
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let report = plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+        assert_eq!(report.installed.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            report.failed[0].0,
+            Operation::CreateLink(Symlink::new(
+                PathBuf::from("/dotfiles/a.conf"),
+                PathBuf::from("/etc/a.conf")
+            ))
+        );
+        assert!(fs.is_symlink(Path::new("/home/user/b.conf")));
+    }
+
+    #[test]
+    fn mem_fs_full_disk_fails_saving_cache() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/a.conf", "a");
+        let manifest = mem_manifest(&fs, "\"a.conf\" = \"/home/user/a.conf\"");
+        fs.fail("/var/cache", 28);
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let result = plan.execute(&fs, Path::new("/var/cache/homage/cache.toml"));
+        assert!(matches!(
+            result,
+            Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::StorageFull
+        ));
+    }
+
+    #[test]
+    fn operations_are_ordered() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/new.conf", "new");
+        fs.add_file("/dotfiles/old.conf", "old");
+        fs.add_symlink("/dotfiles/old.conf", "/home/user/old.conf");
+        fs.add_symlink("/dotfiles/gone.conf", "/home/user/.config/app/new.conf");
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/dotfiles/old.conf".to_string(),
+            "/home/user/old.conf".to_string(),
+        );
+        let manifest = mem_manifest(
+            &fs,
+            "\"new.conf\" = \"/home/user/.config/app/new.conf\"\n\"old.conf\" = \"/home/user/nested/old.conf\"",
+        );
+
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        let cache_path = Path::new("/cache.toml");
+        let operations = plan.operations(&fs, cache_path);
+
+        let link = |source: &str, target: &str| Symlink::new(source.into(), target.into());
+        assert_eq!(
+            operations,
+            vec![
+                Operation::RemoveLink(link("/dotfiles/old.conf", "/home/user/old.conf")),
+                Operation::CreateDir(PathBuf::from("/home/user/nested")),
+                Operation::RemoveLink(link(
+                    "/dotfiles/gone.conf",
+                    "/home/user/.config/app/new.conf"
+                )),
+                Operation::CreateLink(link(
+                    "/dotfiles/new.conf",
+                    "/home/user/.config/app/new.conf"
+                )),
+                Operation::CreateLink(link("/dotfiles/old.conf", "/home/user/nested/old.conf")),
+                Operation::WriteCache {
+                    path: cache_path.to_path_buf(),
+                    cache: plan.cache.clone(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resolved_conflicts_replace_existing_files() {
+        let fs = MemFs::new();
+        for name in ["overwrite", "backup", "adopt", "skip"] {
+            fs.add_file(format!("/dotfiles/{name}"), "repo");
+            fs.add_file(format!("/home/user/{name}"), "local");
+        }
+        fs.add_file("/home/user/backup.bak", "older backup");
+
+        let manifest = mem_manifest(
+            &fs,
+            "overwrite = \"/home/user/overwrite\"\n\
+             backup = \"/home/user/backup\"\n\
+             adopt = \"/home/user/adopt\"\n\
+             skip = \"/home/user/skip\"",
+        );
+        let mut plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        assert_eq!(plan.conflicts.len(), 4);
+
+        let home = Path::new("/home/user");
+        assert!(plan.resolve(&fs, &home.join("overwrite"), Resolution::Overwrite));
+        assert!(plan.resolve(&fs, &home.join("backup"), Resolution::Backup));
+        assert!(plan.resolve(&fs, &home.join("adopt"), Resolution::Adopt));
+        assert!(plan.resolve(&fs, &home.join("skip"), Resolution::Skip));
+        assert!(!plan.resolve(&fs, &home.join("missing"), Resolution::Skip));
+
+        let report = plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(report.installed.len(), 3);
+
+        let read = |path: &str| fs.read_to_string(Path::new(path)).unwrap();
+        assert_eq!(read("/home/user/overwrite"), "repo");
+        assert_eq!(read("/home/user/backup"), "repo");
+        assert_eq!(read("/home/user/backup.bak.1"), "local");
+        assert_eq!(read("/home/user/adopt"), "local");
+        assert_eq!(read("/dotfiles/adopt"), "local");
+        assert!(!fs.is_symlink(&home.join("skip")));
+        assert_eq!(read("/home/user/skip"), "local");
+
+        let cache = Cache::load(&fs, Path::new("/cache.toml")).unwrap();
+        assert_eq!(cache.files.len(), 3);
+        assert!(!cache.files.contains_key("/dotfiles/skip"));
+    }
+
+    #[test]
+    fn identical_conflicts_are_overwritten() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/same", "content");
+        fs.add_file("/dotfiles/changed", "content");
+        fs.add_file("/home/user/same", "content");
+        fs.add_file("/home/user/changed", "local content");
+
+        let manifest = mem_manifest(
+            &fs,
+            "same = \"/home/user/same\"\nchanged = \"/home/user/changed\"",
+        );
+        let mut plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+
+        assert_eq!(plan.resolve_identical(&fs), 1);
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(
+            plan.conflicts[0].target,
+            PathBuf::from("/home/user/changed")
+        );
+        assert_eq!(
+            plan.resolved[0].1,
+            Operation::RemoveFile(PathBuf::from("/home/user/same"))
+        );
+    }
+
+    #[test]
+    fn secrets_are_decrypted_and_tracked() {
+        let fs = MemFs::new();
+        let key = SecretKey::Identities(vec![age::x25519::Identity::generate()]);
+        let source = fs.add_file("/dotfiles/token.age", "");
+        let source = source.as_path();
+        let target = Path::new("/home/user/token");
+        fs.write(source, &key.encrypt(b"token").unwrap()).unwrap();
+        let manifest = mem_manifest(
+            &fs,
+            "\"token.age\" = { target = \"/home/user/token\", encrypted = true }",
+        );
+        let cache_path = Path::new("/cache.toml");
+
+        // Without a key the secret fails to install and nothing is written
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        assert!(plan.needs_key());
+        let report = plan.execute(&fs, cache_path).unwrap();
+        assert!(matches!(report.failed[0].1, Error::MissingKey(_)));
+        assert!(!fs.exists(target));
+
+        let mut plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        plan.decrypt(&fs, &key).unwrap();
+        let report = plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(report.installed.len(), 1);
+        assert_eq!(fs.read(target).unwrap(), b"token");
+        assert!(!fs.is_symlink(target));
+        assert_eq!(fs.metadata(target).unwrap().mode & 0o777, 0o600);
+
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        assert!(Plan::install(&fs, &manifest, &cache, &Selection::default()).is_empty());
+
+        // Changing the source reinstalls the secret, changing the target is a conflict
+        fs.write(source, &key.encrypt(b"new token").unwrap())
+            .unwrap();
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.install.len(), 1);
+        fs.write(target, b"edited").unwrap();
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.conflicts.len(), 1);
+
+        let mut plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        plan.resolve(&fs, target, Resolution::Adopt);
+        assert!(matches!(plan.resolved[0].1, Operation::Backup { .. }));
+    }
+
+    #[test]
+    fn installed_secrets_are_uninstalled_unless_modified() {
+        let fs = MemFs::new();
+        let key = SecretKey::passphrase("passphrase".to_string());
+        let source = fs.add_file("/dotfiles/token.age", "");
+        fs.write(&source, &key.encrypt(b"token").unwrap()).unwrap();
+        let manifest = mem_manifest(
+            &fs,
+            "\"token.age\" = { target = \"/home/user/token\", encrypted = true }",
+        );
+        let cache_path = Path::new("/cache.toml");
+
+        let mut plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        plan.decrypt(&fs, &key).unwrap();
+        plan.execute(&fs, cache_path).unwrap();
+        let cache = Cache::load(&fs, cache_path).unwrap();
+
+        fs.write(Path::new("/home/user/token"), b"edited").unwrap();
+        let plan = Plan::uninstall(&fs, &manifest, &cache, &Selection::default());
+        assert!(plan.remove.is_empty());
+
+        fs.write(Path::new("/home/user/token"), b"token").unwrap();
+        let plan = Plan::uninstall(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.remove.len(), 1);
+    }
+
+    #[test]
+    fn modes_are_repaired_without_reinstalling() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/ssh/config", "Host *");
+        let manifest = mem_manifest(
+            &fs,
+            "\"ssh/config\" = { target = \"/home/user/.ssh/config\", mode = \"0600\", dir_mode = \"0700\" }",
+        );
+        let target = Path::new("/home/user/.ssh/config");
+        let cache_path = Path::new("/cache.toml");
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let operations = plan.operations(&fs, cache_path);
+        assert_eq!(
+            operations[operations.len() - 3..operations.len() - 1],
+            [
+                Operation::SetMode {
+                    path: source.clone(),
+                    mode: 0o600
+                },
+                Operation::SetMode {
+                    path: PathBuf::from("/home/user/.ssh"),
+                    mode: 0o700
+                },
+            ]
+        );
+        plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(fs.metadata(target).unwrap().mode, 0o600);
+        assert_eq!(
+            fs.metadata(Path::new("/home/user/.ssh")).unwrap().mode,
+            0o700
+        );
+
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        assert!(Plan::install(&fs, &manifest, &cache, &Selection::default()).is_empty());
+
+        fs.set_mode(&source, 0o644).unwrap();
+        let symlink = &manifest.to_symlinks()[0];
+        assert_eq!(symlink.status(&fs), crate::symlink::Status::WrongMode);
+
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert!(plan.install.is_empty());
+        assert_eq!(plan.modes.len(), 1);
+        plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(fs.metadata(&source).unwrap().mode, 0o600);
+    }
+
+    #[test]
+    fn readable_files_in_private_locations_are_exposed() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/ssh/config", "Host *");
+        fs.add_file("/dotfiles/ssh/known_hosts", "");
+        let manifest = mem_manifest(
+            &fs,
+            "\"ssh/config\" = { target = \"/home/user/.ssh/config\", mode = \"0600\" }\n\
+             \"ssh/known_hosts\" = \"/home/user/.ssh/known_hosts\"",
+        );
+        let layout = Layout::new(None, Some(PathBuf::from("/home/user")));
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let exposed = plan.exposed(&fs, &layout);
+        assert_eq!(exposed.len(), 1);
+        assert_eq!(
+            exposed[0].target,
+            PathBuf::from("/home/user/.ssh/known_hosts")
+        );
+    }
+
+    #[test]
+    fn hardlinks_follow_replaced_sources() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/app.conf", "old");
+        let manifest = mem_manifest(
+            &fs,
+            "\"app.conf\" = { target = \"/home/user/app.conf\", link = \"hardlink\" }",
+        );
+        let target = Path::new("/home/user/app.conf");
+        let cache_path = Path::new("/cache.toml");
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let report = plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(report.installed.len(), 1);
+        assert!(!fs.is_symlink(target));
+        assert_eq!(fs.read(target).unwrap(), b"old");
+
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        assert_eq!(
+            cache.hardlinks.values().collect::<Vec<_>>(),
+            [&fs.metadata(&source).unwrap().ino]
+        );
+        assert!(Plan::install(&fs, &manifest, &cache, &Selection::default()).is_empty());
+
+        // An editor saving by write and rename leaves the link with the old file
+        fs.remove_file(&source).unwrap();
+        fs.add_file(&source, "new");
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(plan.install.len(), 1);
+        plan.execute(&fs, cache_path).unwrap();
+        assert_eq!(fs.read(target).unwrap(), b"new");
+
+        // Once removed from the manifest the replaced link is still recognized as stale
+        let cache = Cache::load(&fs, cache_path).unwrap();
+        fs.remove_file(&source).unwrap();
+        fs.add_file(&source, "newer");
+        let manifest = mem_manifest(&fs, "");
+        let plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.remove.len(), 1);
+        plan.execute(&fs, cache_path).unwrap();
+        assert!(!fs.exists(target));
+    }
+
+    #[test]
+    fn hardlinks_across_filesystems_fail() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/app.conf", "app");
+        fs.mount("/home");
+        let manifest = mem_manifest(
+            &fs,
+            "\"app.conf\" = { target = \"/home/user/app.conf\", link = \"hardlink\" }",
+        );
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let report = plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+        assert!(matches!(
+            &report.failed[..],
+            [(_, Error::CrossDevice(target))] if target == Path::new("/home/user/app.conf")
+        ));
+    }
+
+    #[test]
+    fn links_replaced_by_files_are_recognized() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/app.conf", "old");
+        fs.add_file("/dotfiles/other.conf", "other");
+        fs.add_file("/home/user/other.conf", "unmanaged");
+        let manifest = mem_manifest(
+            &fs,
+            "\"app.conf\" = \"/home/user/app.conf\"\n\"other.conf\" = \"/home/user/other.conf\"",
+        );
+        let target = Path::new("/home/user/app.conf");
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/dotfiles/app.conf".to_string(),
+            "/home/user/app.conf".to_string(),
+        );
+        fs.add_file(target, "edited");
+
+        let mut plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert_eq!(plan.conflicts.len(), 2);
+        assert_eq!(plan.replaced, BTreeSet::from([target.to_path_buf()]));
+        assert!(plan.is_replaced(&plan.conflicts[0]));
+        assert!(!plan.is_replaced(&plan.conflicts[1]));
+
+        // Pulling the changes adopts the file into the repository
+        plan.resolve(&fs, target, Resolution::Adopt);
+        plan.resolve(&fs, Path::new("/home/user/other.conf"), Resolution::Skip);
+        assert!(plan.replaced.is_empty());
+        plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+        assert_eq!(fs.read(&source).unwrap(), b"edited");
+        assert!(fs.is_symlink(target));
+    }
+
+    #[test]
+    fn uninstall_all_works_from_the_cache_alone() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/app.conf", "app");
+        fs.add_file("/dotfiles/data.conf", "data");
+        fs.add_symlink("/dotfiles/app.conf", "/home/user/app.conf");
+        fs.add_symlink("/dotfiles/gone.conf", "/home/user/gone.conf");
+        fs.add_symlink("/elsewhere/other.conf", "/home/user/other.conf");
+        fs.hard_link(
+            Path::new("/dotfiles/data.conf"),
+            Path::new("/home/user/data.conf"),
+        )
+        .unwrap();
+        let ino = fs.metadata(Path::new("/dotfiles/data.conf")).unwrap().ino;
+        fs.remove_file(Path::new("/dotfiles/data.conf")).unwrap();
+
+        let mut cache = Cache::default();
+        for name in ["app.conf", "gone.conf", "other.conf", "data.conf"] {
+            cache
+                .files
+                .insert(format!("/dotfiles/{name}"), format!("/home/user/{name}"));
+        }
+        cache
+            .hardlinks
+            .insert("/dotfiles/data.conf".to_string(), ino);
+
+        let mut plan = Plan::uninstall_all(&fs, &cache, &Selection::default());
+        // The link pointing elsewhere was changed by hand and is left alone
+        assert_eq!(plan.remove.len(), 3);
+        assert_eq!(
+            plan.orphaned(&fs)
+                .iter()
+                .map(|s| s.target.as_path())
+                .collect::<Vec<_>>(),
+            vec![Path::new("/home/user/data.conf")]
+        );
+
+        assert!(plan.keep(Path::new("/home/user/data.conf")));
+        plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+
+        assert!(!fs.is_symlink(Path::new("/home/user/app.conf")));
+        assert!(!fs.is_symlink(Path::new("/home/user/gone.conf")));
+        assert!(fs.is_symlink(Path::new("/home/user/other.conf")));
+        assert_eq!(fs.read(Path::new("/home/user/data.conf")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn eject_replaces_links_with_copies() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/app.conf", "app");
+        fs.set_mode(Path::new("/dotfiles/app.conf"), 0o640).unwrap();
+        fs.add_file("/dotfiles/data.conf", "data");
+        fs.add_symlink("/dotfiles/app.conf", "/home/user/app.conf");
+        fs.add_symlink("/dotfiles/gone.conf", "/home/user/gone.conf");
+        fs.add_symlink("/elsewhere/other.conf", "/home/user/other.conf");
+        fs.hard_link(
+            Path::new("/dotfiles/data.conf"),
+            Path::new("/home/user/data.conf"),
+        )
+        .unwrap();
+        let ino = fs.metadata(Path::new("/dotfiles/data.conf")).unwrap().ino;
+
+        let mut cache = Cache::default();
+        for name in ["app.conf", "gone.conf", "other.conf", "data.conf"] {
+            cache
+                .files
+                .insert(format!("/dotfiles/{name}"), format!("/home/user/{name}"));
+        }
+        cache
+            .hardlinks
+            .insert("/dotfiles/data.conf".to_string(), ino);
+
+        let plan = Plan::eject(&fs, &Manifest::default(), &cache, &Selection::default());
+        assert_eq!(plan.eject.len(), 2);
+        plan.execute(&fs, Path::new("/cache.toml")).unwrap();
+
+        let target = Path::new("/home/user/app.conf");
+        assert!(!fs.is_symlink(target));
+        assert_eq!(fs.read(target).unwrap(), b"app");
+        assert_eq!(fs.metadata(target).unwrap().mode & 0o7777, 0o640);
+        assert!(!fs.exists(Path::new("/home/user/app.conf.homage-eject")));
+
+        // The hard link no longer shares the file of its source
+        let data = fs.metadata(Path::new("/home/user/data.conf")).unwrap();
+        assert_ne!(data.ino, ino);
+        assert_eq!(fs.read(Path::new("/home/user/data.conf")).unwrap(), b"data");
+
+        // The dangling link and the link changed by hand are left alone
+        assert!(fs.is_symlink(Path::new("/home/user/gone.conf")));
+        assert!(fs.is_symlink(Path::new("/home/user/other.conf")));
+        assert_eq!(
+            Cache::load(&fs, Path::new("/cache.toml"))
+                .unwrap()
+                .files
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["/dotfiles/gone.conf", "/dotfiles/other.conf"]
+        );
+    }
+}
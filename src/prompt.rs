@@ -0,0 +1,359 @@
+use std::io::{BufRead, Write};
+
+use homage::diff::diff_files;
+use homage::format::{fmt_diff, fmt_error, fmt_file};
+use homage::plan::Resolution;
+use homage::{OsFs, Plan, Symlink};
+
+/// An answer to the conflict prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Diff,
+    Resolve(Resolution),
+    /// Resolves the current conflict and all remaining ones the same way.
+    ResolveAll(Resolution),
+    Quit,
+}
+
+const CHOICES: &str = "[d]iff, [o]verwrite, [b]ackup, [a]dopt, [s]kip, [q]uit \
+                       (uppercase applies to all remaining)";
+
+const REPAIR_CHOICES: &str = "[p]ull changes into the repository, [d]iscard changes, [s]kip, [q]uit \
+                              (uppercase applies to all remaining)";
+
+const ORPHAN_CHOICES: &str = "[k]eep a copy, [r]emove, [q]uit (uppercase applies to all remaining)";
+
+/// Asks for a yes/no confirmation, anything but y/yes is a no.
+pub fn confirm(input: &mut impl BufRead) -> bool {
+    let mut buffer = String::new();
+
+    match input.read_line(&mut buffer) {
+        Ok(_) => matches!(buffer.trim().to_lowercase().as_str(), "yes" | "y"),
+        Err(_) => false,
+    }
+}
+
+/// Walks through the conflicts of the plan and resolves each one as answered.
+/// Returns false if the user quit, in which case the remaining conflicts are left unresolved.
+pub fn resolve_conflicts(plan: &mut Plan, input: &mut impl BufRead) -> bool {
+    let mut remaining: Option<Resolution> = None;
+
+    for conflict in plan.conflicts.clone() {
+        let resolution = match remaining {
+            Some(resolution) => resolution,
+            None => match ask_resolution(&conflict, plan.is_replaced(&conflict), input) {
+                Some(Answer::Resolve(resolution)) => resolution,
+                Some(Answer::ResolveAll(resolution)) => {
+                    remaining = Some(resolution);
+                    resolution
+                }
+                _ => return false,
+            },
+        };
+
+        plan.resolve(&OsFs, &conflict.target, resolution);
+    }
+
+    true
+}
+
+/// Walks through the links that were replaced by files, showing how each file differs from its
+/// source and asking whether to pull the changes into the repository or discard them.
+/// Returns false if the user quit, in which case the remaining links are left unresolved.
+pub fn repair_replaced(plan: &mut Plan, input: &mut impl BufRead) -> bool {
+    let mut remaining: Option<Resolution> = None;
+    let replaced: Vec<Symlink> = plan
+        .conflicts
+        .iter()
+        .filter(|s| plan.is_replaced(s))
+        .cloned()
+        .collect();
+
+    for symlink in replaced {
+        let resolution = match remaining {
+            Some(resolution) => resolution,
+            None => match ask_repair(&symlink, input) {
+                Some(Answer::Resolve(resolution)) => resolution,
+                Some(Answer::ResolveAll(resolution)) => {
+                    remaining = Some(resolution);
+                    resolution
+                }
+                _ => return false,
+            },
+        };
+
+        plan.resolve(&OsFs, &symlink.target, resolution);
+    }
+
+    true
+}
+
+/// Walks through the secrets and hard links to uninstall whose source is gone, asking whether
+/// to keep each target as a plain copy of the content or to remove it.
+/// Returns false if the user quit, in which case nothing should be uninstalled.
+pub fn keep_orphans(plan: &mut Plan, input: &mut impl BufRead) -> bool {
+    let mut remaining: Option<Resolution> = None;
+    let orphaned: Vec<Symlink> = plan.orphaned(&OsFs).into_iter().cloned().collect();
+
+    for symlink in orphaned {
+        let resolution = match remaining {
+            Some(resolution) => resolution,
+            None => match ask_orphan(&symlink, input) {
+                Some(Answer::Resolve(resolution)) => resolution,
+                Some(Answer::ResolveAll(resolution)) => {
+                    remaining = Some(resolution);
+                    resolution
+                }
+                _ => return false,
+            },
+        };
+
+        if resolution == Resolution::Skip {
+            plan.keep(&symlink.target);
+        }
+    }
+
+    true
+}
+
+fn ask_orphan(symlink: &Symlink, input: &mut impl BufRead) -> Option<Answer> {
+    println!(
+        "The source {} of {} is gone, the target holds the only copy",
+        fmt_error(&symlink.source),
+        fmt_file(&symlink.target)
+    );
+
+    loop {
+        print!("{}? ", ORPHAN_CHOICES);
+        let _ = std::io::stdout().flush();
+
+        let mut buffer = String::new();
+        if input.read_line(&mut buffer).ok()? == 0 {
+            return None;
+        }
+
+        match parse_orphan(buffer.trim()) {
+            Some(answer) => return Some(answer),
+            None => println!("Unknown answer, expected one of {}", ORPHAN_CHOICES),
+        }
+    }
+}
+
+fn ask_repair(symlink: &Symlink, input: &mut impl BufRead) -> Option<Answer> {
+    println!(
+        "{} was replaced by a file, it should link to {}",
+        fmt_error(&symlink.target),
+        fmt_file(&symlink.source)
+    );
+    match diff_files(&OsFs, &symlink.source, &symlink.target) {
+        Some(diff) => print!("{}", fmt_diff(&diff)),
+        None => println!("The file is identical to its source"),
+    }
+
+    loop {
+        print!("{}? ", REPAIR_CHOICES);
+        let _ = std::io::stdout().flush();
+
+        let mut buffer = String::new();
+        if input.read_line(&mut buffer).ok()? == 0 {
+            return None;
+        }
+
+        match parse_repair(buffer.trim()) {
+            Some(answer) => return Some(answer),
+            None => println!("Unknown answer, expected one of {}", REPAIR_CHOICES),
+        }
+    }
+}
+
+// Prompts until the conflict is resolved or the user quits, showing diffs on request.
+// End of input counts as quitting.
+fn ask_resolution(conflict: &Symlink, replaced: bool, input: &mut impl BufRead) -> Option<Answer> {
+    let state = match replaced {
+        true => "was replaced by a file",
+        false => "already exists",
+    };
+    println!(
+        "{} {}, it should link to {}",
+        fmt_error(&conflict.target),
+        state,
+        fmt_file(&conflict.source)
+    );
+
+    loop {
+        print!("{}? ", CHOICES);
+        let _ = std::io::stdout().flush();
+
+        let mut buffer = String::new();
+        if input.read_line(&mut buffer).ok()? == 0 {
+            return None;
+        }
+
+        match parse_answer(buffer.trim()) {
+            Some(Answer::Diff) => match diff_files(&OsFs, &conflict.target, &conflict.source) {
+                Some(diff) => print!("{}", fmt_diff(&diff)),
+                None => println!("The files are identical"),
+            },
+            Some(answer) => return Some(answer),
+            None => println!("Unknown answer, expected one of {}", CHOICES),
+        }
+    }
+}
+
+fn parse_answer(answer: &str) -> Option<Answer> {
+    let resolution = match answer.to_lowercase().as_str() {
+        "d" | "diff" => return Some(Answer::Diff),
+        "q" | "quit" => return Some(Answer::Quit),
+        "o" | "overwrite" => Resolution::Overwrite,
+        "b" | "backup" => Resolution::Backup,
+        "a" | "adopt" => Resolution::Adopt,
+        "s" | "skip" => Resolution::Skip,
+        _ => return None,
+    };
+
+    Some(for_answer(answer, resolution))
+}
+
+// Pulling the changes adopts the file, discarding them overwrites it.
+fn parse_repair(answer: &str) -> Option<Answer> {
+    let resolution = match answer.to_lowercase().as_str() {
+        "q" | "quit" => return Some(Answer::Quit),
+        "p" | "pull" => Resolution::Adopt,
+        "d" | "discard" => Resolution::Overwrite,
+        "s" | "skip" => Resolution::Skip,
+        _ => return None,
+    };
+
+    Some(for_answer(answer, resolution))
+}
+
+// Keeping a copy leaves the target alone like skipping a conflict, removing it discards the content.
+fn parse_orphan(answer: &str) -> Option<Answer> {
+    let resolution = match answer.to_lowercase().as_str() {
+        "q" | "quit" => return Some(Answer::Quit),
+        "k" | "keep" => Resolution::Skip,
+        "r" | "remove" => Resolution::Overwrite,
+        _ => return None,
+    };
+
+    Some(for_answer(answer, resolution))
+}
+
+// An uppercase answer applies to all remaining prompts.
+fn for_answer(answer: &str, resolution: Resolution) -> Answer {
+    match answer.chars().next() {
+        Some(first) if first.is_uppercase() => Answer::ResolveAll(resolution),
+        _ => Answer::Resolve(resolution),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use homage::Operation;
+
+    fn conflicts(targets: &[&str]) -> Plan {
+        let symlinks: Vec<_> = targets
+            .iter()
+            .map(|target| Symlink::new(PathBuf::from("/nonexistent/source"), target.into()))
+            .collect();
+
+        Plan {
+            conflicts: symlinks,
+            ..Plan::default()
+        }
+    }
+
+    #[test]
+    fn parse_answers() {
+        assert_eq!(parse_answer("d"), Some(Answer::Diff));
+        assert_eq!(parse_answer("b"), Some(Answer::Resolve(Resolution::Backup)));
+        assert_eq!(
+            parse_answer("S"),
+            Some(Answer::ResolveAll(Resolution::Skip))
+        );
+        assert_eq!(
+            parse_answer("Overwrite"),
+            Some(Answer::ResolveAll(Resolution::Overwrite))
+        );
+        assert_eq!(parse_answer("x"), None);
+    }
+
+    #[test]
+    fn resolve_conflicts_applies_answers() {
+        let mut plan = conflicts(&["/nonexistent/a", "/nonexistent/b", "/nonexistent/c"]);
+        let mut input = Cursor::new("x\no\nS\n");
+
+        assert!(resolve_conflicts(&mut plan, &mut input));
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(plan.resolved.len(), 1);
+        assert_eq!(plan.resolved[0].0.target, PathBuf::from("/nonexistent/a"));
+    }
+
+    #[test]
+    fn repair_replaced_only_asks_for_replaced_links() {
+        let mut plan = conflicts(&["/nonexistent/a", "/nonexistent/b", "/nonexistent/c"]);
+        plan.replaced = ["/nonexistent/a", "/nonexistent/c"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+
+        assert!(repair_replaced(
+            &mut plan,
+            &mut Cursor::new(
+                "x
+p
+d
+"
+            )
+        ));
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(plan.conflicts[0].target, PathBuf::from("/nonexistent/b"));
+        assert!(matches!(plan.resolved[0].1, Operation::Adopt(_)));
+        assert!(matches!(plan.resolved[1].1, Operation::RemoveFile(_)));
+    }
+
+    #[test]
+    fn resolve_conflicts_stops_on_quit() {
+        let mut plan = conflicts(&["/nonexistent/a", "/nonexistent/b"]);
+
+        assert!(!resolve_conflicts(&mut plan, &mut Cursor::new("a\nq\n")));
+        assert_eq!(plan.resolved.len(), 1);
+        assert_eq!(plan.conflicts.len(), 1);
+
+        assert!(!resolve_conflicts(&mut plan, &mut Cursor::new("")));
+        assert_eq!(plan.conflicts.len(), 1);
+    }
+
+    #[test]
+    fn keep_orphans_keeps_copies_as_answered() {
+        let orphan = |target: &str| {
+            Symlink::hardlink(PathBuf::from("/nonexistent/source"), target.into(), Some(1))
+        };
+        let mut plan = Plan {
+            remove: vec![
+                orphan("/nonexistent/a"),
+                orphan("/nonexistent/b"),
+                Symlink::new(
+                    PathBuf::from("/nonexistent/source"),
+                    "/nonexistent/c".into(),
+                ),
+            ],
+            ..Plan::default()
+        };
+
+        assert!(keep_orphans(&mut plan, &mut Cursor::new("x\nk\nr\n")));
+        let targets: Vec<_> = plan.remove.iter().map(|s| s.target.clone()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                PathBuf::from("/nonexistent/b"),
+                PathBuf::from("/nonexistent/c")
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::cache::{Cache, decode_path};
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+use crate::manifest::Manifest;
+use crate::symlink::{Kind, Symlink};
+
+/// A move of the source tree, such as the dotfiles repository moving from `~/dotfiles`
+/// to `~/src/dotfiles`. Every cached entry below `from` is moved below `to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Relocation {
+    pub fn new(from: PathBuf, to: PathBuf) -> Relocation {
+        Relocation { from, to }
+    }
+
+    /// Detects a moved source tree by matching the cache against the manifest. Entries sharing a
+    /// target but not a source have to agree on a move, which keeps the path of each source
+    /// relative to the moved root. The deepest such root is used, and it must be gone.
+    pub fn detect(fs: &dyn Fs, manifest: &Manifest, cache: &Cache) -> Option<Relocation> {
+        let sources: BTreeMap<PathBuf, &PathBuf> = manifest
+            .entries
+            .iter()
+            .map(|(src, entry)| (entry.target.clone(), src))
+            .collect();
+
+        let mut candidates: Option<Vec<Relocation>> = None;
+        for (src, target) in &cache.files {
+            let src = decode_path(src);
+            let Some(new_src) = sources.get(&decode_path(target)) else {
+                continue;
+            };
+            if src == **new_src {
+                continue;
+            }
+
+            let moves = Relocation::candidates(&src, new_src);
+            candidates = Some(match candidates {
+                Some(previous) => previous.into_iter().filter(|c| moves.contains(c)).collect(),
+                None => moves,
+            });
+        }
+
+        candidates?
+            .pop()
+            .filter(|relocation| !fs.exists(&relocation.from))
+    }
+
+    // Returns every move turning `old` into `new` by replacing a root while keeping the path
+    // below it, from the shallowest root to the deepest.
+    fn candidates(old: &Path, new: &Path) -> Vec<Relocation> {
+        let mut from: Vec<_> = old.components().collect();
+        let mut to: Vec<_> = new.components().collect();
+
+        let mut candidates = Vec::new();
+        while from.len() > 1 && to.len() > 1 && from.last() == to.last() {
+            from.pop();
+            to.pop();
+            candidates.push(Relocation::new(from.iter().collect(), to.iter().collect()));
+        }
+
+        candidates.retain(|relocation| relocation.from != relocation.to);
+        candidates.reverse();
+        candidates
+    }
+
+    /// Returns the cached entries below the old root along with their relocated counterpart.
+    pub fn entries(&self, cache: &Cache) -> Vec<(Symlink, Symlink)> {
+        cache
+            .all_entries()
+            .into_iter()
+            .filter_map(|symlink| {
+                let rel = symlink.source.strip_prefix(&self.from).ok()?;
+                let relocated = Symlink {
+                    source: self.to.join(rel),
+                    ..symlink.clone()
+                };
+                Some((symlink, relocated))
+            })
+            .collect()
+    }
+
+    /// Moves the cached entries below the old root and re-points every link still pointing at
+    /// its old source. Each link is replaced atomically by renaming a new link over it, so a
+    /// target is never missing. Returns the relocated entries, stopping at the first failure
+    /// with the cache only holding the entries relocated until then.
+    pub fn apply(&self, fs: &dyn Fs, cache: &mut Cache) -> Result<Vec<Symlink>> {
+        let mut relocated = Vec::new();
+
+        for (old, new) in self.entries(cache) {
+            if old.kind == Kind::Link && fs.read_link(&old.target).is_ok_and(|s| s == old.source) {
+                let mut temp = OsString::from(old.target.as_os_str());
+                temp.push(".homage-relocate");
+                let temp = PathBuf::from(temp);
+
+                fs.symlink(&new.source, &temp)
+                    .map_err(|err| Error::io("Failed to create symlink", &temp, err))?;
+                fs.rename(&temp, &new.target)
+                    .map_err(|err| Error::io("Failed to replace symlink", &new.target, err))?;
+            }
+
+            cache.rename(&old.source, &new.source);
+            relocated.push(new);
+        }
+
+        Ok(relocated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+    use crate::utils::Layout;
+
+    #[test]
+    fn moved_sources_are_detected() {
+        let fs = MemFs::new();
+        fs.add_file("/src/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file("/src/dotfiles/zshrc", "zsh");
+        let path = fs.add_file(
+            "/src/dotfiles/manifest.toml",
+            "[files]\n\"nvim\" = \"/home/user/.config/nvim\"\n\"zshrc\" = \"/home/user/.zshrc\"",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::default()).unwrap();
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/home/user/dotfiles/nvim/init.lua".to_string(),
+            "/home/user/.config/nvim/init.lua".to_string(),
+        );
+        cache.files.insert(
+            "/home/user/dotfiles/zshrc".to_string(),
+            "/home/user/.zshrc".to_string(),
+        );
+
+        let relocation = Relocation::detect(&fs, &manifest, &cache).unwrap();
+        assert_eq!(
+            relocation,
+            Relocation::new("/home/user/dotfiles".into(), "/src/dotfiles".into())
+        );
+
+        // Nothing moved while the old root still exists
+        fs.create_dir_all(Path::new("/home/user/dotfiles")).unwrap();
+        assert_eq!(Relocation::detect(&fs, &manifest, &cache), None);
+    }
+
+    #[test]
+    fn renamed_sources_are_not_a_move() {
+        assert!(
+            Relocation::candidates(Path::new("/dotfiles/a.conf"), Path::new("/dotfiles/b.conf"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn apply_repoints_links_and_rewrites_cache() {
+        let fs = MemFs::new();
+        fs.add_file("/new/dotfiles/zshrc", "zsh");
+        fs.add_symlink("/old/dotfiles/zshrc", "/home/user/.zshrc");
+        fs.add_file("/home/user/.bashrc", "edited by hand");
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/old/dotfiles/zshrc".to_string(),
+            "/home/user/.zshrc".to_string(),
+        );
+        cache.files.insert(
+            "/old/dotfiles/bashrc".to_string(),
+            "/home/user/.bashrc".to_string(),
+        );
+        cache.files.insert(
+            "/elsewhere/vimrc".to_string(),
+            "/home/user/.vimrc".to_string(),
+        );
+
+        let relocation = Relocation::new("/old/dotfiles".into(), "/new/dotfiles".into());
+        let relocated = relocation.apply(&fs, &mut cache).unwrap();
+
+        assert_eq!(relocated.len(), 2);
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/.zshrc")).unwrap(),
+            Path::new("/new/dotfiles/zshrc")
+        );
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/.bashrc")).unwrap(),
+            "edited by hand"
+        );
+        assert!(!fs.exists(Path::new("/home/user/.zshrc.homage-relocate")));
+        assert_eq!(
+            cache.files.keys().collect::<Vec<_>>(),
+            vec![
+                "/elsewhere/vimrc",
+                "/new/dotfiles/bashrc",
+                "/new/dotfiles/zshrc"
+            ]
+        );
+    }
+}
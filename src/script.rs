@@ -0,0 +1,371 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::export::{sh_quote, sh_quote_path};
+use crate::fs::Fs;
+use crate::plan::Operation;
+
+/// Helpers called by every step of the script. Each one first checks that the filesystem is
+/// still in the state the plan was made against, and stops the script otherwise. Directories,
+/// links and removals already in place are skipped.
+const PREAMBLE: &str = r#"#!/bin/sh
+# Generated by homage. Every step checks that its target is still as planned and stops the
+# script otherwise. The last step records the changes in the state.
+set -eu
+
+fail() {
+  printf 'homage: %s\n' "$*" >&2
+  exit 1
+}
+
+# Whether anything is at the path, including a broken link
+occupied() {
+  [ -e "$1" ] || [ -L "$1" ]
+}
+
+inode() {
+  ls -di "$1" | awk '{ print $1 }'
+}
+
+# Whether the file holds exactly the given content
+holds() {
+  [ -f "$1" ] && [ ! -L "$1" ] && printf '%s' "$2" | cmp -s - "$1"
+}
+
+create_dir() {
+  if [ -d "$1" ]; then return; fi
+  if occupied "$1"; then fail "$1 is in the way of a directory"; fi
+  $run mkdir -p "$1"
+}
+
+remove_link() {
+  if ! occupied "$1"; then return; fi
+  if [ ! -L "$1" ] || [ "$(readlink "$1")" != "$2" ]; then fail "$1 no longer links to $2"; fi
+  $run rm "$1"
+}
+
+remove_file() {
+  if ! occupied "$1"; then return; fi
+  if [ "$(inode "$1")" != "$2" ]; then fail "$1 changed since the plan was made"; fi
+  $run rm "$1"
+}
+
+backup() {
+  if ! occupied "$1" || [ "$(inode "$1")" != "$3" ]; then fail "$1 changed since the plan was made"; fi
+  if occupied "$2"; then fail "$2 is in the way of the backup of $1"; fi
+  $run mv "$1" "$2"
+}
+
+link() {
+  if [ -L "$2" ] && [ "$(readlink "$2")" = "$1" ]; then return; fi
+  if occupied "$2"; then fail "$2 is in the way of the link to $1"; fi
+  if [ ! -e "$1" ]; then fail "$1 does not exist"; fi
+  $run ln -s "$1" "$2"
+}
+
+hardlink() {
+  if [ ! -f "$1" ]; then fail "$1 does not exist"; fi
+  if [ -f "$2" ] && [ ! -L "$2" ] && [ "$(inode "$2")" = "$(inode "$1")" ]; then return; fi
+  if occupied "$2"; then fail "$2 is in the way of the hard link to $1"; fi
+  $run ln "$1" "$2"
+}
+
+set_mode() {
+  if [ ! -e "$1" ]; then fail "$1 does not exist"; fi
+  $run chmod "$2" "$1"
+}
+
+create_state() {
+  if holds "$1" "$2"; then return; fi
+  if occupied "$1"; then fail "$1 changed since the plan was made"; fi
+  $run mkdir -p "$(dirname "$1")"
+  printf '%s' "$2" | $run tee "$1" > /dev/null
+}
+
+replace_state() {
+  if holds "$1" "$3"; then return; fi
+  if ! holds "$1" "$2"; then fail "$1 changed since the plan was made"; fi
+  printf '%s' "$3" | $run tee "$1" > /dev/null
+}
+
+delete_state() {
+  if ! occupied "$1"; then return; fi
+  if ! holds "$1" "$2"; then fail "$1 changed since the plan was made"; fi
+  $run rm "$1"
+}
+"#;
+
+/// An install plan rendered as a self-contained POSIX shell script, for reviewing the exact
+/// changes before they are made. Files are identified by the inode they had when the plan was
+/// made, so the script stops instead of removing or moving a file that was replaced since.
+#[derive(Debug, Default)]
+pub struct Script {
+    body: String,
+    /// Operations the script can not perform, with the reason.
+    pub skipped: Vec<String>,
+}
+
+impl Script {
+    /// Appends the steps of the given operations, which were planned against `fs`. With an
+    /// escalation command, such as `["sudo"]`, every change is run through it. Secrets are left
+    /// out, since the script neither holds their plaintext nor can verify their contents. The
+    /// state is written by the last step, while the state it was planned against is unchanged.
+    /// If any operation was left out the state is not written, as `homage install` has to
+    /// finish the plan and records it then.
+    pub fn push(
+        &mut self,
+        fs: &dyn Fs,
+        operations: &[Operation],
+        escalate: Option<&[String]>,
+    ) -> Result<()> {
+        let mut steps = String::new();
+        let skipped = self.skipped.len();
+
+        for operation in operations {
+            let step = match operation {
+                Operation::CreateDir(dir) => format!("create_dir {}", sh_quote_path(dir)),
+                Operation::RemoveLink(symlink) if symlink.is_secret() => {
+                    self.skip(
+                        &symlink.target,
+                        "removing a secret is left to 'homage install'",
+                    );
+                    continue;
+                }
+                Operation::RemoveLink(symlink) if symlink.is_hardlink() => format!(
+                    "remove_file {} {}",
+                    sh_quote_path(&symlink.target),
+                    inode(fs, &symlink.target)?
+                ),
+                Operation::RemoveLink(symlink) => format!(
+                    "remove_link {} {}",
+                    sh_quote_path(&symlink.target),
+                    sh_quote_path(&symlink.source)
+                ),
+                Operation::RemoveFile(path) => {
+                    format!("remove_file {} {}", sh_quote_path(path), inode(fs, path)?)
+                }
+                Operation::Backup { path, backup } => format!(
+                    "backup {} {} {}",
+                    sh_quote_path(path),
+                    sh_quote_path(backup),
+                    inode(fs, path)?
+                ),
+                Operation::CreateLink(symlink) => format!(
+                    "{} {} {}",
+                    if symlink.is_hardlink() {
+                        "hardlink"
+                    } else {
+                        "link"
+                    },
+                    sh_quote_path(&symlink.source),
+                    sh_quote_path(&symlink.target)
+                ),
+                Operation::SetMode { path, mode } => {
+                    format!("set_mode {} {:04o}", sh_quote_path(path), mode)
+                }
+                Operation::Decrypt { symlink, .. } => {
+                    self.skip(&symlink.target, "secrets are decrypted by 'homage install'");
+                    continue;
+                }
+                Operation::Adopt(symlink) | Operation::Eject(symlink) => {
+                    self.skip(&symlink.target, "not supported by scripts");
+                    continue;
+                }
+                Operation::WriteCache { path, .. } | Operation::DeleteCache(path)
+                    if self.skipped.len() > skipped =>
+                {
+                    self.skip(
+                        path,
+                        "the state is recorded once 'homage install' finished the plan",
+                    );
+                    continue;
+                }
+                Operation::WriteCache { path, cache } => {
+                    let content = cache.to_toml()?;
+                    match read_state(fs, path)? {
+                        Some(current) if current == content => continue,
+                        Some(current) => format!(
+                            "replace_state {} {} {}",
+                            sh_quote_path(path),
+                            sh_quote(&current),
+                            sh_quote(&content)
+                        ),
+                        None => format!(
+                            "create_state {} {}",
+                            sh_quote_path(path),
+                            sh_quote(&content)
+                        ),
+                    }
+                }
+                Operation::DeleteCache(path) => match read_state(fs, path)? {
+                    Some(current) => {
+                        format!(
+                            "delete_state {} {}",
+                            sh_quote_path(path),
+                            sh_quote(&current)
+                        )
+                    }
+                    None => continue,
+                },
+            };
+            let _ = writeln!(steps, "{step}");
+        }
+
+        if steps.is_empty() {
+            return Ok(());
+        }
+
+        let run = match escalate {
+            Some(command) => sh_quote(&command.join(" ")),
+            None => String::new(),
+        };
+        let _ = write!(self.body, "\nrun={run}\n{steps}");
+        Ok(())
+    }
+
+    /// Whether the script has no steps.
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+
+    /// Returns the complete script.
+    pub fn render(&self) -> String {
+        format!("{PREAMBLE}{}", self.body)
+    }
+
+    fn skip(&mut self, target: &Path, reason: &str) {
+        self.skipped
+            .push(format!("{}: {}", target.display(), reason));
+    }
+}
+
+// Returns the current content of the state, if there is one.
+fn read_state(fs: &dyn Fs, path: &Path) -> Result<Option<String>> {
+    if !fs.exists(path) {
+        return Ok(None);
+    }
+    fs.read_to_string(path)
+        .map(Some)
+        .map_err(|err| Error::io("Failed to read state", path, err))
+}
+
+fn inode(fs: &dyn Fs, path: &Path) -> Result<u64> {
+    fs.symlink_metadata(path)
+        .map(|metadata| metadata.ino)
+        .map_err(|err| Error::io("Failed to read", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Cache;
+    use crate::fs::MemFs;
+    use crate::manifest::Manifest;
+    use crate::plan::{Plan, Resolution};
+    use crate::selection::Selection;
+    use crate::utils::Layout;
+
+    #[test]
+    fn steps_follow_the_plan() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/bashrc", "bashrc");
+        fs.add_file("/dotfiles/nvim/init.lua", "nvim");
+        fs.add_file("/dotfiles/token.age", "secret");
+        fs.add_file("/home/.bashrc", "existing");
+        fs.add_symlink("/dotfiles/profile", "/home/.profile");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"bashrc\" = \"/home/.bashrc\"\n\"nvim\" = \"/home/.config/nvim\"\n\
+             \"token.age\" = { target = \"/home/.token\", encrypted = true }",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::default()).unwrap();
+
+        let mut cache = Cache::default();
+        cache.files.insert(
+            "/dotfiles/profile".to_string(),
+            "/home/.profile".to_string(),
+        );
+        let mut plan = Plan::install(&fs, &manifest, &cache, &Selection::default());
+        assert!(plan.resolve(&fs, Path::new("/home/.bashrc"), Resolution::Backup));
+
+        let mut script = Script::default();
+        let operations = plan.operations(&fs, Path::new("/state.toml"));
+        script.push(&fs, &operations, None).unwrap();
+        let ino = fs.symlink_metadata(Path::new("/home/.bashrc")).unwrap().ino;
+
+        let content = script.render();
+        let steps: Vec<_> = content.split("\nrun=\n").nth(1).unwrap().lines().collect();
+        assert_eq!(
+            steps,
+            [
+                "remove_link '/home/.profile' '/dotfiles/profile'",
+                "create_dir '/home/.config/nvim'",
+                &format!("backup '/home/.bashrc' '/home/.bashrc.bak' {ino}"),
+                "link '/dotfiles/bashrc' '/home/.bashrc'",
+                "link '/dotfiles/nvim/init.lua' '/home/.config/nvim/init.lua'",
+            ]
+        );
+        assert_eq!(
+            script.skipped,
+            [
+                "/home/.token: secrets are decrypted by 'homage install'",
+                "/state.toml: the state is recorded once 'homage install' finished the plan",
+            ]
+        );
+    }
+
+    #[test]
+    fn state_is_written_last() {
+        let fs = MemFs::new();
+        fs.add_file("/dotfiles/bashrc", "bashrc");
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"bashrc\" = \"/home/.bashrc\"",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::default()).unwrap();
+        let state = fs.add_file("/state.toml", "files = {}\n");
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let mut script = Script::default();
+        script
+            .push(&fs, &plan.operations(&fs, &state), None)
+            .unwrap();
+        assert!(script.skipped.is_empty());
+
+        let content = script.render();
+        assert!(content.ends_with(
+            "\nrun=\ncreate_dir '/home'\nlink '/dotfiles/bashrc' '/home/.bashrc'\n\
+             replace_state '/state.toml' 'files = {}\n' 'version = 2\n\n\
+             [files]\n\"/dotfiles/bashrc\" = \"/home/.bashrc\"\n'\n"
+        ));
+    }
+
+    #[test]
+    fn non_utf8_paths_are_escaped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fs = MemFs::new();
+        fs.add_file(
+            Path::new("/dotfiles/latin1").join(OsStr::from_bytes(b"caf\xe9")),
+            "latin1",
+        );
+        let path = fs.add_file(
+            "/dotfiles/manifest.toml",
+            "[files]\n\"latin1\" = \"/home/latin1\"",
+        );
+        let manifest = Manifest::load(&fs, &path, &Layout::default()).unwrap();
+
+        let plan = Plan::install(&fs, &manifest, &Cache::default(), &Selection::default());
+        let mut script = Script::default();
+        script
+            .push(&fs, &plan.operations(&fs, Path::new("/state.toml")), None)
+            .unwrap();
+        assert!(script.skipped.is_empty());
+        assert!(script.render().contains(
+            "\nlink '/dotfiles/latin1/caf'\"$(printf '\\351')\" '/home/latin1/caf'\"$(printf '\\351')\"\n"
+        ));
+    }
+}
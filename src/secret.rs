@@ -0,0 +1,166 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::str::FromStr;
+
+use age::secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::fs::Fs;
+
+/// The key secrets are encrypted with, either age X25519 identities or a passphrase.
+pub enum SecretKey {
+    Identities(Vec<age::x25519::Identity>),
+    Passphrase(SecretString),
+}
+
+impl SecretKey {
+    /// Reads the `AGE-SECRET-KEY-` lines of an age identity file, as written by `age-keygen`.
+    pub fn from_identity_file(fs: &dyn Fs, path: &Path) -> Result<SecretKey> {
+        let content = fs
+            .read_to_string(path)
+            .map_err(|err| Error::io("Failed to read identity file", path, err))?;
+
+        let identities = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(age::x25519::Identity::from_str)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::InvalidIdentity(path.to_path_buf()))?;
+
+        if identities.is_empty() {
+            return Err(Error::InvalidIdentity(path.to_path_buf()));
+        }
+
+        Ok(SecretKey::Identities(identities))
+    }
+
+    pub fn passphrase(passphrase: String) -> SecretKey {
+        SecretKey::Passphrase(SecretString::from(passphrase))
+    }
+
+    /// Encrypts to the first identity, or with the passphrase.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let result = match self {
+            SecretKey::Identities(identities) => {
+                age::encrypt(&identities[0].to_public(), plaintext)
+            }
+            SecretKey::Passphrase(passphrase) => {
+                age::encrypt(&age::scrypt::Recipient::new(passphrase.clone()), plaintext)
+            }
+        };
+
+        result.map_err(|err| Error::Encrypt(err.to_string()))
+    }
+
+    /// Decrypts the encrypted contents of the file at `path`.
+    pub fn decrypt(&self, path: &Path, ciphertext: &[u8]) -> Result<Plaintext> {
+        let result = match self {
+            SecretKey::Identities(identities) => {
+                let identities = identities.iter().map(|i| i as &dyn age::Identity);
+                age::Decryptor::new_buffered(ciphertext)
+                    .and_then(|decryptor| decryptor.decrypt(identities))
+                    .and_then(|mut reader| {
+                        let mut plaintext = Vec::new();
+                        std::io::Read::read_to_end(&mut reader, &mut plaintext)?;
+                        Ok(plaintext)
+                    })
+            }
+            SecretKey::Passphrase(passphrase) => {
+                age::decrypt(&age::scrypt::Identity::new(passphrase.clone()), ciphertext)
+            }
+        };
+
+        result.map(Plaintext).map_err(|err| Error::Decrypt {
+            path: path.to_path_buf(),
+            message: err.to_string(),
+        })
+    }
+}
+
+/// Decrypted contents of a secret, never shown in debug output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Plaintext(pub Vec<u8>);
+
+impl Debug for Plaintext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Plaintext([redacted])")
+    }
+}
+
+/// Hashes recorded for an installed secret, so changes can be detected without storing plaintext.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SecretHashes {
+    /// Hash of the encrypted source file.
+    pub source: String,
+    /// Hash of the decrypted content written to the target.
+    pub content: String,
+}
+
+/// Returns the hex encoded SHA-256 hash of the given bytes.
+pub fn hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the hash of the file at the given path, if it can be read.
+pub fn hash_file(fs: &dyn Fs, path: &Path) -> Option<String> {
+    fs.read(path).ok().map(|bytes| hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fs::MemFs;
+
+    #[test]
+    fn encrypt_and_decrypt_with_identity() {
+        use age::secrecy::ExposeSecret;
+
+        let identity = age::x25519::Identity::generate().to_string();
+        let fs = MemFs::new();
+        let path = fs.add_file(
+            "/keys/identity.txt",
+            &format!("# created: now\n{}\n", identity.expose_secret()),
+        );
+        let key = SecretKey::from_identity_file(&fs, &path).unwrap();
+
+        let ciphertext = key.encrypt(b"token").unwrap();
+        assert_ne!(ciphertext, b"token");
+        assert_eq!(
+            key.decrypt(Path::new("/secret.age"), &ciphertext).unwrap(),
+            Plaintext(b"token".to_vec())
+        );
+
+        let other = SecretKey::passphrase("wrong".to_string());
+        assert!(matches!(
+            other.decrypt(Path::new("/secret.age"), &ciphertext),
+            Err(Error::Decrypt { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_identity_files_are_rejected() {
+        let fs = MemFs::new();
+        let path = fs.add_file("/keys/identity.txt", "not a key");
+
+        assert!(matches!(
+            SecretKey::from_identity_file(&fs, &path),
+            Err(Error::InvalidIdentity(_))
+        ));
+    }
+
+    #[test]
+    fn hash_is_sha256() {
+        assert_eq!(
+            hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(format!("{:?}", Plaintext(b"token".to_vec())).contains("redacted"));
+    }
+}
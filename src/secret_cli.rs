@@ -0,0 +1,203 @@
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, anyhow};
+use log::info;
+
+use homage::OsFs;
+use homage::format::fmt_file;
+use homage::secret::SecretKey;
+
+/// Environment variable holding the passphrase, checked before prompting for it.
+const PASSPHRASE_VAR: &str = "HOMAGE_PASSPHRASE";
+
+/// Loads the key secrets are encrypted with. An identity file wins, otherwise the passphrase
+/// is taken from $HOMAGE_PASSPHRASE or prompted for.
+pub fn load_key(identity: Option<&Path>) -> anyhow::Result<SecretKey> {
+    if let Some(identity) = identity {
+        return Ok(SecretKey::from_identity_file(&OsFs, identity)?);
+    }
+
+    let passphrase = match std::env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            rpassword::prompt_password("Passphrase: ").context("Failed to read passphrase")?
+        }
+    };
+
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase must not be empty"));
+    }
+
+    Ok(SecretKey::passphrase(passphrase))
+}
+
+/// Encrypts the file to `output`, which defaults to the file with an added '.age' extension.
+pub fn encrypt(
+    file: &Path,
+    output: Option<PathBuf>,
+    identity: Option<&Path>,
+) -> anyhow::Result<()> {
+    let output = output.unwrap_or_else(|| {
+        let mut name = file.as_os_str().to_os_string();
+        name.push(".age");
+        PathBuf::from(name)
+    });
+
+    let plaintext = fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let key = load_key(identity)?;
+    let ciphertext = key.encrypt(&plaintext)?;
+
+    fs::write(&output, ciphertext)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    info!("Encrypted {} to {}", fmt_file(file), fmt_file(&output));
+    Ok(())
+}
+
+/// Decrypts the secret into a private temporary directory, opens it in the editor
+/// and encrypts it again if it was changed. The decrypted copy is always removed.
+pub fn edit(file: &Path, identity: Option<&Path>) -> anyhow::Result<()> {
+    let ciphertext =
+        fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let key = load_key(identity)?;
+    let plaintext = key.decrypt(file, &ciphertext)?;
+
+    let dir = PrivateDir::create()?;
+    let name = file
+        .file_stem()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("secret"));
+    let path = dir.0.join(name);
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut temp| std::io::Write::write_all(&mut temp, &plaintext.0))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    // Run through the shell so editors configured with arguments work
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status()
+        .with_context(|| format!("Failed to run editor '{}'", editor))?;
+
+    if !status.success() {
+        return Err(anyhow!("Editor '{}' exited with {}", editor, status));
+    }
+
+    let edited = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if edited == plaintext.0 {
+        info!("No changes to {}", fmt_file(file));
+        return Ok(());
+    }
+
+    replace_file(file, &key.encrypt(&edited)?)
+        .with_context(|| format!("Failed to write {}", file.display()))?;
+
+    info!("Updated {}", fmt_file(file));
+    Ok(())
+}
+
+// Writes the content next to the file and renames it over the file, keeping its mode. The file
+// is never left truncated, it either holds the old or the new content.
+fn replace_file(file: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut temp = file.as_os_str().to_os_string();
+    temp.push(".homage-edit");
+    let temp = PathBuf::from(temp);
+
+    let replace = || {
+        let permissions = fs::metadata(file)?.permissions();
+        let mut writer = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        std::io::Write::write_all(&mut writer, content)?;
+        writer.sync_all()?;
+        fs::set_permissions(&temp, permissions)?;
+        fs::rename(&temp, file)
+    };
+    replace().inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+// A temporary directory only accessible by the owner, removed with everything in it when dropped.
+struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    fn create() -> anyhow::Result<PrivateDir> {
+        let path = std::env::temp_dir().join(format!("homage-secret-{}", std::process::id()));
+
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        Ok(PrivateDir(path))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{test_dir, write_file};
+
+    #[test]
+    fn failed_replace_keeps_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("secret_replace");
+        let file = write_file(&dir, "token.age", "old");
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+
+        replace_file(&file, b"new").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+        assert_eq!(
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+
+        // A leftover temporary file blocks the write, leaving the file as it was
+        fs::create_dir(dir.join("token.age.homage-edit")).unwrap();
+        assert!(replace_file(&file, b"newer").is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+    }
+
+    #[test]
+    fn encrypt_writes_age_file() {
+        let dir = test_dir("secret_encrypt");
+        let file = write_file(&dir, "token", "token");
+        let identity = age::x25519::Identity::generate();
+        let identity_path = write_file(
+            &dir,
+            "identity.txt",
+            age::secrecy::ExposeSecret::expose_secret(&identity.to_string()),
+        );
+
+        encrypt(&file, None, Some(&identity_path)).unwrap();
+
+        let ciphertext = fs::read(dir.join("token.age")).unwrap();
+        let key = load_key(Some(&identity_path)).unwrap();
+        assert_eq!(key.decrypt(&file, &ciphertext).unwrap().0, b"token");
+    }
+}
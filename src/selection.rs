@@ -0,0 +1,91 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Restricts an action to a subset of the manifest entries.
+/// Filters of different kinds must all match, while any of the values given for one kind may match.
+/// Source and target filters match the path itself and everything below it.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    tags: Vec<String>,
+    targets: Vec<PathBuf>,
+    sources: Vec<PathBuf>,
+}
+
+impl Selection {
+    pub fn new(tags: Vec<String>, targets: Vec<PathBuf>, sources: Vec<PathBuf>) -> Selection {
+        Selection {
+            tags,
+            targets,
+            sources,
+        }
+    }
+
+    /// Whether the selection includes every entry.
+    pub fn is_all(&self) -> bool {
+        self.tags.is_empty() && self.targets.is_empty() && self.sources.is_empty()
+    }
+
+    /// Whether an entry with the given source, target and tags is selected.
+    pub fn matches(&self, source: &Path, target: &Path, tags: &BTreeSet<String>) -> bool {
+        (self.tags.is_empty() || self.tags.iter().any(|tag| tags.contains(tag)))
+            && self.matches_paths(source, target)
+    }
+
+    /// Whether an entry whose tags are unknown is selected.
+    /// Such entries are never selected by a tag filter, so partial runs leave them alone.
+    pub fn matches_untagged(&self, source: &Path, target: &Path) -> bool {
+        self.tags.is_empty() && self.matches_paths(source, target)
+    }
+
+    fn matches_paths(&self, source: &Path, target: &Path) -> bool {
+        (self.sources.is_empty() || self.sources.iter().any(|s| source.starts_with(s)))
+            && (self.targets.is_empty() || self.targets.iter().any(|t| target.starts_with(t)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(values: &[&str]) -> BTreeSet<String> {
+        values.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_selection_matches_everything() {
+        let selection = Selection::default();
+        assert!(selection.is_all());
+        assert!(selection.matches(Path::new("/src/a"), Path::new("/target/a"), &tags(&[])));
+        assert!(selection.matches_untagged(Path::new("/src/a"), Path::new("/target/a")));
+    }
+
+    #[test]
+    fn tag_filter_matches_any_tag() {
+        let selection =
+            Selection::new(vec!["gui".to_string(), "shell".to_string()], vec![], vec![]);
+        assert!(selection.matches(Path::new("/src/a"), Path::new("/t/a"), &tags(&["shell"])));
+        assert!(!selection.matches(Path::new("/src/a"), Path::new("/t/a"), &tags(&["work"])));
+        assert!(!selection.matches_untagged(Path::new("/src/a"), Path::new("/t/a")));
+    }
+
+    #[test]
+    fn path_filters_match_prefixes() {
+        let selection = Selection::new(
+            vec![],
+            vec![PathBuf::from("/home/user/.config/nvim")],
+            vec![PathBuf::from("/dotfiles/nvim")],
+        );
+        assert!(selection.matches_untagged(
+            Path::new("/dotfiles/nvim/init.lua"),
+            Path::new("/home/user/.config/nvim/init.lua")
+        ));
+        assert!(!selection.matches_untagged(
+            Path::new("/dotfiles/nvim/init.lua"),
+            Path::new("/home/user/.config/nvim-old/init.lua")
+        ));
+        assert!(!selection.matches_untagged(
+            Path::new("/dotfiles/zsh/zshrc"),
+            Path::new("/home/user/.config/nvim/zshrc")
+        ));
+    }
+}
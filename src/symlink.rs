@@ -1,90 +1,336 @@
-use std::{fmt::Display, fs, os::unix::fs as unix_fs, path::PathBuf};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
-use log::{error, info};
+use log::info;
 
+use crate::error::{Error, Result};
 use crate::format::{fmt_error, fmt_file, fmt_link};
+use crate::fs::Fs;
+use crate::secret::{Plaintext, SecretHashes, hash_file};
 
+/// A link from a target path to a source file in the dotfiles repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Symlink {
     pub source: PathBuf,
     pub target: PathBuf,
+    pub kind: Kind,
+    pub modes: Modes,
+}
+
+/// Permission modes enforced for an entry, unset modes are left as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modes {
+    /// Mode of the file. A symlink has the mode of its source, so it is set on the source,
+    /// while it is set on the target of a secret.
+    pub file: Option<u32>,
+    /// Mode of the directory containing the target.
+    pub dir: Option<u32>,
+}
+
+/// How a source is installed at its target.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// A symlink pointing to the source.
+    #[default]
+    Link,
+    /// A private file holding the decrypted source, along with the hashes recorded
+    /// when it was last installed.
+    Secret(Option<SecretHashes>),
+    /// A hard link to the source, along with the inode of the source when it was last installed.
+    Hardlink(Option<u64>),
+}
+
+/// The state of a target compared to its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Installed,
+    Missing,
+    /// The target is occupied by a file not managed by homage.
+    Conflict,
+    /// An installed secret was changed at the target.
+    Modified,
+    /// The source of an installed secret changed since it was installed, or the source of
+    /// a hard link was replaced by a new file.
+    Outdated,
+    /// Installed, but a file or directory does not have the mode set for the entry.
+    WrongMode,
 }
 
 impl Symlink {
     pub fn new(source: PathBuf, target: PathBuf) -> Symlink {
-        Symlink { source, target }
+        Symlink {
+            source,
+            target,
+            kind: Kind::Link,
+            modes: Modes::default(),
+        }
     }
 
-    pub fn is_installed(&self) -> bool {
-        if !self.target.is_symlink() {
-            return false;
+    pub fn secret(source: PathBuf, target: PathBuf, hashes: Option<SecretHashes>) -> Symlink {
+        Symlink {
+            source,
+            target,
+            kind: Kind::Secret(hashes),
+            modes: Modes::default(),
         }
+    }
 
-        match fs::read_link(&self.target) {
-            Ok(current) => current == *self.source,
-            Err(_) => false,
+    pub fn hardlink(source: PathBuf, target: PathBuf, ino: Option<u64>) -> Symlink {
+        Symlink {
+            source,
+            target,
+            kind: Kind::Hardlink(ino),
+            modes: Modes::default(),
         }
     }
 
-    pub fn exists(&self) -> bool {
-        if let Ok(result) = self.target.try_exists()
-            && result
-        {
-            return true;
+    /// Whether the target is managed by homage. A secret counts as installed as long as the
+    /// target holds the content written by homage, even if its source changed since.
+    /// Likewise a hard link counts while it is the file the source was when it was installed.
+    pub fn is_installed(&self, fs: &dyn Fs) -> bool {
+        match &self.kind {
+            Kind::Link => {
+                if !fs.is_symlink(&self.target) {
+                    return false;
+                }
+
+                match fs.read_link(&self.target) {
+                    Ok(current) => current == *self.source,
+                    Err(_) => false,
+                }
+            }
+            Kind::Secret(hashes) => {
+                !fs.is_symlink(&self.target)
+                    && hashes.as_ref().is_some_and(|hashes| {
+                        hash_file(fs, &self.target).as_ref() == Some(&hashes.content)
+                    })
+            }
+            Kind::Hardlink(ino) => {
+                let Ok(target) = fs.symlink_metadata(&self.target) else {
+                    return false;
+                };
+
+                // The source device is unknown once it is gone, then the inode alone decides
+                target.is_file()
+                    && match fs.metadata(&self.source) {
+                        Ok(source) => {
+                            source.dev == target.dev
+                                && (source.ino == target.ino || *ino == Some(target.ino))
+                        }
+                        Err(_) => *ino == Some(target.ino),
+                    }
+            }
         }
+    }
 
-        false
+    // Whether the target and the source are the same file.
+    fn is_hard_linked(&self, fs: &dyn Fs) -> bool {
+        match (fs.metadata(&self.source), fs.symlink_metadata(&self.target)) {
+            (Ok(source), Ok(target)) => source.dev == target.dev && source.ino == target.ino,
+            _ => false,
+        }
     }
 
-    pub fn install(&self) {
-        if let Some(parent) = self.target.parent() {
-            match fs::create_dir_all(parent) {
-                Ok(_) => {}
-                Err(err) => {
-                    error!(
-                        "Failed to create parent directory {}: {}",
-                        parent.display(),
-                        err
-                    );
-                    return;
-                }
-            }
+    /// Whether the target is installed and up to date with the source.
+    /// Wrong modes do not count, those are repaired without reinstalling.
+    pub fn is_current(&self, fs: &dyn Fs) -> bool {
+        matches!(self.status(fs), Status::Installed | Status::WrongMode)
+    }
+
+    pub fn status(&self, fs: &dyn Fs) -> Status {
+        match self.content_status(fs) {
+            Status::Installed if !self.mode_changes(fs).is_empty() => Status::WrongMode,
+            status => status,
         }
+    }
 
-        if self.target.is_symlink() && !self.exists() {
-            info!("Overwriting broken symlink at {}", fmt_error(&self.target));
+    fn content_status(&self, fs: &dyn Fs) -> Status {
+        if !self.exists(fs) {
+            return Status::Missing;
+        }
 
-            match fs::remove_file(&self.target) {
-                Ok(_) => {}
-                Err(err) => {
-                    error!("Failed to cleanup broken symlink {}: {}", self, err);
+        match &self.kind {
+            Kind::Link if self.is_installed(fs) => Status::Installed,
+            Kind::Link => Status::Conflict,
+            Kind::Secret(Some(hashes)) if !fs.is_symlink(&self.target) => {
+                if hash_file(fs, &self.target).as_ref() != Some(&hashes.content) {
+                    Status::Modified
+                } else if hash_file(fs, &self.source).as_ref() != Some(&hashes.source) {
+                    Status::Outdated
+                } else {
+                    Status::Installed
                 }
             }
+            Kind::Secret(_) => Status::Conflict,
+            Kind::Hardlink(_) if self.is_hard_linked(fs) => Status::Installed,
+            Kind::Hardlink(_) if self.is_installed(fs) => Status::Outdated,
+            Kind::Hardlink(_) => Status::Conflict,
         }
+    }
 
-        match unix_fs::symlink(&self.source, &self.target) {
-            Ok(_) => {}
-            Err(err) => {
-                error!("Failed to create symlink {}: {}", self, err);
+    /// Returns the paths whose mode differs from the modes set for the entry, along with
+    /// the mode to set. The directory containing the target is included if it does not exist yet.
+    pub fn mode_changes(&self, fs: &dyn Fs) -> Vec<(PathBuf, u32)> {
+        let mut changes = Vec::new();
+        let differs = |path: &Path, mode: u32| {
+            fs.metadata(path)
+                .is_ok_and(|metadata| metadata.mode & 0o7777 != mode)
+        };
+
+        if let Some(mode) = self.modes.file {
+            let path = match self.kind {
+                Kind::Link | Kind::Hardlink(_) => &self.source,
+                Kind::Secret(_) => &self.target,
+            };
+            if differs(path, mode) {
+                changes.push((path.clone(), mode));
             }
         }
+
+        if let (Some(mode), Some(parent)) = (self.modes.dir, self.target.parent())
+            && (!fs.exists(parent) || differs(parent, mode))
+        {
+            changes.push((parent.to_path_buf(), mode));
+        }
+
+        changes
     }
 
-    pub fn uninstall(&self) {
-        match fs::remove_file(&self.target) {
-            Ok(_) => {}
-            Err(err) => {
-                error!("Failed to remove symlink {}: {}", self, err);
-            }
+    /// The mode the target is readable with once installed.
+    pub fn installed_mode(&self, fs: &dyn Fs) -> Option<u32> {
+        match self.kind {
+            Kind::Link | Kind::Hardlink(_) => self
+                .modes
+                .file
+                .or_else(|| fs.metadata(&self.source).ok().map(|m| m.mode & 0o7777)),
+            Kind::Secret(_) => Some(self.modes.file.unwrap_or(SECRET_MODE)),
         }
     }
+
+    pub fn exists(&self, fs: &dyn Fs) -> bool {
+        fs.exists(&self.target)
+    }
+
+    pub fn is_secret(&self) -> bool {
+        matches!(self.kind, Kind::Secret(_))
+    }
+
+    pub fn is_hardlink(&self) -> bool {
+        matches!(self.kind, Kind::Hardlink(_))
+    }
+
+    /// Create the symlink or hard link, along with any missing parent directories.
+    /// A broken symlink occupying the target is replaced.
+    pub fn install(&self, fs: &dyn Fs) -> Result<()> {
+        if self.is_secret() {
+            return Err(Error::MissingKey(self.source.clone()));
+        }
+
+        if let Some(parent) = self.target.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create parent directory", parent, err))?;
+        }
+
+        if fs.is_symlink(&self.target) && !self.exists(fs) {
+            info!("Overwriting broken symlink at {}", fmt_error(&self.target));
+
+            fs.remove_file(&self.target)
+                .map_err(|err| Error::io("Failed to cleanup broken symlink", &self.target, err))?;
+        }
+
+        if self.is_hardlink() {
+            return self.install_hardlink(fs);
+        }
+
+        fs.symlink(&self.source, &self.target)
+            .map_err(|err| Error::io("Failed to create symlink", &self.target, err))
+    }
+
+    fn install_hardlink(&self, fs: &dyn Fs) -> Result<()> {
+        let source_dev = fs.metadata(&self.source).map(|m| m.dev);
+        let target_dev = self.target.parent().map(|parent| fs.metadata(parent));
+        if let (Ok(source_dev), Some(Ok(parent))) = (source_dev, target_dev)
+            && source_dev != parent.dev
+        {
+            return Err(Error::CrossDevice(self.target.clone()));
+        }
+
+        fs.hard_link(&self.source, &self.target)
+            .map_err(|err| match err.raw_os_error() {
+                Some(EXDEV) => Error::CrossDevice(self.target.clone()),
+                _ => Error::io("Failed to create hard link", &self.target, err),
+            })
+    }
+
+    /// Write the decrypted secret to the target, only readable by the owner.
+    /// A symlink occupying the target is replaced, but files not written by homage never are.
+    pub fn install_secret(&self, fs: &dyn Fs, plaintext: &Plaintext) -> Result<()> {
+        if fs.exists(&self.target) && !fs.is_symlink(&self.target) && !self.is_installed(fs) {
+            return Err(Error::io(
+                "Refusing to overwrite",
+                &self.target,
+                std::io::ErrorKind::AlreadyExists.into(),
+            ));
+        }
+
+        if let Some(parent) = self.target.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|err| Error::io("Failed to create parent directory", parent, err))?;
+        }
+
+        if fs.is_symlink(&self.target) {
+            fs.remove_file(&self.target)
+                .map_err(|err| Error::io("Failed to remove symlink", &self.target, err))?;
+        }
+
+        // Restrict the mode before the content is written, so the secret is never readable by others
+        let write = || {
+            fs.write(&self.target, b"")?;
+            fs.set_mode(&self.target, self.modes.file.unwrap_or(SECRET_MODE))?;
+            fs.write(&self.target, &plaintext.0)
+        };
+        write().map_err(|err| Error::io("Failed to write secret", &self.target, err))
+    }
+
+    /// Remove the symlink or secret at the target path.
+    pub fn uninstall(&self, fs: &dyn Fs) -> Result<()> {
+        fs.remove_file(&self.target)
+            .map_err(|err| Error::io("Failed to remove symlink", &self.target, err))
+    }
+}
+
+/// Permission bits of decrypted secrets.
+pub const SECRET_MODE: u32 = 0o600;
+
+// Error number of a link across filesystems.
+const EXDEV: i32 = 18;
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            Status::Installed => "installed",
+            Status::Missing => "missing",
+            Status::Conflict => "conflict",
+            Status::Modified => "modified",
+            Status::Outdated => "outdated",
+            Status::WrongMode => "mode",
+        };
+        f.pad(status)
+    }
 }
 
 impl Display for Symlink {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arrow = match self.kind {
+            Kind::Link => "->",
+            Kind::Secret(_) => "=>",
+            Kind::Hardlink(_) => "==",
+        };
         write!(
             f,
-            "{} -> {}",
+            "{} {} {}",
             fmt_file(&self.source),
+            arrow,
             fmt_link(&self.target)
         )
     }
@@ -94,87 +340,99 @@ impl Display for Symlink {
 mod tests {
     use super::*;
 
-    use std::fs::{self};
-
-    use crate::tests::tests::{test_dir, write_file};
+    use crate::fs::MemFs;
 
     #[test]
     fn is_installed_false_for_regular_file() {
-        let base = test_dir("regular");
-
-        let source = write_file(&base, "source.txt", "src");
-        let target = write_file(&base, "target.txt", "target");
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/source.txt", "src");
+        let target = fs.add_file("/home/user/target.txt", "target");
 
         let link = Symlink::new(source, target);
 
-        assert!(!link.is_installed());
+        assert!(!link.is_installed(&fs));
+        assert!(link.exists(&fs));
     }
 
     #[test]
     fn install_and_uninstall_symlink() {
-        let base = test_dir("install");
-        let source = write_file(&base, "source.txt", "src");
-
-        let target_dir = base.join("target");
-        let target = target_dir.join("source.txt");
-
-        let link = Symlink::new(source.clone(), target.clone());
-        assert!(!link.is_installed());
-
-        link.install();
-
-        assert!(target.is_symlink());
-        assert!(link.is_installed());
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/source.txt", "src");
+        let target = PathBuf::from("/home/user/target/source.txt");
 
-        link.uninstall();
-
-        assert!(!target.exists());
-    }
+        let link = Symlink::new(source, target.clone());
+        assert!(!link.is_installed(&fs));
+        assert!(!link.exists(&fs));
 
-    #[test]
-    fn exists_true_for_existing_file() {
-        let base = test_dir("exists");
+        link.install(&fs).unwrap();
 
-        let source = write_file(&base, "source.txt", "src");
-        let target = write_file(&base, "target.txt", "target");
+        assert!(fs.is_symlink(&target));
+        assert!(link.is_installed(&fs));
 
-        let link = Symlink::new(source, target.clone());
+        link.uninstall(&fs).unwrap();
 
-        assert!(link.exists());
+        assert!(!fs.exists(&target));
     }
 
     #[test]
     fn install_replaces_broken_symlink() {
-        let base = test_dir("broken_symlink");
-
-        let old_source = base.join("old_source.txt");
-        let new_source = write_file(&base, "new_source.txt", "new content");
-        let target = base.join("link.txt");
-
-        // Create a broken symlink by pointing to a non-existent file
-        unix_fs::symlink(&old_source, &target).unwrap();
-        assert!(target.is_symlink());
-        assert!(!target.exists()); // broken: destination doesn't exist
+        let fs = MemFs::new();
+        let new_source = fs.add_file("/dotfiles/new_source.txt", "new content");
+        let target = PathBuf::from("/home/user/link.txt");
+        fs.add_symlink("/dotfiles/old_source.txt", &target);
+        assert!(fs.is_symlink(&target));
+        assert!(!fs.exists(&target));
 
         // Install should replace the broken symlink
-        let link = Symlink::new(new_source.clone(), target.clone());
-        link.install();
+        let link = Symlink::new(new_source, target.clone());
+        link.install(&fs).unwrap();
 
-        assert!(target.is_symlink());
-        assert!(link.is_installed());
-        assert_eq!(fs::read_to_string(&target).unwrap(), "new content");
+        assert!(link.is_installed(&fs));
+        assert_eq!(fs.read_to_string(&target).unwrap(), "new content");
     }
 
     #[test]
-    fn is_installed_true_after_install() {
-        let base = test_dir("installed");
-
-        let source = write_file(&base, "source.txt", "src");
-        let target = base.join("link.txt");
-
-        let link = Symlink::new(source.clone(), target.clone());
-        link.install();
+    fn install_reports_permission_denied() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/source.txt", "src");
+        fs.create_dir_all(std::path::Path::new("/home/user"))
+            .unwrap();
+        fs.fail("/home/user", 13);
+
+        let link = Symlink::new(source, PathBuf::from("/home/user/link.txt"));
+        let err = link.install(&fs).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Io { source, .. } if source.kind() == std::io::ErrorKind::PermissionDenied
+        ));
+        assert!(!link.is_installed(&fs));
+    }
 
-        assert!(link.is_installed());
+    #[test]
+    fn secret_status_tracks_hashes() {
+        let fs = MemFs::new();
+        let source = fs.add_file("/dotfiles/netrc.age", "ciphertext");
+        let target = PathBuf::from("/home/user/.netrc");
+
+        let hashes = SecretHashes {
+            source: crate::secret::hash(b"ciphertext"),
+            content: crate::secret::hash(b"machine example.com"),
+        };
+        let secret = Symlink::secret(source.clone(), target.clone(), Some(hashes));
+        assert_eq!(secret.status(&fs), Status::Missing);
+
+        secret
+            .install_secret(&fs, &Plaintext(b"machine example.com".to_vec()))
+            .unwrap();
+        assert_eq!(fs.metadata(&target).unwrap().mode, SECRET_MODE);
+        assert_eq!(secret.status(&fs), Status::Installed);
+
+        fs.write(&source, b"new ciphertext").unwrap();
+        assert_eq!(secret.status(&fs), Status::Outdated);
+        assert!(secret.is_installed(&fs));
+
+        fs.write(&target, b"changed").unwrap();
+        assert_eq!(secret.status(&fs), Status::Modified);
+        assert!(!secret.is_installed(&fs));
     }
 }
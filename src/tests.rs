@@ -1,26 +1,57 @@
-#[cfg(test)]
-pub mod tests {
-    use std::{fs, io::Write, path::PathBuf};
-
-    pub fn test_dir(name: &str) -> PathBuf {
-        let ts = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        let dir = std::env::temp_dir().join(format!("homage_test_{}_{}", name, ts));
-        fs::create_dir_all(&dir).unwrap();
-        dir
+use std::{
+    fs,
+    io::Write,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A scratch directory for tests that need the real filesystem, removed along with its
+/// contents once dropped. Everything else is tested against `MemFs`.
+pub struct TestDir(PathBuf);
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
     }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
 
-    pub fn write_file(dir: &PathBuf, name: &str, content: &str) -> PathBuf {
-        let path = dir.join(name);
+pub fn test_dir(name: &str) -> TestDir {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!(
+        "homage_test_{}_{}_{}",
+        name,
+        std::process::id(),
+        ts
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+}
 
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).unwrap();
-        }
+pub fn write_file(dir: &Path, name: &str, content: &str) -> PathBuf {
+    let path = dir.join(name);
 
-        let mut f = fs::File::create(&path).unwrap();
-        f.write_all(content.as_bytes()).unwrap();
-        path
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
     }
+
+    let mut f = fs::File::create(&path).unwrap();
+    f.write_all(content.as_bytes()).unwrap();
+    path
 }
@@ -1,60 +1,322 @@
-use anyhow::anyhow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::fs::Fs;
 
 const HOME: &str = "HOME";
 const XDG_CACHE_HOME: &str = "XDG_CACHE_HOME";
+const XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+const XDG_DATA_HOME: &str = "XDG_DATA_HOME";
+const XDG_STATE_HOME: &str = "XDG_STATE_HOME";
+
+/// File names probed when looking for a manifest in a directory, in order of preference.
+const MANIFEST_NAMES: [&str; 2] = ["manifest.toml", "homage.toml"];
+
+/// The cache of installed `[system]` entries, owned by root and written through the escalation command.
+const SYSTEM_CACHE: &str = "/var/lib/homage/cache.toml";
 
-/// Returns the path to the cache file at `$XDG_CACHE_HOME/homage/cache.toml`.
+/// Directories below the home directory holding private files, which should not be readable by others.
+const SENSITIVE_DIRS: [&str; 2] = [".ssh", ".gnupg"];
+
+/// Returns the path to the state file recording the installed links at `$XDG_STATE_HOME/homage/state.toml`.
+/// Falls back to `$HOME/.local/state/homage/state.toml` if `XDG_STATE_HOME` is not set.
+pub fn state_path() -> Result<PathBuf> {
+    Ok(xdg_dir(XDG_STATE_HOME, ".local/state")?
+        .join("homage")
+        .join("state.toml"))
+}
+
+/// Returns the path the state was kept at by earlier versions, `$XDG_CACHE_HOME/homage/cache.toml`.
 /// Falls back to `$HOME/.cache/homage/cache.toml` if `XDG_CACHE_HOME` is not set.
-pub fn cache_path() -> anyhow::Result<PathBuf> {
-    let cache_home = match std::env::var(XDG_CACHE_HOME) {
-        Ok(val) if !val.is_empty() => PathBuf::from(val),
+pub fn legacy_cache_path() -> Result<PathBuf> {
+    Ok(xdg_dir(XDG_CACHE_HOME, ".cache")?
+        .join("homage")
+        .join("cache.toml"))
+}
+
+/// Describes where targets and the state are placed on the filesystem.
+/// By default targets are expanded against `$HOME` and used as is. A `home` override replaces
+/// `$HOME`, and a `root` prefixes every target and the state, leaving the sources untouched.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    root: Option<PathBuf>,
+    home: Option<PathBuf>,
+}
+
+impl Layout {
+    pub fn new(root: Option<PathBuf>, home: Option<PathBuf>) -> Layout {
+        Layout { root, home }
+    }
+
+    /// A layout keeping `~` in targets, for manifests installed on another machine.
+    pub fn portable() -> Layout {
+        Layout::new(None, Some(PathBuf::from("~")))
+    }
+
+    /// Returns the home directory used for tilde expansion, before re-rooting.
+    fn home(&self) -> Result<PathBuf> {
+        match &self.home {
+            Some(home) => Ok(home.clone()),
+            None => std::env::var(HOME)
+                .map(PathBuf::from)
+                .map_err(|_| Error::MissingEnv(HOME)),
+        }
+    }
+
+    /// Expands a manifest target path and places it under the root, if any.
+    pub fn target(&self, path: &str) -> Result<PathBuf> {
+        let expanded = if let Some(rest) = path.strip_prefix("~/") {
+            self.home()?.join(rest)
+        } else if path == "~" {
+            self.home()?
+        } else {
+            PathBuf::from(path)
+        };
+
+        Ok(self.reroot(&expanded))
+    }
+
+    /// Whether the target is located in a directory holding private files, such as `~/.ssh`.
+    pub fn is_sensitive(&self, target: &Path) -> bool {
+        let Ok(home) = self.home() else {
+            return false;
+        };
+        let home = self.reroot(&home);
+
+        SENSITIVE_DIRS
+            .iter()
+            .any(|dir| target.starts_with(home.join(dir)))
+    }
+
+    /// Returns the path to the state file, placed under the root if any.
+    /// When the home directory is overridden the state lives below it and `XDG_STATE_HOME` is ignored.
+    pub fn state_path(&self) -> Result<PathBuf> {
+        let path = match &self.home {
+            Some(home) => home.join(".local/state/homage/state.toml"),
+            None => state_path()?,
+        };
+
+        Ok(self.reroot(&path))
+    }
+
+    /// Returns the path the state was kept at by earlier versions, placed under the root if any.
+    pub fn legacy_cache_path(&self) -> Result<PathBuf> {
+        let path = match &self.home {
+            Some(home) => home.join(".cache/homage/cache.toml"),
+            None => legacy_cache_path()?,
+        };
+
+        Ok(self.reroot(&path))
+    }
+
+    /// Returns the directory bundles are unpacked into, placed under the root if any.
+    /// When the home directory is overridden it lives below it and `XDG_DATA_HOME` is ignored.
+    pub fn bundle_dir(&self) -> Result<PathBuf> {
+        let path = match &self.home {
+            Some(home) => home.join(".local/share/homage/bundle"),
+            None => xdg_dir(XDG_DATA_HOME, ".local/share")?
+                .join("homage")
+                .join("bundle"),
+        };
+
+        Ok(self.reroot(&path))
+    }
+
+    /// Returns the path to the cache of the `[system]` entries at `/var/lib/homage/cache.toml`,
+    /// placed under the root if any.
+    pub fn system_cache_path(&self) -> PathBuf {
+        self.reroot(Path::new(SYSTEM_CACHE))
+    }
+
+    fn reroot(&self, path: &Path) -> PathBuf {
+        match &self.root {
+            Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+/// Returns the path to the config file at `$XDG_CONFIG_HOME/homage/config.toml`.
+/// Falls back to `$HOME/.config/homage/config.toml` if `XDG_CONFIG_HOME` is not set.
+pub fn config_path() -> Result<PathBuf> {
+    Ok(xdg_dir(XDG_CONFIG_HOME, ".config")?
+        .join("homage")
+        .join("config.toml"))
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf> {
+    match std::env::var(var) {
+        Ok(val) if !val.is_empty() => Ok(PathBuf::from(val)),
         _ => {
-            let home = std::env::var(HOME)
-                .map_err(|_| anyhow!("Could not determine $HOME for cache path"))?;
-            PathBuf::from(home).join(".cache")
+            let home = std::env::var(HOME).map_err(|_| Error::MissingEnv(HOME))?;
+            Ok(PathBuf::from(home).join(fallback))
         }
-    };
+    }
+}
+
+/// Parses an octal permission mode such as `"0600"`, `"755"` or `"0o700"`.
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(8)) {
+        return None;
+    }
 
-    Ok(cache_home.join("homage").join("cache.toml"))
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
 }
 
-/// Expand a leading `~` or `~/` in a path to the value of `$HOME`.
-pub fn expand_tilde(path: &str) -> anyhow::Result<PathBuf> {
-    if let Some(rest) = path.strip_prefix("~/") {
-        let home = std::env::var(HOME)
-            .map_err(|_| anyhow!("Could not determine $HOME for tilde expansion"))?;
-        Ok(PathBuf::from(home).join(rest))
-    } else if path == "~" {
-        let home = std::env::var(HOME)
-            .map_err(|_| anyhow!("Could not determine $HOME for tilde expansion"))?;
-        Ok(PathBuf::from(home))
-    } else {
-        Ok(PathBuf::from(path))
+/// Returns the root of the git repository containing `dir`, if any.
+pub fn git_root(fs: &dyn Fs, dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| fs.exists(&ancestor.join(".git")))
+        .map(Path::to_path_buf)
+}
+
+/// Looks for a manifest in `dir` and then in the root of its git repository.
+pub fn discover_manifest(fs: &dyn Fs, dir: &Path) -> Option<PathBuf> {
+    let mut candidates = vec![dir.to_path_buf()];
+    if let Some(root) = git_root(fs, dir)
+        && root != dir
+    {
+        candidates.push(root);
     }
+
+    candidates
+        .iter()
+        .flat_map(|candidate| MANIFEST_NAMES.iter().map(|name| candidate.join(name)))
+        .find(|path| fs.is_file(path))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::fs;
+
+    use crate::fs::OsFs;
+    use crate::tests::{test_dir, write_file};
+
     #[test]
-    fn tilde_expansion() {
-        let result = expand_tilde("~/config/file").unwrap();
+    fn default_layout_expands_against_home() {
+        let layout = Layout::default();
         let home = std::env::var(HOME).unwrap();
-        assert_eq!(result, PathBuf::from(home).join("config/file"));
+        assert_eq!(
+            layout.target("~/config/file").unwrap(),
+            PathBuf::from(home).join("config/file")
+        );
+        assert_eq!(
+            layout.target("/etc/hosts").unwrap(),
+            PathBuf::from("/etc/hosts")
+        );
     }
 
     #[test]
-    fn tilde_alone_expands() {
-        let result = expand_tilde("~").unwrap();
-        let home = std::env::var(HOME).unwrap();
-        assert_eq!(result, PathBuf::from(home));
+    fn layout_home_override() {
+        let layout = Layout::new(None, Some(PathBuf::from("/etc/skel")));
+        assert_eq!(
+            layout.target("~/.bashrc").unwrap(),
+            PathBuf::from("/etc/skel/.bashrc")
+        );
+        assert_eq!(
+            layout.state_path().unwrap(),
+            PathBuf::from("/etc/skel/.local/state/homage/state.toml")
+        );
+        assert_eq!(
+            layout.legacy_cache_path().unwrap(),
+            PathBuf::from("/etc/skel/.cache/homage/cache.toml")
+        );
+        assert_eq!(
+            layout.bundle_dir().unwrap(),
+            PathBuf::from("/etc/skel/.local/share/homage/bundle")
+        );
+    }
+
+    #[test]
+    fn portable_layout_keeps_tilde() {
+        let layout = Layout::portable();
+        assert_eq!(
+            layout.target("~/.bashrc").unwrap(),
+            PathBuf::from("~/.bashrc")
+        );
+        assert_eq!(layout.target("~").unwrap(), PathBuf::from("~"));
+        assert_eq!(
+            layout.target("/etc/hosts").unwrap(),
+            PathBuf::from("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn layout_root_prefixes_targets_and_cache() {
+        let layout = Layout::new(
+            Some(PathBuf::from("/stage")),
+            Some(PathBuf::from("/home/user")),
+        );
+        assert_eq!(
+            layout.target("~/.bashrc").unwrap(),
+            PathBuf::from("/stage/home/user/.bashrc")
+        );
+        assert_eq!(
+            layout.target("/etc/keyd/default.conf").unwrap(),
+            PathBuf::from("/stage/etc/keyd/default.conf")
+        );
+        assert_eq!(
+            layout.state_path().unwrap(),
+            PathBuf::from("/stage/home/user/.local/state/homage/state.toml")
+        );
+        assert_eq!(
+            layout.system_cache_path(),
+            PathBuf::from("/stage/var/lib/homage/cache.toml")
+        );
+    }
+
+    #[test]
+    fn sensitive_targets() {
+        let layout = Layout::new(
+            Some(PathBuf::from("/stage")),
+            Some(PathBuf::from("/home/user")),
+        );
+        assert!(layout.is_sensitive(Path::new("/stage/home/user/.ssh/config")));
+        assert!(layout.is_sensitive(Path::new("/stage/home/user/.gnupg/gpg.conf")));
+        assert!(!layout.is_sensitive(Path::new("/stage/home/user/.sshrc")));
+        assert!(!layout.is_sensitive(Path::new("/home/user/.ssh/config")));
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!(parse_mode("0600"), Some(0o600));
+        assert_eq!(parse_mode("755"), Some(0o755));
+        assert_eq!(parse_mode("0o700"), Some(0o700));
+        assert_eq!(parse_mode("0800"), None);
+        assert_eq!(parse_mode("17777"), None);
+        assert_eq!(parse_mode(""), None);
+    }
+
+    #[test]
+    fn discover_prefers_current_directory() {
+        let dir = test_dir("discover_cwd");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        write_file(&dir, "manifest.toml", "");
+        let nested = write_file(&dir, "nested/homage.toml", "");
+
+        let found = discover_manifest(&OsFs, &dir.join("nested")).unwrap();
+        assert_eq!(found, nested);
+    }
+
+    #[test]
+    fn discover_falls_back_to_git_root() {
+        let dir = test_dir("discover_root");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+        let root_manifest = write_file(&dir, "homage.toml", "");
+
+        let found = discover_manifest(&OsFs, &dir.join("nested/deeper")).unwrap();
+        assert_eq!(found, root_manifest);
     }
 
     #[test]
-    fn absolute_path_unchanged() {
-        let result = expand_tilde("/absolute/path").unwrap();
-        assert_eq!(result, PathBuf::from("/absolute/path"));
+    fn discover_without_manifest_returns_none() {
+        let dir = test_dir("discover_none");
+        assert!(discover_manifest(&OsFs, &dir).is_none());
     }
 }